@@ -22,5 +22,8 @@ fn main() {
         panic!("You need to set the Wi-Fi credentials in `cfg.toml`!");
     }
 
-    embuild::espidf::sysenv::output();
+    //only an ESP-IDF build has an environment to pass on
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("espidf") {
+        embuild::espidf::sysenv::output();
+    }
 }
@@ -0,0 +1,83 @@
+pub mod router;
+
+#[cfg(target_os = "espidf")]
+pub mod esp_server;
+#[cfg(not(target_os = "espidf"))]
+pub mod host_server;
+
+use anyhow::{bail, Result};
+
+use crate::diagnostics::{self, Snapshot};
+use crate::kasa::hub::{Device, KasaHub};
+use crate::kasa::light::{LightChange, LightState};
+use crate::ota::{self, OtaStatus};
+use crate::peripheral_util::battery_monitor::{battery_state, BatteryState};
+use crate::peripheral_util::bitmap::Bitmap;
+use crate::peripheral_util::screenshot;
+use crate::service_manager::{self, ServiceStatus};
+use crate::{event_log, CONFIG};
+use router::ApiBackend;
+
+impl ApiBackend for KasaHub {
+    fn devices(&self) -> Vec<Device> {
+        KasaHub::devices(self)
+    }
+
+    fn set_outlet(&self, dev: usize, outlet: usize, on: Option<bool>) -> Result<bool> {
+        KasaHub::set_outlet(self, dev, outlet, on)
+    }
+
+    fn set_light(&self, dev: usize, change: &LightChange) -> Result<LightState> {
+        KasaHub::set_light(self, dev, change)
+    }
+
+    fn scene_names(&self) -> Vec<String> {
+        self.scenes().iter().map(|s| s.name.clone()).collect()
+    }
+
+    fn run_scene(&self, name: &str) -> Result<()> {
+        KasaHub::run_scene(self, name)
+    }
+
+    fn battery(&self) -> BatteryState {
+        battery_state()
+    }
+
+    fn authorize_update(&self, token: Option<&str>) -> bool {
+        ota::authorized(token)
+    }
+
+    fn start_update(&self) -> Result<()> {
+        if CONFIG.ota_url.is_empty() {
+            bail!("no ota_url configured");
+        }
+        #[cfg(target_os = "espidf")]
+        return ota::esp_update::start_download(CONFIG.ota_url);
+        #[cfg(not(target_os = "espidf"))]
+        bail!("firmware updates only run on the remote")
+    }
+
+    fn update_status(&self) -> OtaStatus {
+        ota::status()
+    }
+
+    fn event_log(&self) -> Vec<String> {
+        event_log::entries()
+    }
+
+    fn services(&self) -> Vec<ServiceStatus> {
+        service_manager::statuses()
+    }
+
+    fn diagnostics(&self) -> Snapshot {
+        diagnostics::latest().unwrap_or_else(diagnostics::sample)
+    }
+
+    fn capture_screen(&self) -> bool {
+        screenshot::capture()
+    }
+
+    fn screenshot(&self) -> Option<Bitmap> {
+        screenshot::captured()
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::Result;
+use esp_idf_svc::http::server::{
+    Configuration, EspHttpConnection, EspHttpServer, Request as HttpRequest,
+};
+use esp_idf_svc::http::Method as HttpMethod;
+use esp_idf_svc::io::{Read, Write};
+use serde_json::json;
+use std::sync::Arc;
+
+use super::router::{self, ApiBackend, Method, Request};
+use crate::ota::{self, esp_update};
+
+//requests are small JSON objects, anything past this is dropped
+const MAX_BODY: usize = 512;
+
+/// Serve the API with the esp-idf http server, it runs on its own task
+/// until the returned server is dropped
+pub fn start<B>(backend: B) -> Result<EspHttpServer<'static>>
+where
+    B: ApiBackend + Send + Sync + 'static,
+{
+    let mut server = EspHttpServer::new(&Configuration {
+        //handlers talk to the plugs, give them room
+        stack_size: 10240,
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    //registered first so the /api/* wildcard doesn't get it
+    server.fn_handler("/api/ota/upload", HttpMethod::Post, upload)?;
+    let backend = Arc::new(backend);
+    let routes = [
+        ("/api/*", HttpMethod::Get, Method::Get),
+        ("/api/*", HttpMethod::Post, Method::Post),
+        ("/api/*", HttpMethod::Put, Method::Put),
+        ("/metrics", HttpMethod::Get, Method::Get),
+    ];
+    for (uri, http_method, method) in routes {
+        let backend = backend.clone();
+        server.fn_handler(uri, http_method, move |mut req| -> Result<()> {
+            let mut body = [0u8; MAX_BODY];
+            let mut len = 0;
+            while len < MAX_BODY {
+                let n = req.read(&mut body[len..])?;
+                if n == 0 {
+                    break;
+                }
+                len += n;
+            }
+            let path = req.uri().to_string();
+            let token = req.header("Authorization").and_then(router::bearer_token);
+            let res = router::handle(
+                &*backend,
+                &Request {
+                    method,
+                    path: &path,
+                    token,
+                    body: &body[..len],
+                },
+            );
+            req.into_response(res.status, None, &[("Content-Type", res.content_type)])?
+                .write_all(&res.body)?;
+            Ok(())
+        })?;
+    }
+    log::info!("http api listening");
+    Ok(server)
+}
+
+//firmware is streamed straight to flash, it never fits the router's body.
+//`POST /api/ota/upload?signature=<hex>` with the image as the body and
+//`Authorization: Bearer <ota_token>`.
+fn upload(mut req: HttpRequest<&mut EspHttpConnection>) -> Result<()> {
+    if !ota::authorized(req.header("Authorization").and_then(router::bearer_token)) {
+        let body = json!({ "error": "firmware updates need the ota_token" });
+        req.into_response(401, None, &[("Content-Type", "application/json")])?
+            .write_all(body.to_string().as_bytes())?;
+        return Ok(());
+    }
+    let uri = req.uri().to_string();
+    let signature = uri
+        .split_once("signature=")
+        .map_or("", |(_, rest)| rest.split('&').next().unwrap_or(""));
+    let size = req.header("Content-Length").and_then(|l| l.parse().ok());
+    let result = esp_update::upload(|buf| Ok(req.read(buf)?), size, signature);
+    let body = match &result {
+        Ok(()) => json!({ "state": "rebooting" }),
+        Err(e) => json!({ "error": format!("{:}", e) }),
+    };
+    let status = if result.is_ok() { 200 } else { 400 };
+    req.into_response(status, None, &[("Content-Type", "application/json")])?
+        .write_all(body.to_string().as_bytes())?;
+    if result.is_ok() {
+        esp_update::restart_soon();
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//Bare std server for running the API on a PC against real plugs,
+//one connection at a time is plenty for that.
+
+use anyhow::{anyhow, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use super::router::{self, ApiBackend, Method, Request};
+
+const MAX_BODY: usize = 512;
+
+pub fn serve<B: ApiBackend>(addr: &str, backend: &B) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!("http api listening on {:}", addr);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("accept failed: {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = handle_connection(stream, backend) {
+            log::warn!("request failed: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle_connection<B: ApiBackend>(mut stream: TcpStream, backend: &B) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = Method::parse(parts.next().ok_or_else(|| anyhow!("empty request"))?);
+    let path = parts.next().ok_or_else(|| anyhow!("no path"))?.to_string();
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>()?.min(MAX_BODY);
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    let res = router::handle(
+        backend,
+        &Request {
+            method,
+            path: &path,
+            token: authorization.as_deref().and_then(router::bearer_token),
+            body: &body,
+        },
+    );
+    write!(
+        stream,
+        "HTTP/1.1 {:} {:}\r\nContent-Type: {:}\r\nContent-Length: {:}\r\nConnection: close\r\n\r\n",
+        res.status,
+        reason(res.status),
+        res.content_type,
+        res.body.len(),
+    )?;
+    stream.write_all(&res.body)?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        409 => "Conflict",
+        502 => "Bad Gateway",
+        _ => "",
+    }
+}
@@ -0,0 +1,659 @@
+//Transport independent side of the HTTP API. Both servers turn whatever
+//they received into a `Request` and write back the `Response`, so this part
+//only needs std and serde_json and runs the same on the remote and a PC.
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::diagnostics::Snapshot;
+use crate::kasa::hub::{Device, Outlet};
+use crate::kasa::light::{LightChange, LightState};
+use crate::metrics;
+use crate::ota::OtaStatus;
+use crate::peripheral_util::battery_monitor::BatteryState;
+use crate::peripheral_util::bitmap::Bitmap;
+use crate::peripheral_util::screenshot;
+use crate::service_manager::ServiceStatus;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Other,
+}
+
+impl Method {
+    pub fn parse(method: &str) -> Self {
+        match method {
+            "GET" => Method::Get,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            _ => Method::Other,
+        }
+    }
+}
+
+pub struct Request<'a> {
+    pub method: Method,
+    /// Path with any query string still attached
+    pub path: &'a str,
+    /// Bearer token from the Authorization header
+    pub token: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+/// The token out of an `Authorization: Bearer <token>` header value
+pub fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then_some(token.trim())
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, msg: &str) -> Self {
+        Self::json(status, json!({ "error": msg }))
+    }
+}
+
+/// Everything the API can see or do, the real one is the Kasa hub plus the
+/// battery monitor
+pub trait ApiBackend {
+    fn devices(&self) -> Vec<Device>;
+    /// `None` toggles, returns the new state
+    fn set_outlet(&self, dev: usize, outlet: usize, on: Option<bool>) -> Result<bool>;
+    /// Brightness and color of a bulb or dimmer, returns the new state
+    fn set_light(&self, dev: usize, change: &LightChange) -> Result<LightState>;
+    fn scene_names(&self) -> Vec<String>;
+    fn run_scene(&self, name: &str) -> Result<()>;
+    fn battery(&self) -> BatteryState;
+    /// Whether `token` may start a firmware update
+    fn authorize_update(&self, token: Option<&str>) -> bool;
+    /// Download and install the signed firmware at `ota_url`
+    fn start_update(&self) -> Result<()>;
+    fn update_status(&self) -> OtaStatus;
+    /// Persistent event log, oldest first
+    fn event_log(&self) -> Vec<String>;
+    /// Background services in the order they were started
+    fn services(&self) -> Vec<ServiceStatus>;
+    /// Latest stack, heap and CPU numbers
+    fn diagnostics(&self) -> Snapshot;
+    /// Keep what's on screen for `screenshot`, false if nothing was drawn yet
+    fn capture_screen(&self) -> bool;
+    /// The last capture
+    fn screenshot(&self) -> Option<Bitmap>;
+}
+
+fn outlet_json(outlet: &Outlet) -> Value {
+    json!({
+        "alias": outlet.alias,
+        "on": outlet.on,
+        "realtime": outlet.realtime.as_ref().map(|rt| json!({
+            "current_ma": rt.current_ma,
+            "voltage_mv": rt.voltage_mv,
+            "power_mw": rt.power_mw,
+            "total_wh": rt.total_wh,
+        })),
+    })
+}
+
+fn light_json(state: &LightState) -> Value {
+    json!({
+        "on": state.on,
+        "brightness": state.brightness,
+        "color_temp": state.color_temp,
+        "hue": state.hue,
+        "saturation": state.saturation,
+    })
+}
+
+//anything missing or out of range is left alone rather than rejected
+fn light_change(body: &Value) -> LightChange {
+    let field = |name: &str| body[name].as_u64();
+    LightChange {
+        on: body["on"].as_bool(),
+        brightness: field("brightness").map(|v| v.min(100) as u8),
+        color_temp: field("color_temp").map(|v| v.min(u16::MAX as u64) as u16),
+        hue: field("hue").map(|v| v.min(360) as u16),
+        saturation: field("saturation").map(|v| v.min(100) as u8),
+    }
+}
+
+fn device_json(idx: usize, device: &Device) -> Value {
+    json!({
+        "id": idx,
+        "addr": device.addr,
+        "alias": device.alias,
+        "model": device.model,
+        "kind": device.kind.name(),
+        "light": device.light.as_ref().map(light_json),
+        "reachable": device.reachable,
+        "outlets": device.outlets.iter().map(outlet_json).collect::<Vec<_>>(),
+    })
+}
+
+fn battery_json(state: &BatteryState) -> Value {
+    if !state.valid {
+        return json!({ "valid": false });
+    }
+    json!({
+        "valid": true,
+        "soc": state.soc,
+        "voltage": state.voltage,
+        "rate": state.rate,
+        "charging": state.charging,
+        "low": state.low,
+        "remaining_s": state.remaining.map(|d| d.as_secs()),
+    })
+}
+
+fn ota_json(status: &OtaStatus) -> Value {
+    match status {
+        OtaStatus::Idle => json!({ "state": "idle" }),
+        OtaStatus::Running { written, size } => {
+            json!({ "state": "running", "written": written, "size": size })
+        }
+        OtaStatus::Failed(e) => json!({ "state": "failed", "error": e }),
+        OtaStatus::Rebooting => json!({ "state": "rebooting" }),
+    }
+}
+
+fn service_json(status: &ServiceStatus) -> Value {
+    json!({
+        "name": status.spec.name,
+        "state": status.state.name(),
+        "priority": status.spec.priority,
+        "stack_size": status.spec.stack_size,
+        "depends_on": status.spec.depends_on,
+        "since_heartbeat_ms": status.since_heartbeat.map(|d| d.as_millis() as u64),
+    })
+}
+
+fn diagnostics_json(snapshot: &Snapshot) -> Value {
+    json!({
+        "uptime_s": snapshot.uptime.as_secs(),
+        "heap": {
+            "free": snapshot.heap.free,
+            "min_free": snapshot.heap.min_free,
+            "largest_block": snapshot.heap.largest_block,
+        },
+        "tasks": snapshot.tasks.iter().map(|task| json!({
+            "name": task.name,
+            "priority": task.priority,
+            "stack_free_min": task.stack_free_min,
+            "stack_size": task.stack_size,
+            "cpu_percent": task.cpu_percent,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+/// Answer one API request
+pub fn handle(backend: &dyn ApiBackend, req: &Request) -> Response {
+    let path = req.path.split('?').next().unwrap_or("");
+    let parts: Vec<&str> = path
+        .trim_matches('/')
+        .split('/')
+        .filter(|p| !p.is_empty())
+        .collect();
+    match (req.method, parts.as_slice()) {
+        (Method::Get, ["api", "devices"]) => {
+            let devices = backend.devices();
+            Response::json(
+                200,
+                Value::Array(
+                    devices
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, d)| device_json(idx, d))
+                        .collect(),
+                ),
+            )
+        }
+        (Method::Get, ["api", "devices", dev]) => match index(dev) {
+            Some(dev) => match backend.devices().get(dev) {
+                Some(device) => Response::json(200, device_json(dev, device)),
+                None => Response::error(404, "no such device"),
+            },
+            None => Response::error(400, "bad device id"),
+        },
+        (Method::Post | Method::Put, ["api", "devices", dev, "outlets", outlet]) => {
+            let on = serde_json::from_slice::<Value>(req.body)
+                .ok()
+                .and_then(|body| body["on"].as_bool());
+            match on {
+                Some(on) => set_outlet(backend, dev, outlet, Some(on)),
+                None => Response::error(400, "expected {\"on\": true|false}"),
+            }
+        }
+        (Method::Post, ["api", "devices", dev, "outlets", outlet, "toggle"]) => {
+            set_outlet(backend, dev, outlet, None)
+        }
+        (Method::Post | Method::Put, ["api", "devices", dev, "light"]) => {
+            let change = serde_json::from_slice::<Value>(req.body)
+                .map(|body| light_change(&body))
+                .unwrap_or_default();
+            if change.is_empty() {
+                return Response::error(
+                    400,
+                    "expected any of on, brightness, color_temp, hue, saturation",
+                );
+            }
+            set_light(backend, dev, &change)
+        }
+        (Method::Get, ["api", "scenes"]) => Response::json(200, json!(backend.scene_names())),
+        (Method::Post, ["api", "scenes", name]) => {
+            if !backend.scene_names().iter().any(|n| n == name) {
+                return Response::error(404, "no such scene");
+            }
+            match backend.run_scene(name) {
+                Ok(()) => Response::json(200, json!({ "scene": name })),
+                Err(e) => Response::error(502, &format!("{:}", e)),
+            }
+        }
+        (Method::Get, ["api", "battery"]) => Response::json(200, battery_json(&backend.battery())),
+        (Method::Get, ["api", "ota"]) => Response::json(200, ota_json(&backend.update_status())),
+        (Method::Post, ["api", "ota"]) => {
+            if !backend.authorize_update(req.token) {
+                return Response::error(401, "firmware updates need the ota_token");
+            }
+            //the image only ever comes from the configured url
+            let body = serde_json::from_slice::<Value>(req.body).unwrap_or(Value::Null);
+            if !body["url"].is_null() || !body["sha256"].is_null() {
+                return Response::error(400, "the firmware url is only taken from ota_url");
+            }
+            match backend.start_update() {
+                Ok(()) => Response::json(202, ota_json(&backend.update_status())),
+                Err(e) => Response::error(409, &format!("{:}", e)),
+            }
+        }
+        (Method::Get, ["api", "services"]) => Response::json(
+            200,
+            Value::Array(backend.services().iter().map(service_json).collect()),
+        ),
+        (Method::Get, ["api", "diagnostics"]) => {
+            Response::json(200, diagnostics_json(&backend.diagnostics()))
+        }
+        (Method::Get, ["api", "log"]) => {
+            let mut body = backend.event_log().join("\n");
+            body.push('\n');
+            Response {
+                status: 200,
+                content_type: "text/plain; charset=utf-8",
+                body: body.into_bytes(),
+            }
+        }
+        (Method::Post, ["api", "screenshot"]) => {
+            if backend.capture_screen() {
+                Response::json(200, json!({ "captured": true }))
+            } else {
+                Response::error(409, "nothing drawn yet")
+            }
+        }
+        (Method::Get, ["api", "screenshot.png"]) => {
+            screenshot_response(backend, "image/png", screenshot::png)
+        }
+        (Method::Get, ["api", "screenshot.pbm"]) => {
+            screenshot_response(backend, "image/x-portable-bitmap", screenshot::pbm)
+        }
+        (Method::Get, ["metrics"]) => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics::render(&backend.devices(), &backend.battery()).into_bytes(),
+        },
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn screenshot_response(
+    backend: &dyn ApiBackend,
+    content_type: &'static str,
+    encode: fn(&Bitmap) -> Vec<u8>,
+) -> Response {
+    match backend.screenshot() {
+        Some(bitmap) => Response {
+            status: 200,
+            content_type,
+            body: encode(&bitmap),
+        },
+        None => Response::error(404, "no screenshot taken yet"),
+    }
+}
+
+fn index(s: &str) -> Option<usize> {
+    s.parse().ok()
+}
+
+fn set_outlet(backend: &dyn ApiBackend, dev: &str, outlet: &str, on: Option<bool>) -> Response {
+    let (dev, outlet) = match (index(dev), index(outlet)) {
+        (Some(dev), Some(outlet)) => (dev, outlet),
+        _ => return Response::error(400, "bad device or outlet id"),
+    };
+    //tell a typo apart from a device that didn't answer
+    let known = backend
+        .devices()
+        .get(dev)
+        .is_some_and(|d| outlet < d.outlets.len());
+    if !known {
+        return Response::error(404, "no such outlet");
+    }
+    match backend.set_outlet(dev, outlet, on) {
+        Ok(on) => Response::json(200, json!({ "device": dev, "outlet": outlet, "on": on })),
+        Err(e) => Response::error(502, &format!("{:}", e)),
+    }
+}
+
+fn set_light(backend: &dyn ApiBackend, dev: &str, change: &LightChange) -> Response {
+    let dev = match index(dev) {
+        Some(dev) => dev,
+        None => return Response::error(400, "bad device id"),
+    };
+    match backend.devices().get(dev) {
+        Some(device) if device.light.is_some() => (),
+        Some(_) => return Response::error(404, "device is not a light"),
+        None => return Response::error(404, "no such device"),
+    }
+    match backend.set_light(dev, change) {
+        Ok(state) => Response::json(200, light_json(&state)),
+        Err(e) => Response::error(502, &format!("{:}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::HeapStats;
+    use crate::kasa::light::DeviceKind;
+    use anyhow::bail;
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    //two devices, a strip with two outlets and a plug, and one scene
+    struct FakeBackend {
+        devices: RefCell<Vec<Device>>,
+        battery: BatteryState,
+        calls: RefCell<Vec<String>>,
+    }
+
+    fn outlet(alias: &str, on: bool) -> Outlet {
+        Outlet {
+            id: None,
+            alias: alias.to_string(),
+            on,
+            realtime: None,
+        }
+    }
+
+    fn device(alias: &str, kind: DeviceKind, outlets: Vec<Outlet>) -> Device {
+        Device {
+            addr: format!("{:}.local", alias),
+            alias: alias.to_string(),
+            model: "HS300".to_string(),
+            kind,
+            light: None,
+            outlets,
+            reachable: true,
+        }
+    }
+
+    impl FakeBackend {
+        fn new() -> Self {
+            Self {
+                devices: RefCell::new(vec![
+                    device(
+                        "strip",
+                        DeviceKind::Strip,
+                        vec![outlet("lamp", false), outlet("fan", true)],
+                    ),
+                    device("plug", DeviceKind::Plug, vec![outlet("plug", false)]),
+                ]),
+                battery: BatteryState {
+                    soc: 50.0,
+                    voltage: 3.75,
+                    rate: -2.0,
+                    charging: false,
+                    low: false,
+                    remaining: Some(Duration::from_secs(90_000)),
+                    valid: true,
+                },
+                calls: RefCell::new(vec![]),
+            }
+        }
+    }
+
+    impl ApiBackend for FakeBackend {
+        fn devices(&self) -> Vec<Device> {
+            self.devices.borrow().clone()
+        }
+
+        fn set_outlet(&self, dev: usize, outlet: usize, on: Option<bool>) -> Result<bool> {
+            self.calls
+                .borrow_mut()
+                .push(format!("outlet {:} {:} {:?}", dev, outlet, on));
+            let mut devices = self.devices.borrow_mut();
+            let outlet = &mut devices[dev].outlets[outlet];
+            outlet.on = on.unwrap_or(!outlet.on);
+            Ok(outlet.on)
+        }
+
+        fn set_light(&self, _dev: usize, _change: &LightChange) -> Result<LightState> {
+            bail!("no lights here")
+        }
+
+        fn scene_names(&self) -> Vec<String> {
+            vec!["evening".to_string()]
+        }
+
+        fn run_scene(&self, name: &str) -> Result<()> {
+            self.calls.borrow_mut().push(format!("scene {:}", name));
+            Ok(())
+        }
+
+        fn battery(&self) -> BatteryState {
+            self.battery
+        }
+
+        fn authorize_update(&self, token: Option<&str>) -> bool {
+            token == Some("s3cret")
+        }
+
+        fn start_update(&self) -> Result<()> {
+            self.calls.borrow_mut().push("update".to_string());
+            Ok(())
+        }
+
+        fn update_status(&self) -> OtaStatus {
+            OtaStatus::Idle
+        }
+
+        fn event_log(&self) -> Vec<String> {
+            vec![]
+        }
+
+        fn services(&self) -> Vec<ServiceStatus> {
+            vec![]
+        }
+
+        fn diagnostics(&self) -> Snapshot {
+            Snapshot {
+                uptime: Duration::ZERO,
+                heap: HeapStats::default(),
+                tasks: vec![],
+            }
+        }
+
+        fn capture_screen(&self) -> bool {
+            false
+        }
+
+        fn screenshot(&self) -> Option<Bitmap> {
+            None
+        }
+    }
+
+    fn request(backend: &FakeBackend, method: Method, path: &str, body: &str) -> (u16, Value) {
+        request_as(backend, None, method, path, body)
+    }
+
+    fn request_as(
+        backend: &FakeBackend,
+        token: Option<&str>,
+        method: Method,
+        path: &str,
+        body: &str,
+    ) -> (u16, Value) {
+        let res = handle(
+            backend,
+            &Request {
+                method,
+                path,
+                token,
+                body: body.as_bytes(),
+            },
+        );
+        assert_eq!(res.content_type, "application/json");
+        (res.status, serde_json::from_slice(&res.body).unwrap())
+    }
+
+    #[test]
+    fn lists_devices() {
+        let backend = FakeBackend::new();
+        let (status, body) = request(&backend, Method::Get, "/api/devices", "");
+        assert_eq!(status, 200);
+        let devices = body.as_array().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0]["id"], 0);
+        assert_eq!(devices[0]["kind"], "strip");
+        assert_eq!(devices[0]["outlets"][1]["alias"], "fan");
+        assert_eq!(devices[1]["alias"], "plug");
+
+        let (status, body) = request(&backend, Method::Get, "/api/devices/1", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["id"], 1);
+    }
+
+    #[test]
+    fn toggles_an_outlet() {
+        let backend = FakeBackend::new();
+        let path = "/api/devices/0/outlets/1/toggle";
+        let (status, body) = request(&backend, Method::Post, path, "");
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({ "device": 0, "outlet": 1, "on": false }));
+        assert_eq!(*backend.calls.borrow(), ["outlet 0 1 None"]);
+    }
+
+    #[test]
+    fn switches_an_outlet() {
+        let backend = FakeBackend::new();
+        let path = "/api/devices/0/outlets/0";
+        let (status, body) = request(&backend, Method::Put, path, r#"{"on": true}"#);
+        assert_eq!(status, 200);
+        assert_eq!(body["on"], true);
+        assert_eq!(*backend.calls.borrow(), ["outlet 0 0 Some(true)"]);
+    }
+
+    #[test]
+    fn runs_a_scene() {
+        let backend = FakeBackend::new();
+        let (status, body) = request(&backend, Method::Post, "/api/scenes/evening", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["scene"], "evening");
+        assert_eq!(*backend.calls.borrow(), ["scene evening"]);
+
+        let (status, body) = request(&backend, Method::Get, "/api/scenes", "");
+        assert_eq!(status, 200);
+        assert_eq!(body, json!(["evening"]));
+    }
+
+    #[test]
+    fn reads_the_battery() {
+        let mut backend = FakeBackend::new();
+        let (status, body) = request(&backend, Method::Get, "/api/battery", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["valid"], true);
+        assert_eq!(body["soc"], 50.0);
+        assert_eq!(body["charging"], false);
+        assert_eq!(body["remaining_s"], 90_000);
+
+        backend.battery.valid = false;
+        let (_, body) = request(&backend, Method::Get, "/api/battery", "");
+        assert_eq!(body, json!({ "valid": false }));
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let backend = FakeBackend::new();
+        let cases = [
+            (Method::Get, "/api/nothing", "", 404),
+            (Method::Get, "/api/devices/7", "", 404),
+            (Method::Get, "/api/devices/x", "", 400),
+            (Method::Post, "/api/devices/0/outlets/5/toggle", "", 404),
+            (Method::Post, "/api/devices/0/outlets/y/toggle", "", 400),
+            (Method::Put, "/api/devices/0/outlets/0", "on", 400),
+            (Method::Put, "/api/devices/0/outlets/0", r#"{"on": 1}"#, 400),
+            (Method::Post, "/api/devices/1/light", r#"{"on": true}"#, 404),
+            (Method::Post, "/api/devices/0/light", "{}", 400),
+            (Method::Post, "/api/scenes/morning", "", 404),
+            //right path, wrong method
+            (Method::Get, "/api/scenes/evening", "", 404),
+            (Method::Other, "/api/devices", "", 404),
+        ];
+        for (method, path, body, expected) in cases {
+            let (status, body) = request(&backend, method, path, body);
+            assert_eq!(status, expected, "{:?} {:}", method, path);
+            assert!(body["error"].is_string());
+        }
+        assert!(backend.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn updates_need_the_token() {
+        let backend = FakeBackend::new();
+        for token in [None, Some(""), Some("s3cre"), Some("S3CRET")] {
+            let (status, _) = request_as(&backend, token, Method::Post, "/api/ota", "");
+            assert_eq!(status, 401, "{:?}", token);
+        }
+        assert!(backend.calls.borrow().is_empty());
+
+        let (status, body) = request_as(&backend, Some("s3cret"), Method::Post, "/api/ota", "");
+        assert_eq!(status, 202);
+        assert_eq!(body["state"], "idle");
+        assert_eq!(*backend.calls.borrow(), ["update"]);
+    }
+
+    #[test]
+    fn updates_only_come_from_ota_url() {
+        let backend = FakeBackend::new();
+        let bodies = [
+            r#"{"url": "http://example.com/evil.bin"}"#,
+            r#"{"sha256": "00"}"#,
+        ];
+        for body in bodies {
+            let (status, _) = request_as(&backend, Some("s3cret"), Method::Post, "/api/ota", body);
+            assert_eq!(status, 400, "{:}", body);
+        }
+        assert!(backend.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn reads_bearer_tokens() {
+        assert_eq!(bearer_token("Bearer s3cret"), Some("s3cret"));
+        assert_eq!(bearer_token("bearer  s3cret "), Some("s3cret"));
+        assert_eq!(bearer_token("Basic czNjcmV0"), None);
+        assert_eq!(bearer_token("s3cret"), None);
+    }
+}
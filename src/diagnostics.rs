@@ -0,0 +1,229 @@
+//Stack, heap and CPU numbers for tuning the stack sizes in `main.rs` with
+//evidence. Sampled every so often from the main loop, CPU use is over the
+//time since the previous sample.
+
+#[cfg(target_os = "espidf")]
+mod esp_tasks;
+#[cfg(target_os = "espidf")]
+use esp_tasks::{heap, tasks};
+
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+use crate::service_manager;
+
+const SAMPLE_EVERY: Duration = Duration::from_secs(10);
+const LOG_EVERY: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub struct TaskStats {
+    pub name: String,
+    pub priority: u32,
+    /// Least free stack the task has ever had, in bytes
+    pub stack_free_min: u32,
+    /// Stack it was given, only known for the tasks the service manager started
+    pub stack_size: Option<u32>,
+    /// Share of both cores since the previous sample, None on the first one
+    pub cpu_percent: Option<f32>,
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct HeapStats {
+    pub free: u32,
+    /// Lowest free heap since boot
+    pub min_free: u32,
+    /// Biggest single allocation that would still succeed
+    pub largest_block: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub uptime: Duration,
+    pub heap: HeapStats,
+    /// Least free stack first
+    pub tasks: Vec<TaskStats>,
+}
+
+//run time counter of every task and the total, as of the last sample. The
+//counters are 32 bit and wrap, so differences are taken before widening.
+#[derive(Default)]
+struct RunTimes {
+    total: u32,
+    tasks: Vec<(String, u32)>,
+}
+
+//the run time total is wall time, two busy cores add up to twice that
+const CORES: f32 = 2.0;
+
+static PREVIOUS: Mutex<Option<RunTimes>> = Mutex::new(None);
+static LATEST: Mutex<Option<Snapshot>> = Mutex::new(None);
+
+#[cfg(not(target_os = "espidf"))]
+fn heap() -> HeapStats {
+    HeapStats::default()
+}
+
+//a PC has no FreeRTOS tasks to look at
+#[cfg(not(target_os = "espidf"))]
+fn tasks() -> (Vec<(TaskStats, u32)>, u32) {
+    (vec![], 0)
+}
+
+//share of both cores a task used, from its run time counter now and at the
+//previous sample and the total run time between them
+fn cpu_percent(run_time: u32, before: u32, elapsed: u32) -> Option<f32> {
+    if elapsed == 0 {
+        return None;
+    }
+    Some(run_time.wrapping_sub(before) as f32 * 100.0 / elapsed as f32 / CORES)
+}
+
+/// Take a fresh sample, keep it for `latest` and return it
+pub fn sample() -> Snapshot {
+    let (raw, total) = tasks();
+    let mut previous = PREVIOUS.lock().unwrap();
+    let elapsed = previous.as_ref().map(|p| total.wrapping_sub(p.total));
+    let sizes = service_manager::statuses();
+    let mut tasks: Vec<TaskStats> = raw
+        .iter()
+        .map(|(task, run_time)| {
+            let before = previous
+                .as_ref()
+                .and_then(|p| p.tasks.iter().find(|(name, _)| *name == task.name))
+                .map(|(_, before)| *before);
+            let cpu_percent = match (elapsed, before) {
+                (Some(elapsed), Some(before)) => cpu_percent(*run_time, before, elapsed),
+                _ => None,
+            };
+            TaskStats {
+                stack_size: sizes
+                    .iter()
+                    .find(|s| s.spec.name == task.name)
+                    .map(|s| s.spec.stack_size as u32),
+                cpu_percent,
+                ..task.clone()
+            }
+        })
+        .collect();
+    tasks.sort_by_key(|t| t.stack_free_min);
+    *previous = Some(RunTimes {
+        total,
+        tasks: raw
+            .into_iter()
+            .map(|(t, run_time)| (t.name, run_time))
+            .collect(),
+    });
+
+    let snapshot = Snapshot {
+        uptime: metrics::uptime(),
+        heap: heap(),
+        tasks,
+    };
+    *LATEST.lock().unwrap() = Some(snapshot.clone());
+    snapshot
+}
+
+/// The last sample taken, None before the first
+pub fn latest() -> Option<Snapshot> {
+    LATEST.lock().unwrap().clone()
+}
+
+/// "1d 02:03:04" or "02:03:04"
+pub fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let clock = format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    );
+    match secs / 86_400 {
+        0 => clock,
+        days => format!("{:}d {:}", days, clock),
+    }
+}
+
+/// The whole snapshot as log lines
+pub fn log_snapshot(snapshot: &Snapshot) {
+    log::info!(
+        "diag: up {:} heap free {:} min {:} largest {:}",
+        format_uptime(snapshot.uptime),
+        snapshot.heap.free,
+        snapshot.heap.min_free,
+        snapshot.heap.largest_block
+    );
+    for task in &snapshot.tasks {
+        let mut line = format!(
+            "diag: {:<16} prio {:>2} stack free {:>5}",
+            task.name, task.priority, task.stack_free_min
+        );
+        if let Some(size) = task.stack_size {
+            let _ = write!(line, " of {:>5}", size);
+        }
+        if let Some(cpu) = task.cpu_percent {
+            let _ = write!(line, " cpu {:>5.1}%", cpu);
+        }
+        log::info!("{:}", line);
+    }
+}
+
+/// Samples every `SAMPLE_EVERY` and writes the numbers to the log every
+/// `LOG_EVERY`, polled from the main loop
+pub struct Sampler {
+    next_sample: Instant,
+    next_log: Instant,
+}
+
+impl Sampler {
+    pub fn new() -> Self {
+        Self {
+            next_sample: Instant::now(),
+            next_log: Instant::now() + LOG_EVERY,
+        }
+    }
+
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        if now < self.next_sample {
+            return;
+        }
+        self.next_sample = now + SAMPLE_EVERY;
+        let snapshot = sample();
+        if now >= self.next_log {
+            self.next_log = now + LOG_EVERY;
+            log_snapshot(&snapshot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_is_shared_over_both_cores() {
+        assert_eq!(cpu_percent(1_500, 500, 1_000), Some(50.0));
+        assert_eq!(cpu_percent(2_500, 500, 1_000), Some(100.0));
+        assert_eq!(cpu_percent(500, 500, 1_000), Some(0.0));
+    }
+
+    #[test]
+    fn cpu_survives_counter_wrap() {
+        //the task counter went past u32::MAX since the previous sample
+        let before = u32::MAX - 99;
+        assert_eq!(cpu_percent(400, before, 1_000), Some(25.0));
+    }
+
+    #[test]
+    fn no_cpu_without_elapsed_time() {
+        assert_eq!(cpu_percent(100, 0, 0), None);
+    }
+
+    #[test]
+    fn uptime_formats() {
+        assert_eq!(format_uptime(Duration::from_secs(3_723)), "01:02:03");
+        assert_eq!(format_uptime(Duration::from_secs(93_784)), "1d 02:03:04");
+    }
+}
@@ -0,0 +1,47 @@
+use esp_idf_svc::sys;
+use std::ffi::CStr;
+
+use super::{HeapStats, TaskStats};
+
+pub fn heap() -> HeapStats {
+    unsafe {
+        HeapStats {
+            free: sys::esp_get_free_heap_size(),
+            min_free: sys::esp_get_minimum_free_heap_size(),
+            largest_block: sys::heap_caps_get_largest_free_block(sys::MALLOC_CAP_8BIT) as u32,
+        }
+    }
+}
+
+/// Every FreeRTOS task with its run time counter, and the counter total.
+/// Needs the trace facility and run time stats from `sdkconfig.defaults`.
+pub fn tasks() -> (Vec<(TaskStats, u32)>, u32) {
+    //a little room for tasks started between counting and reading them
+    let capacity = unsafe { sys::uxTaskGetNumberOfTasks() } as usize + 4;
+    let mut status: Vec<sys::TaskStatus_t> = Vec::with_capacity(capacity);
+    let mut total = 0;
+    unsafe {
+        let count = sys::uxTaskGetSystemState(status.as_mut_ptr(), capacity as _, &mut total);
+        status.set_len(count as usize);
+    }
+    let tasks = status
+        .iter()
+        .map(|task| {
+            let name = unsafe { CStr::from_ptr(task.pcTaskName) }
+                .to_string_lossy()
+                .into_owned();
+            (
+                TaskStats {
+                    name,
+                    priority: task.uxCurrentPriority as u32,
+                    //ESP-IDF counts stack in bytes rather than words
+                    stack_free_min: task.usStackHighWaterMark as u32,
+                    stack_size: None,
+                    cpu_percent: None,
+                },
+                task.ulRunTimeCounter as u32,
+            )
+        })
+        .collect();
+    (tasks, total as u32)
+}
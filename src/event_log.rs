@@ -0,0 +1,192 @@
+//Panics, service restarts, Wi-Fi drops and Kasa errors, newest last. On the
+//remote every entry is also written to NVS by `nvs_store` so the log survives
+//a crash or a brown out, elsewhere it only lives as long as the process.
+
+#[cfg(target_os = "espidf")]
+pub mod nvs_store;
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::kasa::energy::{self, Date};
+
+/// How many entries are kept
+pub const CAPACITY: usize = 48;
+//NVS strings are stored in 32 byte chunks, keep entries to three of them
+const MAX_ENTRY: usize = 90;
+
+struct EventLog {
+    entries: VecDeque<String>,
+    boot: u32,
+    started: Instant,
+    #[cfg(target_os = "espidf")]
+    store: Option<nvs_store::NvsStore>,
+}
+
+impl EventLog {
+    fn new(boot: u32, entries: Vec<String>) -> Self {
+        Self {
+            entries: entries.into(),
+            boot,
+            started: Instant::now(),
+            #[cfg(target_os = "espidf")]
+            store: None,
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        let when = stamp(energy::local_secs(), self.started.elapsed());
+        let mut entry = format!("#{:} {:} {:}", self.boot, when, line);
+        entry = entry.replace(['\r', '\n'], " ");
+        if entry.len() > MAX_ENTRY {
+            let mut end = MAX_ENTRY;
+            while !entry.is_char_boundary(end) {
+                end -= 1;
+            }
+            entry.truncate(end);
+        }
+        #[cfg(target_os = "espidf")]
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.write(&entry) {
+                log::warn!("event log write failed: {:?}", e);
+            }
+        }
+        if self.entries.len() >= CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+static LOG: Mutex<Option<EventLog>> = Mutex::new(None);
+
+//wall clock once SNTP has it, seconds since boot before that
+fn stamp(local_secs: Option<i64>, since_boot: Duration) -> String {
+    match local_secs {
+        Some(secs) => {
+            let date = Date::from_days(secs.div_euclid(86_400));
+            let time = secs.rem_euclid(86_400);
+            format!(
+                "{:02}-{:02} {:02}:{:02}:{:02}",
+                date.month,
+                date.day,
+                time / 3600,
+                time / 60 % 60,
+                time % 60
+            )
+        }
+        None => format!("+{:}s", since_boot.as_secs()),
+    }
+}
+
+/// Add a line, tagged with the boot it happened in and when
+pub fn record(line: &str) {
+    let mut log = LOG.lock().unwrap();
+    log.get_or_insert_with(|| EventLog::new(0, vec![]))
+        .push(line);
+}
+
+/// Everything kept, oldest first
+pub fn entries() -> Vec<String> {
+    match LOG.lock().unwrap().as_ref() {
+        Some(log) => log.entries.iter().cloned().collect(),
+        None => vec![],
+    }
+}
+
+/// Split an entry into its boot, time and message
+pub fn parse(entry: &str) -> (&str, &str, &str) {
+    let (boot, rest) = entry.split_once(' ').unwrap_or((entry, ""));
+    //wall clock stamps have a space in them
+    let stamp_len = match rest.as_bytes().first() {
+        Some(b'+') => rest.find(' ').unwrap_or(rest.len()),
+        _ => rest
+            .match_indices(' ')
+            .nth(1)
+            .map_or(rest.len(), |(i, _)| i),
+    };
+    let (stamp, message) = rest.split_at(stamp_len);
+    (boot, stamp, message.trim_start())
+}
+
+/// Record panics before the default hook prints them and the remote resets
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        //the panic may have happened while this thread held the log
+        for _ in 0..10 {
+            if let Ok(mut log) = LOG.try_lock() {
+                if let Some(log) = log.as_mut() {
+                    log.push(&format!("panic: {:}", info));
+                }
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        default_hook(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_wall_clock_once_synced() {
+        //2024-03-05 06:07:08
+        let secs = 1_709_618_828;
+        assert_eq!(stamp(Some(secs), Duration::ZERO), "03-05 06:07:08");
+        assert_eq!(stamp(None, Duration::from_millis(42_900)), "+42s");
+    }
+
+    #[test]
+    fn parses_both_stamps() {
+        assert_eq!(
+            parse("#3 03-05 06:07:08 wifi: down"),
+            ("#3", "03-05 06:07:08", "wifi: down")
+        );
+        assert_eq!(parse("#3 +42s wifi: down"), ("#3", "+42s", "wifi: down"));
+    }
+
+    #[test]
+    fn parses_short_entries() {
+        assert_eq!(parse("#3"), ("#3", "", ""));
+        assert_eq!(parse("#3 +42s"), ("#3", "+42s", ""));
+        assert_eq!(parse("#3 03-05 06:07:08"), ("#3", "03-05 06:07:08", ""));
+    }
+
+    #[test]
+    fn entries_are_one_line() {
+        let mut log = EventLog::new(1, vec![]);
+        log.push("panic: a\r\nb");
+        let (_, _, message) = parse(&log.entries[0]);
+        assert_eq!(message, "panic: a  b");
+    }
+
+    #[test]
+    fn long_entries_are_cut_on_a_char_boundary() {
+        let mut log = EventLog::new(1, vec![]);
+        log.push(&"a".repeat(200));
+        assert_eq!(log.entries[0].len(), MAX_ENTRY);
+        //two byte chars, one of them straddles MAX_ENTRY whatever the stamp
+        for pad in 0..2 {
+            let line = format!("{:}{:}", "a".repeat(pad), "é".repeat(100));
+            log.push(&line);
+            let entry = log.entries.back().unwrap();
+            assert!(entry.len() <= MAX_ENTRY);
+            assert!(entry.len() >= MAX_ENTRY - 1);
+            assert!(entry.ends_with('é'));
+        }
+    }
+
+    #[test]
+    fn oldest_entries_make_room() {
+        let old = (0..CAPACITY).map(|i| format!("#0 +0s {:}", i)).collect();
+        let mut log = EventLog::new(1, old);
+        log.push("newest");
+        assert_eq!(log.entries.len(), CAPACITY);
+        assert_eq!(parse(&log.entries[0]).2, "1");
+        assert_eq!(parse(log.entries.back().unwrap()).2, "newest");
+    }
+}
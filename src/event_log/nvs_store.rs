@@ -0,0 +1,70 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys;
+
+use super::{EventLog, CAPACITY, LOG, MAX_ENTRY};
+
+const NAMESPACE: &str = "eventlog";
+const KEY_NEXT: &str = "next";
+const KEY_BOOT: &str = "boot";
+
+/// Entries go round a fixed set of keys, the oldest one is overwritten first
+pub struct NvsStore {
+    nvs: EspNvs<NvsDefault>,
+    next: u8,
+}
+
+fn entry_key(idx: u8) -> String {
+    format!("e{:}", idx)
+}
+
+impl NvsStore {
+    //oldest first, starting from the slot that gets overwritten next
+    fn load(&self) -> Vec<String> {
+        let mut buf = [0u8; MAX_ENTRY + 1];
+        (0..CAPACITY as u8)
+            .map(|offset| (self.next + offset) % CAPACITY as u8)
+            .filter_map(|idx| match self.nvs.get_str(&entry_key(idx), &mut buf) {
+                Ok(Some(entry)) => Some(entry.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn write(&mut self, entry: &str) -> Result<()> {
+        let next = (self.next + 1) % CAPACITY as u8;
+        self.nvs.set_str(&entry_key(self.next), entry)?;
+        self.nvs.set_u8(KEY_NEXT, next)?;
+        self.next = next;
+        Ok(())
+    }
+}
+
+fn reset_reason() -> Option<&'static str> {
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_PANIC => Some("panic"),
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT => Some("interrupt watchdog"),
+        sys::esp_reset_reason_t_ESP_RST_TASK_WDT => Some("task watchdog"),
+        sys::esp_reset_reason_t_ESP_RST_WDT => Some("watchdog"),
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => Some("brown out"),
+        _ => None,
+    }
+}
+
+/// Load what earlier boots logged and count this boot, nothing is kept in
+/// flash before this. Also notes if the last boot ended in a crash.
+pub fn init(partition: EspDefaultNvsPartition) -> Result<()> {
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let next = nvs.get_u8(KEY_NEXT)?.unwrap_or(0) % CAPACITY as u8;
+    let boot = nvs.get_u32(KEY_BOOT)?.unwrap_or(0).wrapping_add(1);
+    nvs.set_u32(KEY_BOOT, boot)?;
+    let store = NvsStore { nvs, next };
+
+    let mut log = EventLog::new(boot, store.load());
+    log.store = Some(store);
+    if let Some(reason) = reset_reason() {
+        log.push(&format!("reset: {:}", reason));
+    }
+    *LOG.lock().unwrap() = Some(log);
+    Ok(())
+}
@@ -0,0 +1,285 @@
+//Where the background services report what went wrong. Every report is
+//logged, kept in the persistent event log and shown as a toast, and a service
+//that gives up is started again after a growing delay.
+
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::event_log;
+use crate::peripheral_util::display::{DisplayMessage, MessageType};
+use crate::peripheral_util::display_queue::DisplaySender;
+use embedded_graphics::primitives::Rectangle;
+
+/// First delay before restarting a failed service, doubled on every failure
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+//a service that ran this long before failing starts over from BACKOFF_MIN
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+//the same error from the same service again within this is only logged
+const REPEAT_QUIET: Duration = Duration::from_secs(60);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Service {
+    Display,
+    Buttons,
+    Battery,
+    Wifi,
+}
+
+impl Service {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Service::Display => "display",
+            Service::Buttons => "buttons",
+            Service::Battery => "battery",
+            Service::Wifi => "wifi",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ServiceError {
+    /// An I2C transaction with a peripheral failed
+    Bus {
+        device: &'static str,
+        detail: String,
+    },
+    /// A pin couldn't be configured
+    Gpio(String),
+    /// The other end of a channel the service feeds is gone, restarting
+    /// won't bring it back
+    Disconnected(&'static str),
+    Network(String),
+    /// The service was handed something it can't work with
+    Config(&'static str),
+}
+
+impl ServiceError {
+    pub fn bus(device: &'static str, e: impl fmt::Debug) -> Self {
+        ServiceError::Bus {
+            device,
+            detail: format!("{:?}", e),
+        }
+    }
+
+    fn permanent(&self) -> bool {
+        matches!(
+            self,
+            ServiceError::Disconnected(_) | ServiceError::Config(_)
+        )
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceError::Bus { device, detail } => write!(f, "{:} bus error {:}", device, detail),
+            ServiceError::Gpio(detail) => write!(f, "gpio {:}", detail),
+            ServiceError::Disconnected(what) => write!(f, "{:} channel closed", what),
+            ServiceError::Network(detail) => write!(f, "network {:}", detail),
+            ServiceError::Config(what) => write!(f, "bad config: {:}", what),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+pub struct HealthEvent {
+    pub service: Service,
+    pub error: ServiceError,
+}
+
+/// Handle a service reports through, cheap to clone into every thread
+#[derive(Clone)]
+pub struct Reporter {
+    tx: mpsc::Sender<HealthEvent>,
+}
+
+impl Reporter {
+    pub fn new() -> (Self, mpsc::Receiver<HealthEvent>) {
+        let (tx, rx) = mpsc::channel();
+        (Self { tx }, rx)
+    }
+
+    pub fn report(&self, service: Service, error: ServiceError) {
+        let _ = self.tx.send(HealthEvent { service, error });
+    }
+}
+
+/// Delay between restarts, doubling up to a ceiling
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { next: BACKOFF_MIN }
+    }
+
+    pub fn fail(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(BACKOFF_MAX);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = BACKOFF_MIN;
+    }
+}
+
+/// Run a service until it returns Ok, restarting it with backoff whenever it
+/// fails. Errors that a restart can't fix are reported once and end it.
+pub fn supervise(
+    service: Service,
+    reporter: &Reporter,
+    mut run: impl FnMut() -> Result<(), ServiceError>,
+) {
+    let mut backoff = Backoff::new();
+    loop {
+        let started = Instant::now();
+        let error = match run() {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        let permanent = error.permanent();
+        reporter.report(service, error);
+        if permanent {
+            return;
+        }
+        if started.elapsed() > STABLE_AFTER {
+            backoff.reset();
+        }
+        let delay = backoff.fail();
+        let line = format!("{:} restarting in {:}s", service.name(), delay.as_secs());
+        log::warn!("{:}", line);
+        event_log::record(&line);
+        thread::sleep(delay);
+    }
+}
+
+/// Log, record and toast every report until all reporters are gone
+pub fn health_service(rx: mpsc::Receiver<HealthEvent>, disp_tx: DisplaySender) {
+    let mut last: Vec<(Service, ServiceError, Instant)> = vec![];
+    for event in rx {
+        let line = format!("{:}: {:}", event.service.name(), event.error);
+        log::error!("{:}", line);
+        //a flapping peripheral shouldn't bury everything else in the log
+        let repeat = last.iter().any(|(service, error, at)| {
+            *service == event.service && *error == event.error && at.elapsed() < REPEAT_QUIET
+        });
+        last.retain(|(service, _, _)| *service != event.service);
+        last.push((event.service, event.error, Instant::now()));
+        if repeat {
+            continue;
+        }
+        event_log::record(&line);
+        let _ = disp_tx.send(DisplayMessage {
+            module_name: "health".to_string(),
+            content: MessageType::Toast(line),
+            status_line: true,
+            clear_rect: Rectangle::zero(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service_manager::{self, ServiceManager, ServiceSpec, ServiceState};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn spec(name: &'static str) -> ServiceSpec {
+        ServiceSpec {
+            name,
+            stack_size: 64 * 1024,
+            priority: 1,
+            depends_on: &[],
+            liveness: None,
+        }
+    }
+
+    fn state(name: &str) -> Option<ServiceState> {
+        service_manager::check();
+        service_manager::statuses()
+            .into_iter()
+            .find(|s| s.spec.name == name)
+            .map(|s| s.state)
+    }
+
+    fn wait_for_exit(name: &str) {
+        let give_up = Instant::now() + Duration::from_secs(10);
+        while state(name) != Some(ServiceState::Exited) {
+            assert!(Instant::now() < give_up, "{:} never exited", name);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<u64> = (0..8).map(|_| backoff.fail().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[test]
+    fn backoff_reset_starts_over() {
+        let mut backoff = Backoff::new();
+        backoff.fail();
+        backoff.fail();
+        backoff.reset();
+        assert_eq!(backoff.fail(), BACKOFF_MIN);
+        assert_eq!(backoff.fail(), BACKOFF_MIN * 2);
+    }
+
+    #[test]
+    fn failed_service_is_restarted_until_it_succeeds() {
+        let (reporter, rx) = Reporter::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = runs.clone();
+        let mut services = ServiceManager::new();
+        services.register(spec("flaky_test"), move || {
+            supervise(Service::Wifi, &reporter, || {
+                match counted.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(ServiceError::Network("down".to_string())),
+                    _ => Ok(()),
+                }
+            })
+        });
+        services.start().unwrap();
+
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.service, Service::Wifi);
+        assert_eq!(event.error, ServiceError::Network("down".to_string()));
+        wait_for_exit("flaky_test");
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        //the reporter went with the thread, nothing else was reported
+        assert!(matches!(
+            rx.try_recv(),
+            Err(mpsc::TryRecvError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn permanent_errors_are_not_restarted() {
+        let (reporter, rx) = Reporter::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = runs.clone();
+        let mut services = ServiceManager::new();
+        services.register(spec("closed_test"), move || {
+            supervise(Service::Display, &reporter, || {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Err(ServiceError::Disconnected("display"))
+            })
+        });
+        services.start().unwrap();
+
+        wait_for_exit("closed_test");
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let reports: Vec<HealthEvent> = rx.iter().collect();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].error, ServiceError::Disconnected("display"));
+    }
+}
@@ -0,0 +1,22 @@
+pub mod energy;
+pub mod hub;
+pub mod klap;
+#[cfg(not(target_os = "espidf"))]
+pub mod klap_mock;
+pub mod light;
+pub mod protocol;
+pub mod transport;
+
+use crate::CONFIG;
+
+/// Addresses of every Kasa device from `target_ip`, a comma separated list.
+/// `klap://host` marks a device that needs the authenticated protocol.
+pub fn device_addrs() -> Vec<String> {
+    CONFIG
+        .target_ip
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
@@ -0,0 +1,234 @@
+//Energy totals from the emeter's own day and month history, so nothing has
+//to be accumulated on the remote and the numbers survive it being off.
+
+use anyhow::Result;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{hub, protocol};
+use crate::CONFIG;
+
+//anything before this means SNTP hasn't set the clock yet
+const SYNCED_AFTER: i64 = 1_704_067_200; //2024-01-01
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Civil date from days since 1970-01-01, Howard Hinnant's civil_from_days
+    pub fn from_days(days: i64) -> Self {
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+        let year = (yoe + era * 400 + (month <= 2) as i64) as i32;
+        Self { year, month, day }
+    }
+
+    /// Days since 1970-01-01
+    pub fn days(&self) -> i64 {
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let m = self.month as i64;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+
+    /// 0 for Monday through 6 for Sunday
+    pub fn weekday(&self) -> u32 {
+        //1970-01-01 was a Thursday
+        (self.days() + 3).rem_euclid(7) as u32
+    }
+
+    pub fn add_days(&self, n: i64) -> Self {
+        Self::from_days(self.days() + n)
+    }
+
+    /// The Monday of this date's week
+    pub fn week_start(&self) -> Self {
+        self.add_days(-(self.weekday() as i64))
+    }
+}
+
+/// Local time in seconds since 1970-01-01, None until SNTP has set the clock
+pub fn local_secs() -> Option<i64> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    if secs < SYNCED_AFTER {
+        return None;
+    }
+    Some(secs + CONFIG.utc_offset_min as i64 * 60)
+}
+
+/// Local date, None until SNTP has set the clock
+pub fn today() -> Option<Date> {
+    Some(Date::from_days(local_secs()?.div_euclid(86_400)))
+}
+
+/// Price per kWh from `tariff_per_kwh`, None if unset
+pub fn tariff() -> Option<f32> {
+    CONFIG.tariff_per_kwh.trim().parse().ok()
+}
+
+/// Energy in Wh over the current day, week (from Monday) and month
+#[derive(Copy, Clone, Default, Debug)]
+pub struct EnergyStats {
+    pub today: u32,
+    pub week: u32,
+    pub month: u32,
+}
+
+/// Every outlet of a device as (alias, child id), a plug is a single outlet
+pub fn outlets(addr: &str) -> Result<Vec<(String, Option<String>)>> {
+    let info = protocol::sysinfo(addr)?;
+    let device_id = info["deviceId"].as_str().unwrap_or("");
+    Ok(match info["children"].as_array() {
+        Some(children) => children
+            .iter()
+            .map(|child| {
+                let id = hub::child_id(device_id, child);
+                let alias = child["alias"].as_str().unwrap_or(&id).to_string();
+                (alias, Some(id))
+            })
+            .collect(),
+        None => vec![(info["alias"].as_str().unwrap_or(addr).to_string(), None)],
+    })
+}
+
+//Wh logged for `day` in a (day, Wh) daystat list
+fn day_wh(day: u32, list: &[(u32, u32)]) -> u32 {
+    list.iter()
+        .filter(|(d, _)| *d == day)
+        .map(|(_, wh)| wh)
+        .sum()
+}
+
+//Wh from Monday through `today`, `earlier` is the daystat of the month
+//before for a week that started in it
+fn week_wh(today: Date, days: &[(u32, u32)], earlier: &[(u32, u32)]) -> u32 {
+    let monday = today.week_start();
+    (0..=today.weekday() as i64)
+        .map(|n| monday.add_days(n))
+        .map(|date| {
+            if date.month == today.month {
+                day_wh(date.day, days)
+            } else {
+                day_wh(date.day, earlier)
+            }
+        })
+        .sum()
+}
+
+/// Totals for one outlet
+pub fn outlet_stats(addr: &str, child_id: Option<&str>, today: Date) -> Result<EnergyStats> {
+    let days = protocol::daystat(addr, child_id, today.year, today.month)?;
+
+    //the week can start in the previous month
+    let monday = today.week_start();
+    let earlier = if monday.month != today.month {
+        protocol::daystat(addr, child_id, monday.year, monday.month)?
+    } else {
+        vec![]
+    };
+    let week = week_wh(today, &days, &earlier);
+
+    let month = protocol::monthstat(addr, child_id, today.year)?
+        .iter()
+        .filter(|(m, _)| *m == today.month)
+        .map(|(_, wh)| wh)
+        .sum();
+
+    Ok(EnergyStats {
+        today: day_wh(today.day, &days),
+        week,
+        month,
+    })
+}
+
+/// Totals summed over several outlets, a whole strip when given all of them
+pub fn stats(addr: &str, child_ids: &[Option<String>], today: Date) -> Result<EnergyStats> {
+    let mut total = EnergyStats::default();
+    for id in child_ids {
+        let s = outlet_stats(addr, id.as_deref(), today)?;
+        total.today += s.today;
+        total.week += s.week;
+        total.month += s.month;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> Date {
+        Date { year, month, day }
+    }
+
+    #[test]
+    fn days_round_trip() {
+        assert_eq!(Date::from_days(0), date(1970, 1, 1));
+        assert_eq!(date(1970, 1, 1).days(), 0);
+        assert_eq!(Date::from_days(-1), date(1969, 12, 31));
+        assert_eq!(date(2000, 3, 1).days(), 11_017);
+        assert_eq!(Date::from_days(SYNCED_AFTER / 86_400), date(2024, 1, 1));
+        //every day from 1968 through 2104, leap days and century included
+        for days in -730..49_000 {
+            assert_eq!(Date::from_days(days).days(), days);
+        }
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(date(2024, 2, 28).add_days(1), date(2024, 2, 29));
+        assert_eq!(date(2024, 2, 29).add_days(1), date(2024, 3, 1));
+        assert_eq!(date(2023, 2, 28).add_days(1), date(2023, 3, 1));
+        assert_eq!(date(2100, 2, 28).add_days(1), date(2100, 3, 1));
+        assert_eq!(date(2000, 2, 28).add_days(1), date(2000, 2, 29));
+    }
+
+    #[test]
+    fn weekdays() {
+        //a Thursday
+        assert_eq!(date(1970, 1, 1).weekday(), 3);
+        assert_eq!(date(2024, 1, 1).weekday(), 0);
+        assert_eq!(date(2024, 3, 3).weekday(), 6);
+        assert_eq!(date(1969, 12, 29).weekday(), 0);
+        assert_eq!(date(2024, 3, 3).week_start(), date(2024, 2, 26));
+        assert_eq!(date(2024, 1, 1).week_start(), date(2024, 1, 1));
+    }
+
+    #[test]
+    fn week_within_a_month() {
+        //Wednesday the 13th, Monday was the 11th
+        let today = date(2024, 3, 13);
+        let days = [(10, 1000), (11, 1), (12, 20), (13, 300), (14, 4000)];
+        assert_eq!(week_wh(today, &days, &[(11, 50_000)]), 321);
+    }
+
+    #[test]
+    fn week_across_a_month() {
+        //Saturday the 2nd, the week started on February 26th
+        let today = date(2024, 3, 2);
+        let days = [(1, 1), (2, 20), (26, 50_000)];
+        let february = [(25, 50_000), (26, 300), (27, 4000), (29, 50)];
+        assert_eq!(week_wh(today, &days, &february), 4371);
+    }
+
+    #[test]
+    fn week_across_a_year() {
+        //Wednesday, the week started on December 30th
+        let today = date(2025, 1, 1);
+        assert_eq!(today.week_start(), date(2024, 12, 30));
+        let december = [(29, 50_000), (30, 1), (31, 20)];
+        assert_eq!(week_wh(today, &[(1, 300)], &december), 321);
+    }
+}
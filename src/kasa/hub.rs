@@ -0,0 +1,323 @@
+use anyhow::{anyhow, bail, Result};
+use rust_kasa::models::Realtime;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::light::{self, DeviceKind, LightChange, LightState};
+use super::protocol;
+use crate::{event_log, service_manager};
+
+#[derive(Clone)]
+pub struct Outlet {
+    /// Child id on a strip, None for a single plug
+    pub id: Option<String>,
+    pub alias: String,
+    pub on: bool,
+    /// Latest energy reading, None for devices without a meter
+    pub realtime: Option<Realtime>,
+}
+
+#[derive(Clone)]
+pub struct Device {
+    pub addr: String,
+    pub alias: String,
+    pub model: String,
+    pub kind: DeviceKind,
+    /// Brightness and color of a bulb or dimmer
+    pub light: Option<LightState>,
+    /// A strip's outlets, or the device itself as its only outlet
+    pub outlets: Vec<Outlet>,
+    /// False when the last poll couldn't reach it, the outlets are then stale
+    pub reachable: bool,
+}
+
+impl Device {
+    fn unknown(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            alias: addr.to_string(),
+            model: String::new(),
+            kind: DeviceKind::Plug,
+            light: None,
+            outlets: vec![],
+            reachable: false,
+        }
+    }
+}
+
+/// What an outlet should do when a scene runs
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SceneAction {
+    On,
+    Off,
+    Toggle,
+}
+
+/// A named set of outlet changes, from the `scenes` config entry
+#[derive(Clone, Debug)]
+pub struct Scene {
+    pub name: String,
+    /// (device, outlet, action)
+    pub steps: Vec<(usize, usize, SceneAction)>,
+}
+
+/// Parse `name:dev.outlet=on,dev.outlet=off;name2:...`, bad steps are logged
+/// and skipped
+pub fn parse_scenes(config: &str) -> Vec<Scene> {
+    config
+        .split(';')
+        .filter_map(|scene| {
+            let (name, steps) = scene.split_once(':')?;
+            let steps = steps
+                .split(',')
+                .filter(|s| !s.trim().is_empty())
+                .filter_map(|step| {
+                    let parsed = parse_step(step.trim());
+                    if parsed.is_none() {
+                        log::warn!("scene {:}: can't parse {:}", name.trim(), step);
+                    }
+                    parsed
+                })
+                .collect();
+            Some(Scene {
+                name: name.trim().to_string(),
+                steps,
+            })
+        })
+        .collect()
+}
+
+fn parse_step(step: &str) -> Option<(usize, usize, SceneAction)> {
+    let (target, action) = step.split_once('=')?;
+    let (dev, outlet) = target.split_once('.')?;
+    let action = match action {
+        "on" => SceneAction::On,
+        "off" => SceneAction::Off,
+        "toggle" => SceneAction::Toggle,
+        _ => return None,
+    };
+    Some((dev.parse().ok()?, outlet.parse().ok()?, action))
+}
+
+//the hub modules read from, main builds it before the runner starts
+static SHARED: Mutex<Option<KasaHub>> = Mutex::new(None);
+
+/// Keeps the latest state of every configured Kasa device and is the one
+/// place outlets get switched from
+#[derive(Clone)]
+pub struct KasaHub {
+    devices: Arc<Mutex<Vec<Device>>>,
+    scenes: Arc<Vec<Scene>>,
+}
+
+impl KasaHub {
+    pub fn new(addrs: &[String], scenes: Vec<Scene>) -> Self {
+        Self {
+            devices: Arc::new(Mutex::new(
+                addrs.iter().map(|addr| Device::unknown(addr)).collect(),
+            )),
+            scenes: Arc::new(scenes),
+        }
+    }
+
+    /// Hub over devices that are already known, without polling them
+    #[cfg(test)]
+    pub fn from_devices(devices: Vec<Device>) -> Self {
+        Self {
+            devices: Arc::new(Mutex::new(devices)),
+            scenes: Arc::new(vec![]),
+        }
+    }
+
+    /// Make this the hub `shared` hands to modules
+    pub fn share(&self) {
+        *SHARED.lock().unwrap() = Some(self.clone());
+    }
+
+    /// The hub main built, None before it has
+    pub fn shared() -> Option<KasaHub> {
+        SHARED.lock().unwrap().clone()
+    }
+
+    /// Copy of every device as of the last poll
+    pub fn devices(&self) -> Vec<Device> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    pub fn scenes(&self) -> &[Scene] {
+        &self.scenes
+    }
+
+    fn addr(&self, dev: usize) -> Option<String> {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(dev)
+            .map(|d| d.addr.clone())
+    }
+
+    /// Refresh one device, the lock isn't held while talking to it
+    pub fn poll_device(&self, dev: usize) {
+        let addr = match self.addr(dev) {
+            Some(addr) => addr,
+            None => return,
+        };
+        let polled = read_device(&addr);
+        let mut devices = self.devices.lock().unwrap();
+        match polled {
+            Ok(device) => devices[dev] = device,
+            Err(e) => {
+                log::warn!("kasa {:} unreachable: {:?}", addr, e);
+                if devices[dev].reachable {
+                    event_log::record(&format!("kasa {:}: {:}", addr, e));
+                }
+                devices[dev].reachable = false;
+            }
+        }
+    }
+
+    pub fn poll_all(&self) {
+        let count = self.devices.lock().unwrap().len();
+        for dev in 0..count {
+            self.poll_device(dev);
+        }
+    }
+
+    /// Keep polling every `interval`, run as a service of its own
+    pub fn poll_service(&self, interval: Duration) {
+        loop {
+            service_manager::heartbeat();
+            self.poll_all();
+            thread::sleep(interval);
+        }
+    }
+
+    /// Switch an outlet, `None` toggles it. Returns the new state.
+    pub fn set_outlet(&self, dev: usize, outlet: usize, on: Option<bool>) -> Result<bool> {
+        let (addr, kind, id, current) = {
+            let devices = self.devices.lock().unwrap();
+            let device = devices
+                .get(dev)
+                .ok_or_else(|| anyhow!("no device {:}", dev))?;
+            let o = device
+                .outlets
+                .get(outlet)
+                .ok_or_else(|| anyhow!("no outlet {:} on device {:}", outlet, dev))?;
+            (device.addr.clone(), device.kind, o.id.clone(), o.on)
+        };
+        let on = on.unwrap_or(!current);
+        match kind {
+            DeviceKind::Bulb(_) => {
+                light::transition(&addr, &LightChange::power(on), 0)?;
+            }
+            _ => protocol::set_relay(&addr, id.as_deref(), on)?,
+        }
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(o) = devices[dev].outlets.get_mut(outlet) {
+            o.on = on;
+        }
+        if let Some(state) = devices[dev].light.as_mut() {
+            state.on = on;
+        }
+        Ok(on)
+    }
+
+    /// Change the brightness or color of a bulb or dimmer, returns its new state
+    pub fn set_light(&self, dev: usize, change: &LightChange) -> Result<LightState> {
+        let (addr, kind, current) = {
+            let devices = self.devices.lock().unwrap();
+            let device = devices
+                .get(dev)
+                .ok_or_else(|| anyhow!("no device {:}", dev))?;
+            match device.light {
+                Some(current) => (device.addr.clone(), device.kind, current),
+                None => bail!("device {:} is not a light", dev),
+            }
+        };
+        let state = light::apply(&addr, kind, current, change)?;
+        let mut devices = self.devices.lock().unwrap();
+        devices[dev].light = Some(state);
+        if let Some(o) = devices[dev].outlets.first_mut() {
+            o.on = state.on;
+        }
+        Ok(state)
+    }
+
+    /// Run every step of a scene, stops at the first outlet that fails
+    pub fn run_scene(&self, name: &str) -> Result<()> {
+        let scene = match self.scenes.iter().find(|s| s.name == name) {
+            Some(scene) => scene,
+            None => bail!("no scene {:}", name),
+        };
+        for &(dev, outlet, action) in &scene.steps {
+            let on = match action {
+                SceneAction::On => Some(true),
+                SceneAction::Off => Some(false),
+                SceneAction::Toggle => None,
+            };
+            self.set_outlet(dev, outlet, on)?;
+        }
+        Ok(())
+    }
+}
+
+//strips list their outlets as children, a plain plug, dimmer or bulb is its
+//own outlet
+fn read_device(addr: &str) -> Result<Device> {
+    let info = protocol::sysinfo(addr)?;
+    let kind = DeviceKind::from_sysinfo(&info);
+    let light = LightState::from_sysinfo(kind, &info);
+    let device_id = info["deviceId"].as_str().unwrap_or("");
+    let has_meter = info["feature"].as_str().unwrap_or("").contains("ENE")
+        || info["model"].as_str().unwrap_or("").starts_with("HS300");
+    let outlets = match info["children"].as_array() {
+        Some(children) => children
+            .iter()
+            .map(|child| {
+                let id = child_id(device_id, child);
+                let realtime = if has_meter {
+                    protocol::realtime(addr, Some(&id)).ok()
+                } else {
+                    None
+                };
+                Outlet {
+                    alias: child["alias"].as_str().unwrap_or(&id).to_string(),
+                    on: child["state"].as_u64() == Some(1),
+                    realtime,
+                    id: Some(id),
+                }
+            })
+            .collect(),
+        None => vec![Outlet {
+            id: None,
+            alias: info["alias"].as_str().unwrap_or(addr).to_string(),
+            on: light.map_or(info["relay_state"].as_u64() == Some(1), |l| l.on),
+            realtime: if has_meter {
+                protocol::realtime(addr, None).ok()
+            } else {
+                None
+            },
+        }],
+    };
+    Ok(Device {
+        addr: addr.to_string(),
+        alias: info["alias"].as_str().unwrap_or(addr).to_string(),
+        model: info["model"].as_str().unwrap_or("").to_string(),
+        kind,
+        light,
+        outlets,
+        reachable: true,
+    })
+}
+
+//some firmware reports just the two digit suffix, commands want the full id
+pub fn child_id(device_id: &str, child: &Value) -> String {
+    let id = child["id"].as_str().unwrap_or("");
+    if id.len() <= 2 {
+        format!("{:}{:}", device_id, id)
+    } else {
+        id.to_string()
+    }
+}
@@ -0,0 +1,317 @@
+//KLAP, the authenticated protocol of newer Kasa firmware and Tapo devices.
+//Two HTTP handshakes prove both sides know the hash of the cloud account,
+//after that every request is AES-128-CBC under keys derived from the seeds.
+
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use aes::Aes128;
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use super::transport::{Credentials, Transport, MAX_RESPONSE, TIMEOUT};
+
+const HTTP_PORT: u16 = 80;
+//the device says how long a session lasts in its cookie, renew well before
+const SESSION_MARGIN: Duration = Duration::from_secs(20 * 60);
+const DEFAULT_SESSION: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// What both sides hash the seeds with, sha256(sha1(user) + sha1(password))
+pub fn auth_hash(credentials: &Credentials) -> [u8; 32] {
+    let user = Sha1::digest(credentials.username.as_bytes());
+    let password = Sha1::digest(credentials.password.as_bytes());
+    sha256(&[user.as_slice(), password.as_slice()])
+}
+
+/// Proof the device sends back in handshake1
+pub fn server_hash(local_seed: &[u8], remote_seed: &[u8], auth: &[u8; 32]) -> [u8; 32] {
+    sha256(&[local_seed, remote_seed, auth])
+}
+
+/// Proof the client sends in handshake2
+pub fn client_hash(local_seed: &[u8], remote_seed: &[u8], auth: &[u8; 32]) -> [u8; 32] {
+    sha256(&[remote_seed, local_seed, auth])
+}
+
+/// Keys and sequence number of an established session. The device side is
+/// the same, which the mock server relies on.
+pub struct Session {
+    key: [u8; 16],
+    iv: [u8; 12],
+    sig: [u8; 28],
+    pub seq: i32,
+}
+
+impl Session {
+    pub fn new(local_seed: &[u8], remote_seed: &[u8], auth: &[u8; 32]) -> Self {
+        let derive = |label: &[u8]| sha256(&[label, local_seed, remote_seed, auth]);
+        let full_iv = derive(b"iv");
+        let mut session = Self {
+            key: [0; 16],
+            iv: [0; 12],
+            sig: [0; 28],
+            seq: i32::from_be_bytes([full_iv[28], full_iv[29], full_iv[30], full_iv[31]]),
+        };
+        session.key.copy_from_slice(&derive(b"lsk")[..16]);
+        session.iv.copy_from_slice(&full_iv[..12]);
+        session.sig.copy_from_slice(&derive(b"ldk")[..28]);
+        session
+    }
+
+    fn iv_for(&self, seq: i32) -> [u8; 16] {
+        let mut iv = [0u8; 16];
+        iv[..12].copy_from_slice(&self.iv);
+        iv[12..].copy_from_slice(&seq.to_be_bytes());
+        iv
+    }
+
+    /// Signature followed by the ciphertext, under sequence number `seq`
+    pub fn encrypt_with(&self, seq: i32, plain: &[u8]) -> Vec<u8> {
+        let cipher = cbc::Encryptor::<Aes128>::new(&self.key.into(), &self.iv_for(seq).into())
+            .encrypt_padded_vec_mut::<Pkcs7>(plain);
+        let signature = sha256(&[&self.sig, &seq.to_be_bytes(), &cipher]);
+        let mut out = signature.to_vec();
+        out.extend_from_slice(&cipher);
+        out
+    }
+
+    /// Next request's body, bumps the sequence number
+    pub fn encrypt(&mut self, plain: &[u8]) -> Vec<u8> {
+        self.seq = self.seq.wrapping_add(1);
+        self.encrypt_with(self.seq, plain)
+    }
+
+    /// Open a message sent under `seq`, checking its signature
+    pub fn decrypt_with(&self, seq: i32, msg: &[u8]) -> Result<Vec<u8>> {
+        if msg.len() < 32 {
+            bail!("klap message too short");
+        }
+        let (signature, cipher) = msg.split_at(32);
+        if signature != sha256(&[&self.sig, &seq.to_be_bytes(), cipher]) {
+            bail!("klap signature mismatch");
+        }
+        self.decrypt_unsigned(seq, cipher)
+    }
+
+    /// Replies only carry the ciphertext after a signature nobody checks
+    pub fn decrypt(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        if msg.len() < 32 {
+            bail!("klap reply too short");
+        }
+        self.decrypt_unsigned(self.seq, &msg[32..])
+    }
+
+    fn decrypt_unsigned(&self, seq: i32, cipher: &[u8]) -> Result<Vec<u8>> {
+        cbc::Decryptor::<Aes128>::new(&self.key.into(), &self.iv_for(seq).into())
+            .decrypt_padded_vec_mut::<Pkcs7>(cipher)
+            .map_err(|_| anyhow!("klap bad padding"))
+    }
+}
+
+struct HttpReply {
+    status: u16,
+    cookie: Option<String>,
+    body: Vec<u8>,
+}
+
+//just enough HTTP/1.1 for the three KLAP endpoints, one request per connection
+fn post(host: &str, path: &str, cookie: Option<&str>, body: &[u8]) -> Result<HttpReply> {
+    let target = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{:}:{:}", host, HTTP_PORT)
+    };
+    let addr = target
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("can't resolve {:}", host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut head = format!(
+        "POST {:} HTTP/1.1\r\nHost: {:}\r\nContent-Type: application/octet-stream\r\nContent-Length: {:}\r\nConnection: close\r\n",
+        path,
+        target,
+        body.len()
+    );
+    if let Some(cookie) = cookie {
+        head.push_str(&format!("Cookie: {:}\r\n", cookie));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| anyhow!("bad status line from {:}", host))?;
+
+    let mut content_length = None;
+    let mut cookie = None;
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse::<usize>().ok();
+            } else if name.eq_ignore_ascii_case("set-cookie") {
+                cookie = Some(value.to_string());
+            }
+        }
+    }
+
+    let body = match content_length {
+        Some(len) if len > MAX_RESPONSE => bail!("response from {:} too long", host),
+        Some(len) => {
+            let mut body = vec![0u8; len];
+            reader.read_exact(&mut body)?;
+            body
+        }
+        None => {
+            let mut body = vec![];
+            reader.take(MAX_RESPONSE as u64).read_to_end(&mut body)?;
+            body
+        }
+    };
+    Ok(HttpReply {
+        status,
+        cookie,
+        body,
+    })
+}
+
+//`TP_SESSIONID=...;TIMEOUT=86400` into the part to send back and the lifetime
+fn parse_cookie(cookie: &str) -> (String, Duration) {
+    let mut session_id = String::new();
+    let mut timeout = DEFAULT_SESSION;
+    for part in cookie.split(';').map(|p| p.trim()) {
+        match part.split_once('=') {
+            Some(("TP_SESSIONID", _)) => session_id = part.to_string(),
+            Some(("TIMEOUT", secs)) => {
+                if let Ok(secs) = secs.parse() {
+                    timeout = Duration::from_secs(secs);
+                }
+            }
+            _ => (),
+        }
+    }
+    (session_id, timeout)
+}
+
+struct Established {
+    session: Session,
+    cookie: String,
+    expires: Instant,
+}
+
+/// KLAP to one device, handshakes again whenever the session runs out or the
+/// device forgets it
+pub struct KlapTransport {
+    host: String,
+    credentials: Credentials,
+    established: Option<Established>,
+}
+
+impl KlapTransport {
+    pub fn new(host: &str, credentials: Credentials) -> Self {
+        Self {
+            host: host.to_string(),
+            credentials,
+            established: None,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    fn handshake(&self) -> Result<Established> {
+        let mut local_seed = [0u8; 16];
+        getrandom::getrandom(&mut local_seed).map_err(|e| anyhow!("no random seed: {:}", e))?;
+
+        let reply = post(&self.host, "/app/handshake1", None, &local_seed)?;
+        if reply.status != 200 || reply.body.len() != 48 {
+            bail!(
+                "klap handshake1 to {:} failed: {:}",
+                self.host,
+                reply.status
+            );
+        }
+        let (remote_seed, proof) = reply.body.split_at(16);
+        let (cookie, timeout) = parse_cookie(reply.cookie.as_deref().unwrap_or(""));
+
+        //a device that was never bound to an account takes empty credentials
+        let auth = [self.credentials.clone(), Credentials::default()]
+            .iter()
+            .map(auth_hash)
+            .find(|auth| server_hash(&local_seed, remote_seed, auth) == proof)
+            .ok_or_else(|| anyhow!("klap credentials rejected by {:}", self.host))?;
+
+        let hash = client_hash(&local_seed, remote_seed, &auth);
+        let reply = post(&self.host, "/app/handshake2", Some(&cookie), &hash)?;
+        if reply.status != 200 {
+            bail!(
+                "klap handshake2 to {:} failed: {:}",
+                self.host,
+                reply.status
+            );
+        }
+        Ok(Established {
+            session: Session::new(&local_seed, remote_seed, &auth),
+            cookie,
+            expires: Instant::now() + timeout.saturating_sub(SESSION_MARGIN),
+        })
+    }
+
+    fn request(&mut self, cmd: &Value) -> Result<Value> {
+        let established = match self.established.as_mut() {
+            Some(e) if e.expires > Instant::now() => e,
+            _ => {
+                self.established = Some(self.handshake()?);
+                self.established.as_mut().unwrap()
+            }
+        };
+        let body = established.session.encrypt(cmd.to_string().as_bytes());
+        let path = format!("/app/request?seq={:}", established.session.seq);
+        let reply = post(&self.host, &path, Some(&established.cookie), &body)?;
+        if reply.status != 200 {
+            self.established = None;
+            bail!("klap request to {:} failed: {:}", self.host, reply.status);
+        }
+        Ok(serde_json::from_slice(
+            &established.session.decrypt(&reply.body)?,
+        )?)
+    }
+}
+
+impl Transport for KlapTransport {
+    fn exchange(&mut self, cmd: &Value) -> Result<Value> {
+        let had_session = self.established.is_some();
+        match self.request(cmd) {
+            //a rebooted device has forgotten the session, one fresh handshake
+            Err(_) if had_session && self.established.is_none() => self.request(cmd),
+            reply => reply,
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//A pretend KLAP device for a PC: both handshakes, sessions and signed
+//requests, with the commands answered by a callback. Point the transport at
+//`klap://127.0.0.1:<port>` to exercise it without a Tapo plug around.
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use super::klap::{auth_hash, client_hash, server_hash, Session};
+use super::transport::Credentials;
+
+const MAX_BODY: usize = 4096;
+//what real devices hand out, a day
+const SESSION_TIMEOUT: u64 = 86400;
+
+struct MockSession {
+    id: String,
+    local_seed: Vec<u8>,
+    remote_seed: [u8; 16],
+    confirmed: bool,
+}
+
+pub struct MockDevice<F: Fn(&Value) -> Value> {
+    auth: [u8; 32],
+    answer: F,
+    sessions: Vec<MockSession>,
+    next_id: u32,
+    session_timeout: u64,
+}
+
+impl<F: Fn(&Value) -> Value> MockDevice<F> {
+    pub fn new(credentials: &Credentials, answer: F) -> Self {
+        Self {
+            auth: auth_hash(credentials),
+            answer,
+            sessions: vec![],
+            next_id: 0,
+            session_timeout: SESSION_TIMEOUT,
+        }
+    }
+
+    /// Session lifetime announced in the handshake1 cookie
+    pub fn with_session_timeout(mut self, secs: u64) -> Self {
+        self.session_timeout = secs;
+        self
+    }
+
+    /// Handle connections until the listener fails
+    pub fn serve(&mut self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        log::info!("mock klap device on {:}", addr);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("accept failed: {:?}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.connection(stream) {
+                log::warn!("mock klap: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn connection(&mut self, mut stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let path = request_line
+            .split_whitespace()
+            .nth(1)
+            .ok_or_else(|| anyhow!("no path"))?
+            .to_string();
+
+        let mut content_length = 0;
+        let mut cookie = String::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse::<usize>()?.min(MAX_BODY);
+                } else if name.eq_ignore_ascii_case("cookie") {
+                    cookie = value.trim().to_string();
+                }
+            }
+        }
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body)?;
+
+        let (status, set_cookie, reply) = self.route(&path, &cookie, &body);
+        let mut head = format!(
+            "HTTP/1.1 {:} {:}\r\nContent-Length: {:}\r\nConnection: close\r\n",
+            status,
+            if status == 200 { "OK" } else { "Forbidden" },
+            reply.len()
+        );
+        if let Some(set_cookie) = set_cookie {
+            head.push_str(&format!("Set-Cookie: {:}\r\n", set_cookie));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&reply)?;
+        Ok(())
+    }
+
+    fn route(&mut self, path: &str, cookie: &str, body: &[u8]) -> (u16, Option<String>, Vec<u8>) {
+        let forbidden = (403, None, vec![]);
+        if path == "/app/handshake1" {
+            let mut remote_seed = [0u8; 16];
+            if getrandom::getrandom(&mut remote_seed).is_err() {
+                return forbidden;
+            }
+            self.next_id += 1;
+            let id = format!("TP_SESSIONID={:08X}", self.next_id);
+            let mut reply = remote_seed.to_vec();
+            reply.extend_from_slice(&server_hash(body, &remote_seed, &self.auth));
+            self.sessions.push(MockSession {
+                id: id.clone(),
+                local_seed: body.to_vec(),
+                remote_seed,
+                confirmed: false,
+            });
+            let cookie = format!("{:};TIMEOUT={:}", id, self.session_timeout);
+            return (200, Some(cookie), reply);
+        }
+
+        let auth = self.auth;
+        let session = match self.sessions.iter_mut().find(|s| s.id == cookie) {
+            Some(session) => session,
+            None => return forbidden,
+        };
+        if path == "/app/handshake2" {
+            session.confirmed =
+                body == client_hash(&session.local_seed, &session.remote_seed, &auth);
+            return if session.confirmed {
+                (200, None, vec![])
+            } else {
+                forbidden
+            };
+        }
+
+        let seq = match path.strip_prefix("/app/request?seq=").map(|s| s.parse()) {
+            Some(Ok(seq)) if session.confirmed => seq,
+            _ => return forbidden,
+        };
+        let keys = Session::new(&session.local_seed, &session.remote_seed, &auth);
+        let cmd = match keys
+            .decrypt_with(seq, body)
+            .ok()
+            .and_then(|plain| serde_json::from_slice::<Value>(&plain).ok())
+        {
+            Some(cmd) => cmd,
+            None => return forbidden,
+        };
+        let reply = (self.answer)(&cmd);
+        (
+            200,
+            None,
+            keys.encrypt_with(seq, reply.to_string().as_bytes()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kasa::transport;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use std::thread;
+
+    type Mock = MockDevice<fn(&Value) -> Value>;
+
+    //the transport keeps sessions and credentials in statics, one test at a time
+    static TRANSPORT: Mutex<()> = Mutex::new(());
+
+    fn credentials(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    fn echo(cmd: &Value) -> Value {
+        json!({ "echo": cmd })
+    }
+
+    fn mock(credentials: &Credentials) -> Mock {
+        MockDevice::new(credentials, echo as fn(&Value) -> Value)
+    }
+
+    //serve exactly `connections` requests while `client` runs against the
+    //mock's `klap://` address. Every handshake is two, every command one.
+    fn with_device<R>(mock: &mut Mock, connections: usize, client: impl FnOnce(&str) -> R) -> R {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("klap://{:}", listener.local_addr().unwrap());
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for stream in listener.incoming().take(connections) {
+                    let _ = mock.connection(stream.unwrap());
+                }
+            });
+            client(&addr)
+        })
+    }
+
+    fn sysinfo() -> Value {
+        json!({ "system": { "get_sysinfo": {} } })
+    }
+
+    //fresh session cache and the client's credentials, held for the whole test
+    fn client_as(credentials: &Credentials) -> std::sync::MutexGuard<'static, ()> {
+        let guard = TRANSPORT.lock().unwrap_or_else(|e| e.into_inner());
+        transport::set_credentials(credentials.clone());
+        transport::clear_sessions();
+        guard
+    }
+
+    #[test]
+    fn handshake_and_encrypted_request() {
+        let alice = credentials("alice@example.com", "hunter2");
+        let _guard = client_as(&alice);
+        let mut mock = mock(&alice);
+        let reply = with_device(&mut mock, 3, |addr| transport::exchange(addr, &sysinfo()));
+        assert_eq!(reply.unwrap(), json!({ "echo": sysinfo() }));
+        assert_eq!(mock.sessions.len(), 1);
+        assert!(mock.sessions[0].confirmed);
+    }
+
+    //the second command rides the cached session, no new handshake
+    #[test]
+    fn session_is_reused() {
+        let alice = credentials("alice@example.com", "hunter2");
+        let _guard = client_as(&alice);
+        let mut mock = mock(&alice);
+        let cmd = json!({ "system": { "set_relay_state": { "state": 1 } } });
+        let replies = with_device(&mut mock, 4, |addr| {
+            [
+                transport::exchange(addr, &sysinfo()).unwrap(),
+                transport::exchange(addr, &cmd).unwrap(),
+            ]
+        });
+        assert_eq!(replies[1], json!({ "echo": cmd }));
+        assert_eq!(mock.next_id, 1);
+    }
+
+    #[test]
+    fn wrong_credentials_are_rejected() {
+        let _guard = client_as(&credentials("bob@example.com", "wrong"));
+        let mut mock = mock(&credentials("alice@example.com", "hunter2"));
+        //the proof in handshake1 doesn't match, so handshake2 is never sent
+        let err =
+            with_device(&mut mock, 1, |addr| transport::exchange(addr, &sysinfo())).unwrap_err();
+        assert!(err.to_string().contains("credentials rejected"), "{:}", err);
+        assert!(!mock.sessions[0].confirmed);
+    }
+
+    #[test]
+    fn unbound_device_takes_empty_credentials() {
+        let _guard = client_as(&credentials("alice@example.com", "hunter2"));
+        let mut mock = mock(&Credentials::default());
+        let reply = with_device(&mut mock, 3, |addr| transport::exchange(addr, &sysinfo()));
+        assert_eq!(reply.unwrap()["echo"], sysinfo());
+    }
+
+    #[test]
+    fn handshakes_again_after_a_403() {
+        let alice = credentials("alice@example.com", "hunter2");
+        let _guard = client_as(&alice);
+        let mut mock = mock(&alice);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("klap://{:}", listener.local_addr().unwrap());
+        let serve = |mock: &mut Mock, connections: usize| {
+            for stream in listener.incoming().take(connections) {
+                let _ = mock.connection(stream.unwrap());
+            }
+        };
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                serve(&mut mock, 3);
+                //a reboot loses every session, the next request gets a 403
+                mock.sessions.clear();
+                //the rejected request, then a fresh handshake and the retry
+                serve(&mut mock, 4);
+                mock
+            });
+            assert!(transport::exchange(&addr, &sysinfo()).is_ok());
+            let reply = transport::exchange(&addr, &sysinfo());
+            assert_eq!(reply.unwrap()["echo"], sysinfo());
+            let mock = server.join().unwrap();
+            assert_eq!(mock.next_id, 2);
+            assert!(mock.sessions[0].confirmed);
+        });
+    }
+
+    #[test]
+    fn handshakes_again_once_the_session_expires() {
+        let alice = credentials("alice@example.com", "hunter2");
+        let _guard = client_as(&alice);
+        //shorter than the margin the client renews in, so it is always stale
+        let mut mock = mock(&alice).with_session_timeout(60);
+        let replies = with_device(&mut mock, 6, |addr| {
+            [
+                transport::exchange(addr, &sysinfo()),
+                transport::exchange(addr, &sysinfo()),
+            ]
+        });
+        assert!(replies.iter().all(|reply| reply.is_ok()));
+        assert_eq!(mock.next_id, 2);
+    }
+}
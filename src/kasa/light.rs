@@ -0,0 +1,396 @@
+//Bulbs and dimmer switches. Bulbs ignore the relay commands, everything goes
+//through the lighting service's transition_light_state. A dimmer is a plug
+//with an extra brightness service on top.
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+use super::protocol;
+
+const LIGHTING: &str = "smartlife.iot.smartbulb.lightingservice";
+const DIMMER: &str = "smartlife.iot.dimmer";
+
+/// Color temperature range every tunable white bulb can do
+pub const TEMP_RANGE: (u16, u16) = (2700, 6500);
+
+/// What a bulb can do besides switching, from its sysinfo
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BulbFeatures {
+    pub dimmable: bool,
+    pub color: bool,
+    pub color_temp: bool,
+}
+
+/// Device type as far as controlling it goes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DeviceKind {
+    Plug,
+    Strip,
+    /// Wall dimmer like the HS220
+    Dimmer,
+    /// KL series bulb
+    Bulb(BulbFeatures),
+}
+
+impl DeviceKind {
+    pub fn from_sysinfo(info: &Value) -> Self {
+        let mic_type = info["mic_type"]
+            .as_str()
+            .or(info["type"].as_str())
+            .unwrap_or("");
+        let flag = |name: &str| info[name].as_u64() == Some(1);
+        if mic_type.contains("SMARTBULB") {
+            DeviceKind::Bulb(BulbFeatures {
+                dimmable: flag("is_dimmable"),
+                color: flag("is_color"),
+                color_temp: flag("is_variable_color_temp"),
+            })
+        } else if info["children"].is_array() {
+            DeviceKind::Strip
+        } else if info["brightness"].is_u64() {
+            DeviceKind::Dimmer
+        } else {
+            DeviceKind::Plug
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceKind::Plug => "plug",
+            DeviceKind::Strip => "strip",
+            DeviceKind::Dimmer => "dimmer",
+            DeviceKind::Bulb(_) => "bulb",
+        }
+    }
+
+    pub fn is_light(&self) -> bool {
+        matches!(self, DeviceKind::Dimmer | DeviceKind::Bulb(_))
+    }
+}
+
+/// Current output of a bulb or dimmer. A dimmer only has `on` and `brightness`,
+/// `color_temp` is 0 while a color bulb shows a hue.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LightState {
+    pub on: bool,
+    /// 1-100 %
+    pub brightness: u8,
+    /// Kelvin
+    pub color_temp: u16,
+    /// 0-360 degrees
+    pub hue: u16,
+    /// 0-100 %
+    pub saturation: u8,
+}
+
+impl LightState {
+    /// From a bulb's light_state, which also comes back from every transition
+    fn from_light_state(state: &Value) -> Self {
+        //an off bulb only reports what it will come back on with
+        let on = state["on_off"].as_u64() == Some(1);
+        let src = if on { state } else { &state["dft_on_state"] };
+        let field = |name: &str| src[name].as_u64().unwrap_or(0);
+        Self {
+            on,
+            brightness: field("brightness") as u8,
+            color_temp: field("color_temp") as u16,
+            hue: field("hue") as u16,
+            saturation: field("saturation") as u8,
+        }
+    }
+
+    /// Light state of a bulb or dimmer from its sysinfo, None for anything else
+    pub fn from_sysinfo(kind: DeviceKind, info: &Value) -> Option<Self> {
+        match kind {
+            DeviceKind::Bulb(_) => Some(Self::from_light_state(&info["light_state"])),
+            DeviceKind::Dimmer => Some(Self {
+                on: info["relay_state"].as_u64() == Some(1),
+                brightness: info["brightness"].as_u64().unwrap_or(0) as u8,
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Fields to change on a light, everything left None stays as it is
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LightChange {
+    pub on: Option<bool>,
+    pub brightness: Option<u8>,
+    pub color_temp: Option<u16>,
+    pub hue: Option<u16>,
+    pub saturation: Option<u8>,
+}
+
+impl LightChange {
+    pub fn power(on: bool) -> Self {
+        Self {
+            on: Some(on),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+//transition_light_state arguments for a change, clamped to what bulbs accept
+fn transition_args(change: &LightChange, period_ms: u32) -> Value {
+    let mut args = json!({ "transition_period": period_ms, "ignore_default": 1 });
+    if let Some(on) = change.on {
+        args["on_off"] = json!(on as u8);
+    }
+    if let Some(brightness) = change.brightness {
+        args["brightness"] = json!(brightness.clamp(1, 100));
+    }
+    if let Some(hue) = change.hue {
+        args["hue"] = json!(hue.min(360));
+    }
+    if let Some(saturation) = change.saturation {
+        args["saturation"] = json!(saturation.min(100));
+    }
+    match change.color_temp {
+        Some(temp) => args["color_temp"] = json!(temp.clamp(TEMP_RANGE.0, TEMP_RANGE.1)),
+        //a hue only shows once the bulb leaves white mode
+        None if change.hue.is_some() || change.saturation.is_some() => {
+            args["color_temp"] = json!(0)
+        }
+        None => (),
+    }
+    args
+}
+
+/// Fade a bulb to a new state over `period_ms`, returns what it ended up at
+pub fn transition(addr: &str, change: &LightChange, period_ms: u32) -> Result<LightState> {
+    let mut cmd = json!({});
+    cmd[LIGHTING]["transition_light_state"] = transition_args(change, period_ms);
+    let reply = protocol::send(addr, &cmd)?;
+    let state = protocol::result(&reply, LIGHTING, "transition_light_state")?;
+    Ok(LightState::from_light_state(state))
+}
+
+/// Set a dimmer's brightness, switching it is the plain relay command
+pub fn set_dimmer_brightness(addr: &str, brightness: u8) -> Result<()> {
+    let mut cmd = json!({});
+    cmd[DIMMER]["set_brightness"] = json!({ "brightness": brightness.clamp(1, 100) });
+    let reply = protocol::send(addr, &cmd)?;
+    protocol::result(&reply, DIMMER, "set_brightness")?;
+    Ok(())
+}
+
+/// Apply a change to either kind of light, returns the state it should now be in
+pub fn apply(
+    addr: &str,
+    kind: DeviceKind,
+    current: LightState,
+    change: &LightChange,
+) -> Result<LightState> {
+    match kind {
+        DeviceKind::Bulb(_) => transition(addr, change, 0),
+        DeviceKind::Dimmer => {
+            if change.color_temp.is_some() || change.hue.is_some() || change.saturation.is_some() {
+                bail!("a dimmer only does brightness");
+            }
+            let mut state = current;
+            if let Some(brightness) = change.brightness {
+                set_dimmer_brightness(addr, brightness)?;
+                state.brightness = brightness.clamp(1, 100);
+            }
+            if let Some(on) = change.on {
+                protocol::set_relay(addr, None, on)?;
+                state.on = on;
+            }
+            Ok(state)
+        }
+        _ => bail!("not a light"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //trimmed sysinfo of each kind of device
+    fn kl130() -> Value {
+        json!({
+            "model": "KL130(EU)",
+            "mic_type": "IOT.SMARTBULB",
+            "is_dimmable": 1,
+            "is_color": 1,
+            "is_variable_color_temp": 1,
+            "light_state": {
+                "on_off": 1,
+                "mode": "normal",
+                "hue": 120,
+                "saturation": 80,
+                "color_temp": 0,
+                "brightness": 60
+            }
+        })
+    }
+
+    fn hs220() -> Value {
+        json!({
+            "model": "HS220(US)",
+            "mic_type": "IOT.SMARTPLUGSWITCH",
+            "relay_state": 1,
+            "brightness": 35
+        })
+    }
+
+    fn hs300() -> Value {
+        json!({
+            "model": "HS300(US)",
+            "mic_type": "IOT.SMARTPLUGSWITCH",
+            "children": [
+                { "id": "00", "state": 1, "alias": "one" },
+                { "id": "01", "state": 0, "alias": "two" }
+            ]
+        })
+    }
+
+    fn hs110() -> Value {
+        json!({
+            "model": "HS110(EU)",
+            "type": "IOT.SMARTPLUGSWITCH",
+            "relay_state": 0
+        })
+    }
+
+    #[test]
+    fn kinds_from_sysinfo() {
+        let all = BulbFeatures {
+            dimmable: true,
+            color: true,
+            color_temp: true,
+        };
+        assert_eq!(DeviceKind::from_sysinfo(&kl130()), DeviceKind::Bulb(all));
+        assert_eq!(DeviceKind::from_sysinfo(&hs220()), DeviceKind::Dimmer);
+        assert_eq!(DeviceKind::from_sysinfo(&hs300()), DeviceKind::Strip);
+        assert_eq!(DeviceKind::from_sysinfo(&hs110()), DeviceKind::Plug);
+    }
+
+    #[test]
+    fn white_bulbs_have_fewer_features() {
+        let kl110 = json!({ "mic_type": "IOT.SMARTBULB", "is_dimmable": 1, "is_color": 0 });
+        assert_eq!(
+            DeviceKind::from_sysinfo(&kl110),
+            DeviceKind::Bulb(BulbFeatures {
+                dimmable: true,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn light_state_of_a_lit_bulb() {
+        let kind = DeviceKind::from_sysinfo(&kl130());
+        assert_eq!(
+            LightState::from_sysinfo(kind, &kl130()),
+            Some(LightState {
+                on: true,
+                brightness: 60,
+                color_temp: 0,
+                hue: 120,
+                saturation: 80,
+            })
+        );
+    }
+
+    #[test]
+    fn off_bulbs_report_their_default_on_state() {
+        let state = json!({
+            "on_off": 0,
+            "dft_on_state": {
+                "hue": 0,
+                "saturation": 0,
+                "color_temp": 2700,
+                "brightness": 40
+            }
+        });
+        assert_eq!(
+            LightState::from_light_state(&state),
+            LightState {
+                on: false,
+                brightness: 40,
+                color_temp: 2700,
+                hue: 0,
+                saturation: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn light_state_of_a_dimmer() {
+        assert_eq!(
+            LightState::from_sysinfo(DeviceKind::Dimmer, &hs220()),
+            Some(LightState {
+                on: true,
+                brightness: 35,
+                ..Default::default()
+            })
+        );
+        assert_eq!(LightState::from_sysinfo(DeviceKind::Plug, &hs110()), None);
+    }
+
+    #[test]
+    fn transition_only_sends_what_changes() {
+        assert_eq!(
+            transition_args(&LightChange::power(true), 500),
+            json!({ "transition_period": 500, "ignore_default": 1, "on_off": 1 })
+        );
+    }
+
+    #[test]
+    fn transition_clamps_to_what_bulbs_accept() {
+        let change = LightChange {
+            brightness: Some(0),
+            color_temp: Some(9000),
+            ..Default::default()
+        };
+        let args = transition_args(&change, 0);
+        assert_eq!(args["brightness"], 1);
+        assert_eq!(args["color_temp"], TEMP_RANGE.1);
+        let change = LightChange {
+            brightness: Some(150),
+            hue: Some(400),
+            saturation: Some(120),
+            color_temp: Some(1000),
+            ..Default::default()
+        };
+        let args = transition_args(&change, 0);
+        assert_eq!(args["brightness"], 100);
+        assert_eq!(args["hue"], 360);
+        assert_eq!(args["saturation"], 100);
+        assert_eq!(args["color_temp"], TEMP_RANGE.0);
+    }
+
+    #[test]
+    fn hue_changes_leave_white_mode() {
+        let hue = LightChange {
+            hue: Some(200),
+            ..Default::default()
+        };
+        assert_eq!(transition_args(&hue, 0)["color_temp"], 0);
+        let saturation = LightChange {
+            saturation: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(transition_args(&saturation, 0)["color_temp"], 0);
+        //unless a temperature was asked for as well
+        let both = LightChange {
+            hue: Some(200),
+            color_temp: Some(4000),
+            ..Default::default()
+        };
+        assert_eq!(transition_args(&both, 0)["color_temp"], 4000);
+        //brightness alone doesn't touch the mode
+        let brightness = LightChange {
+            brightness: Some(50),
+            ..Default::default()
+        };
+        assert!(transition_args(&brightness, 0).get("color_temp").is_none());
+    }
+}
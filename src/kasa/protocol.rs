@@ -0,0 +1,131 @@
+//The local Kasa protocol's commands. The JSON is the same whichever way it
+//travels, see `transport` for the XOR and KLAP framing.
+
+use anyhow::{anyhow, bail, Result};
+use rust_kasa::models::Realtime;
+use serde_json::{json, Value};
+
+use super::transport;
+use crate::metrics;
+
+/// Send one command and wait for its reply
+pub fn send(addr: &str, cmd: &Value) -> Result<Value> {
+    metrics::count(&metrics::KASA_REQUESTS);
+    let reply = transport::exchange(addr, cmd);
+    if reply.is_err() {
+        metrics::count(&metrics::KASA_FAILURES);
+    }
+    reply
+}
+
+//commands for a single outlet of a strip carry its id in the context
+fn with_context(child_id: Option<&str>, cmd: Value) -> Value {
+    match child_id {
+        Some(id) => {
+            let mut cmd = cmd;
+            cmd["context"] = json!({ "child_ids": [id] });
+            cmd
+        }
+        None => cmd,
+    }
+}
+
+//every reply nests the result under the same keys as the command, and
+//reports failure through err_code
+pub(super) fn result<'a>(reply: &'a Value, module: &str, method: &str) -> Result<&'a Value> {
+    let res = &reply[module][method];
+    if res.is_null() {
+        bail!("{:}.{:} missing from reply", module, method);
+    }
+    match res["err_code"].as_i64().unwrap_or(0) {
+        0 => Ok(res),
+        code => {
+            metrics::count(&metrics::KASA_FAILURES);
+            Err(anyhow!(
+                "{:}.{:} failed: {:} {:}",
+                module,
+                method,
+                code,
+                res["err_msg"].as_str().unwrap_or("")
+            ))
+        }
+    }
+}
+
+pub fn sysinfo(addr: &str) -> Result<Value> {
+    let reply = send(addr, &json!({ "system": { "get_sysinfo": {} } }))?;
+    Ok(result(&reply, "system", "get_sysinfo")?.clone())
+}
+
+pub fn realtime(addr: &str, child_id: Option<&str>) -> Result<Realtime> {
+    let cmd = with_context(child_id, json!({ "emeter": { "get_realtime": {} } }));
+    let reply = send(addr, &cmd)?;
+    let rt = result(&reply, "emeter", "get_realtime")?;
+    let field = |name: &str| rt[name].as_u64().unwrap_or(0) as u32;
+    Ok(Realtime {
+        current_ma: field("current_ma"),
+        err_code: field("err_code"),
+        power_mw: field("power_mw"),
+        slot_id: field("slot_id"),
+        total_wh: field("total_wh"),
+        voltage_mv: field("voltage_mv"),
+    })
+}
+
+pub fn set_relay(addr: &str, child_id: Option<&str>, on: bool) -> Result<()> {
+    let cmd = with_context(
+        child_id,
+        json!({ "system": { "set_relay_state": { "state": on as u8 } } }),
+    );
+    let reply = send(addr, &cmd)?;
+    result(&reply, "system", "set_relay_state")?;
+    Ok(())
+}
+
+//daystat and monthstat both answer with a list of {.., energy_wh}
+fn stat_list(reply: &Value, method: &str, key: &str, period: &str) -> Result<Vec<(u32, u32)>> {
+    let list = result(reply, "emeter", method)?;
+    Ok(list[key]
+        .as_array()
+        .map(|entries| {
+            entries
+                .iter()
+                .map(|e| {
+                    (
+                        e[period].as_u64().unwrap_or(0) as u32,
+                        //v1 hardware reports kWh as `energy`
+                        e["energy_wh"].as_u64().map_or_else(
+                            || (e["energy"].as_f64().unwrap_or(0.0) * 1000.0) as u32,
+                            |wh| wh as u32,
+                        ),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Energy per day of a month as (day, Wh), days without use may be missing
+pub fn daystat(
+    addr: &str,
+    child_id: Option<&str>,
+    year: i32,
+    month: u32,
+) -> Result<Vec<(u32, u32)>> {
+    let cmd = with_context(
+        child_id,
+        json!({ "emeter": { "get_daystat": { "year": year, "month": month } } }),
+    );
+    let reply = send(addr, &cmd)?;
+    stat_list(&reply, "get_daystat", "day_list", "day")
+}
+
+/// Energy per month of a year as (month, Wh)
+pub fn monthstat(addr: &str, child_id: Option<&str>, year: i32) -> Result<Vec<(u32, u32)>> {
+    let cmd = with_context(
+        child_id,
+        json!({ "emeter": { "get_monthstat": { "year": year } } }),
+    );
+    let reply = send(addr, &cmd)?;
+    stat_list(&reply, "get_monthstat", "month_list", "month")
+}
@@ -0,0 +1,143 @@
+//How commands reach a device. Older plugs take the XOR protocol on TCP 9999,
+//newer Kasa firmware and Tapo devices only talk KLAP over HTTP. Which one a
+//device gets is picked by its address, `klap://host` in `target_ip`.
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::klap::KlapTransport;
+
+/// Port of the legacy XOR protocol
+pub const PORT: u16 = 9999;
+const KEY: u8 = 171;
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+//sysinfo from a strip is a couple of KB, anything much bigger is garbage
+pub const MAX_RESPONSE: usize = 16 * 1024;
+
+const KLAP_SCHEME: &str = "klap://";
+
+pub fn encrypt(plain: &[u8]) -> Vec<u8> {
+    let mut key = KEY;
+    plain
+        .iter()
+        .map(|b| {
+            key ^= b;
+            key
+        })
+        .collect()
+}
+
+pub fn decrypt(cipher: &[u8]) -> Vec<u8> {
+    let mut key = KEY;
+    cipher
+        .iter()
+        .map(|&c| {
+            let b = key ^ c;
+            key = c;
+            b
+        })
+        .collect()
+}
+
+/// One request/response exchange with a device
+pub trait Transport {
+    fn exchange(&mut self, cmd: &Value) -> Result<Value>;
+}
+
+/// Cloud account the device is bound to, KLAP hashes it into every session
+#[derive(Clone, Default, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+static CREDENTIALS: Mutex<Credentials> = Mutex::new(Credentials {
+    username: String::new(),
+    password: String::new(),
+});
+//KLAP sessions outlive a single command, the handshake costs two round trips
+static SESSIONS: Mutex<Vec<KlapTransport>> = Mutex::new(Vec::new());
+
+/// Use these credentials for KLAP devices from now on, drops any session
+/// made with the old ones
+pub fn set_credentials(credentials: Credentials) {
+    let mut current = CREDENTIALS.lock().unwrap();
+    if *current != credentials {
+        *current = credentials;
+        SESSIONS.lock().unwrap().clear();
+    }
+}
+
+/// Forget every KLAP session, so tests start with an empty cache
+#[cfg(test)]
+pub fn clear_sessions() {
+    SESSIONS.lock().unwrap().clear();
+}
+
+pub fn credentials() -> Credentials {
+    CREDENTIALS.lock().unwrap().clone()
+}
+
+/// `host` for a `klap://host` address, None for a legacy one
+pub fn klap_host(addr: &str) -> Option<&str> {
+    addr.strip_prefix(KLAP_SCHEME)
+}
+
+/// Send one command over whichever transport the address asks for
+pub fn exchange(addr: &str, cmd: &Value) -> Result<Value> {
+    let host = match klap_host(addr) {
+        Some(host) => host,
+        None => return XorTransport::new(addr).exchange(cmd),
+    };
+    //taken out of the cache so the lock isn't held while talking to the device
+    let cached = {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let idx = sessions.iter().position(|s| s.host() == host);
+        idx.map(|idx| sessions.swap_remove(idx))
+    };
+    let mut transport = cached.unwrap_or_else(|| KlapTransport::new(host, credentials()));
+    let reply = transport.exchange(cmd);
+    SESSIONS.lock().unwrap().push(transport);
+    reply
+}
+
+/// The original protocol: JSON "encrypted" with an autokey XOR cipher, sent
+/// over TCP 9999 with a big endian length prefix
+pub struct XorTransport {
+    addr: String,
+}
+
+impl XorTransport {
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+        }
+    }
+}
+
+impl Transport for XorTransport {
+    fn exchange(&mut self, cmd: &Value) -> Result<Value> {
+        let addr: SocketAddr = format!("{:}:{:}", self.addr, PORT).parse()?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let payload = encrypt(cmd.to_string().as_bytes());
+        stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+        stream.write_all(&payload)?;
+
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_RESPONSE {
+            bail!("response from {:} too long: {:} bytes", addr, len);
+        }
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf)?;
+        Ok(serde_json::from_slice(&decrypt(&buf))?)
+    }
+}
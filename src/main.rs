@@ -1,37 +1,127 @@
-use crate::peripheral_util::{
-    battery_monitor::BatteryMonitor,
-    buttons,
-    display::{display_error, Display, DisplayMessage},
-    wifi,
-};
-use anyhow::{bail, Result};
-use embedded_hal_bus::i2c::MutexDevice;
-use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::hal::prelude::Peripherals;
-use esp_idf_svc::hal::prelude::*;
-use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
-use esp_idf_svc::hal::{gpio, i2c};
-use std::sync::{mpsc, Mutex};
-use std::thread;
-
+pub mod api;
+pub mod diagnostics;
+pub mod event_log;
+pub mod health;
+pub mod kasa;
+pub mod metrics;
+pub mod module_registry;
 pub mod module_runner;
 pub mod modules;
+pub mod mqtt;
+pub mod ota;
 pub mod peripheral_util;
-use crate::modules::{kasa_control, snake, test};
+pub mod service_manager;
+pub mod settings;
+
+use crate::kasa::hub::{parse_scenes, KasaHub};
+use anyhow::Result;
+use std::time::Duration;
 
 /// This configuration is picked up at compile time by `build.rs` from the
 /// file `cfg.toml`.
 #[toml_cfg::toml_config]
 pub struct Config {
+    /// Comma separated addresses of the Kasa devices, the first one is what
+    /// the Kasa module controls
+    #[default("127.0.0.1")]
+    target_ip: &'static str,
+    /// Outlet groups for the HTTP API, `name:dev.outlet=on|off|toggle,...;name2:...`
+    #[default("")]
+    scenes: &'static str,
+    /// Comma separated module names to hide until enabled in settings
+    #[default("")]
+    disabled_modules: &'static str,
+    /// Local time offset from UTC in minutes, for where days start
+    #[default(0)]
+    utc_offset_min: i32,
+    /// Price per kWh for the Energy module, empty hides costs
+    #[default("")]
+    tariff_per_kwh: &'static str,
+    #[default("$")]
+    currency: &'static str,
+    /// Kasa/Tapo account for `klap://` devices until changed in settings
+    #[default("")]
+    kasa_username: &'static str,
+    #[default("")]
+    kasa_password: &'static str,
+    /// Firmware image `POST /api/ota` installs, with its signature at the
+    /// same url plus `.sig`
+    #[default("")]
+    ota_url: &'static str,
+    /// Bearer token both OTA endpoints want, empty turns updates off
+    #[default("")]
+    ota_token: &'static str,
+    /// Hex Ed25519 public key images have to be signed with
+    #[default("")]
+    ota_public_key: &'static str,
+}
+
+/// Settings only the remote itself reads, from the same `cfg.toml` table.
+/// A PC build has no Wi-Fi, MQTT, idle or battery handling to use them.
+#[cfg(target_os = "espidf")]
+#[toml_cfg::toml_config]
+pub struct Remote {
     #[default("blah")]
     wifi_ssid: &'static str,
     #[default("blah")]
     wifi_psk: &'static str,
-    #[default("127.0.0.1")]
-    target_ip: &'static str,
+    /// Broker to bridge the hub to, e.g. `mqtt://192.168.1.2:1883`, empty disables MQTT
+    #[default("")]
+    mqtt_url: &'static str,
+    #[default("")]
+    mqtt_user: &'static str,
+    #[default("")]
+    mqtt_password: &'static str,
+    /// Topic prefix, also the Home Assistant node id
+    #[default("kasa-remote")]
+    mqtt_prefix: &'static str,
+    /// Seconds without a key press before the display dims
+    #[default(30)]
+    dim_timeout_s: u32,
+    /// Seconds before a screensaver replaces the screen to spare the OLED
+    #[default(45)]
+    saver_timeout_s: u32,
+    /// Seconds before the display turns off and Wi-Fi drops to modem sleep
+    #[default(60)]
+    blank_timeout_s: u32,
+    /// Seconds before the remote goes into deep sleep
+    #[default(600)]
+    sleep_timeout_s: u32,
+    /// Charge percentage the fuel gauge raises its low battery alert at, 1-32
+    #[default(10)]
+    battery_alert_pct: u8,
+    /// Charge percentage the remote shuts down at to avoid a brown out
+    #[default(3)]
+    battery_critical_pct: u8,
 }
 
+#[cfg(target_os = "espidf")]
 fn main() -> Result<()> {
+    use crate::health::{Reporter, Service};
+    use crate::module_registry::ModuleRegistry;
+    use crate::modules::{
+        diagnostics as diag_module, energy, kasa_control, log_viewer, snake, test,
+    };
+    use crate::peripheral_util::{
+        battery_monitor::BatteryMonitor,
+        buttons,
+        display::{display_error, Display},
+        display_queue::display_queue,
+        power::{self, IdleTimeouts, IdleTimer, PowerManager, SystemClock},
+        wifi,
+    };
+    use crate::service_manager::{ServiceManager, ServiceSpec};
+    use crate::settings::{SettingsStore, SharedSettings};
+    use anyhow::bail;
+    use embedded_hal_bus::i2c::MutexDevice;
+    use esp_idf_svc::eventloop::EspSystemEventLoop;
+    use esp_idf_svc::hal::prelude::Peripherals;
+    use esp_idf_svc::hal::prelude::*;
+    use esp_idf_svc::hal::{gpio, i2c};
+    use esp_idf_svc::nvs::EspDefaultNvsPartition;
+    use esp_idf_svc::sntp::EspSntp;
+    use std::sync::{mpsc, Mutex};
+
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_svc::sys::link_patches();
@@ -40,11 +130,18 @@ fn main() -> Result<()> {
     }
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
-    let peripherals = Peripherals::take().unwrap();
+    metrics::init();
+    let peripherals = Peripherals::take()?;
     let sysloop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+    if let Err(e) = event_log::nvs_store::init(nvs.clone()) {
+        log::warn!("event log unavailable: {:?}", e);
+    }
+    event_log::install_panic_hook();
 
     // The constant `CONFIG` is auto-generated by `toml_config`.
     let app_config = CONFIG;
+    let remote = REMOTE;
 
     let buttons: Vec<gpio::AnyIOPin> = vec![
         peripherals.pins.gpio46.into(), //1
@@ -69,15 +166,22 @@ fn main() -> Result<()> {
     //and reborrowing via &* to get a place expression from the box
     //https://haibane-tenshi.github.io/rust-reborrowing/
     let bus = &*Box::leak(i2c_mutex);
-    let device1 = MutexDevice::new(bus);
-    let device2 = MutexDevice::new(bus);
+
+    //a flat remote only wakes for the gauge seeing a charger, make sure it
+    //really is one before bringing everything up
+    if power::woke_from_charger()
+        && !BatteryMonitor::charger_confirmed(MutexDevice::new(bus), MutexDevice::new(bus))
+    {
+        power::shutdown_sleep();
+    }
 
     let (but_tx, but_rx) = mpsc::channel();
-    let (disp_tx, disp_rx) = mpsc::channel::<DisplayMessage>();
+    let (disp_tx, disp_rx) = display_queue();
+    let (reporter, health_rx) = Reporter::new();
 
-    let mut _wifi = match wifi::wifi(
-        app_config.wifi_ssid,
-        app_config.wifi_psk,
+    let wifi = match wifi::wifi(
+        remote.wifi_ssid,
+        remote.wifi_psk,
         peripherals.modem,
         sysloop,
         false,
@@ -87,82 +191,214 @@ fn main() -> Result<()> {
             bail!("Could not connect to Wi-Fi network: {:?}", err)
         }
     };
-    //this apparently works for the anteceding thread builder call
-    //https://github.com/esp-rs/esp-idf-hal/issues/228#issuecomment-1676035648
-    ThreadSpawnConfiguration {
-        name: Some("display_service\0".as_bytes()),
-        stack_size: 32000,
-        priority: 13,
-        ..Default::default()
-    }
-    .set()
-    .unwrap();
+    //dates for the energy history, syncs in the background once started
+    let _sntp = EspSntp::new_default()?;
+    let mut services = ServiceManager::new();
+    let display_reporter = reporter.clone();
+    services.register(
+        ServiceSpec {
+            name: "display_service",
+            stack_size: 32000,
+            priority: 13,
+            depends_on: &[],
+            liveness: Some(Duration::from_secs(10)),
+        },
+        move || {
+            let mut display = Display::new();
+            //a fresh handle on the bus every time the panel is brought up again
+            health::supervise(Service::Display, &display_reporter, || {
+                display.display_service(MutexDevice::new(bus), &disp_rx)
+            });
+        },
+    );
 
-    let _d_thread = thread::Builder::new().stack_size(32000).spawn(move || {
-        let _ = Display::new().display_service(device1, disp_rx);
+    let health_dtx = disp_tx.clone();
+    services.register(
+        ServiceSpec {
+            name: "health_service",
+            stack_size: 6000,
+            priority: 10,
+            depends_on: &["display_service"],
+            liveness: None,
+        },
+        move || health::health_service(health_rx, health_dtx),
+    );
+
+    let settings = SharedSettings::new(SettingsStore::new(nvs)?);
+    kasa::transport::set_credentials(settings.get().kasa_credentials());
+    //modules read device state from the hub and switch through it
+    let hub = KasaHub::new(&kasa::device_addrs(), parse_scenes(app_config.scenes));
+    hub.share();
+    let mut registry = ModuleRegistry::new();
+    registry.register(snake::INFO, || Box::new(snake::Snake::new()));
+    registry.register(kasa_control::INFO, || {
+        Box::new(kasa_control::KasaControl::new())
+    });
+    registry.register(energy::INFO, || Box::new(energy::Energy::new()));
+    registry.register(log_viewer::INFO, || Box::new(log_viewer::LogViewer::new()));
+    registry.register(diag_module::INFO, || {
+        Box::new(diag_module::Diagnostics::new())
     });
+    registry.register(test::INFO, || Box::new(test::TestModule::new()));
+    registry.apply_disabled(&settings.get().disabled_modules);
+
+    let timeouts = IdleTimeouts::from_secs(
+        remote.dim_timeout_s,
+        remote.saver_timeout_s,
+        remote.blank_timeout_s,
+        remote.sleep_timeout_s,
+    );
+    let power = PowerManager::new(IdleTimer::new(SystemClock, timeouts), disp_tx.clone());
 
-    ThreadSpawnConfiguration {
-        name: Some("runner_service\0".as_bytes()),
-        stack_size: 10000,
-        priority: 14,
-        ..Default::default()
-    }
-    .set()
-    .unwrap();
     let runner_dtx = disp_tx.clone();
     let mut md = crate::module_runner::ModuleRunner::new(
         but_rx,
         disp_tx.clone(),
-        vec![
-            Box::new(snake::Snake::new()),
-            Box::new(kasa_control::KasaControl::new()),
-            Box::new(test::TestModule::new()),
-        ],
+        registry,
+        settings.clone(),
+        power,
     );
-    let _e_thread = thread::Builder::new().stack_size(10000).spawn(move || {
-        module_runner::runner_service(&mut md);
-        //if module_runner is dying, will it kill child threads?
-        display_error(runner_dtx, "Module_Runner\r\nExited".to_string());
-    });
-
-    ThreadSpawnConfiguration {
-        name: Some("button_service\0".as_bytes()),
-        stack_size: 4000,
-        priority: 15,
-        ..Default::default()
+    if power::woke_from_sleep() {
+        md.restore_last_module();
     }
-    .set()
-    .unwrap();
+    let runner_requests = md.requests();
+    #[cfg(not(feature = "threaded-runner"))]
+    let hook_watch = md.hook_watch();
+    services.register(
+        ServiceSpec {
+            name: "runner_service",
+            stack_size: 10000,
+            priority: 14,
+            depends_on: &["display_service"],
+            liveness: Some(Duration::from_secs(10)),
+        },
+        move || {
+            #[cfg(feature = "threaded-runner")]
+            module_runner::runner_service(&mut md);
+            #[cfg(not(feature = "threaded-runner"))]
+            module_runner::cooperative_service(&mut md);
+            //if module_runner is dying, will it kill child threads?
+            display_error(runner_dtx, "Module_Runner\r\nExited".to_string());
+        },
+    );
 
-    let _e_thread = thread::Builder::new().stack_size(4000).spawn(move || {
-        buttons::button_service(buttons, but_tx.clone());
-    });
+    let buttons_reporter = reporter.clone();
+    services.register(
+        ServiceSpec {
+            name: "button_service",
+            stack_size: 4000,
+            priority: 15,
+            depends_on: &["runner_service"],
+            liveness: Some(Duration::from_secs(5)),
+        },
+        move || {
+            let pins = match buttons::setup(buttons) {
+                Ok(pins) => pins,
+                Err(e) => return buttons_reporter.report(Service::Buttons, e),
+            };
+            health::supervise(Service::Buttons, &buttons_reporter, || {
+                buttons::button_service(&pins, but_tx.clone())
+            });
+        },
+    );
+
+    //formatting floats and errors needs more room than the bare soc did
+    let alert_pct = remote.battery_alert_pct;
+    let critical_pct = remote.battery_critical_pct;
+    let battery_dtx = disp_tx.clone();
+    let battery_reporter = reporter.clone();
+    services.register(
+        ServiceSpec {
+            name: "battery_service",
+            stack_size: 4000,
+            priority: 17,
+            depends_on: &["display_service", "runner_service"],
+            liveness: Some(Duration::from_secs(90)),
+        },
+        move || {
+            let mut monitor = BatteryMonitor::new(alert_pct, critical_pct);
+            health::supervise(Service::Battery, &battery_reporter, || {
+                monitor.battery_service(
+                    MutexDevice::new(bus),
+                    MutexDevice::new(bus),
+                    &battery_dtx,
+                    &runner_requests,
+                )
+            });
+        },
+    );
 
-    ThreadSpawnConfiguration {
-        name: Some("battery_service\0".as_bytes()),
-        stack_size: 2000,
-        priority: 17,
-        ..Default::default()
+    let wifi_dtx = disp_tx.clone();
+    let wifi_reporter = reporter.clone();
+    services.register(
+        ServiceSpec {
+            name: "wifi_service",
+            stack_size: 6000,
+            priority: 12,
+            depends_on: &["display_service"],
+            liveness: Some(Duration::from_secs(30)),
+        },
+        move || wifi::wifi_service(wifi, wifi_dtx, wifi_reporter),
+    );
+
+    let poller = hub.clone();
+    services.register(
+        ServiceSpec {
+            name: "kasa_hub",
+            stack_size: 8000,
+            priority: 12,
+            depends_on: &["wifi_service"],
+            //a poll round waits out the timeout of every unreachable device
+            liveness: Some(Duration::from_secs(120)),
+        },
+        move || poller.poll_service(Duration::from_secs(10)),
+    );
+
+    if !remote.mqtt_url.is_empty() {
+        let (sink, events) = mqtt::esp_client::connect(
+            remote.mqtt_url,
+            remote.mqtt_user,
+            remote.mqtt_password,
+            remote.mqtt_prefix,
+        )?;
+        let bridge = mqtt::MqttBridge::new(sink, hub.clone(), remote.mqtt_prefix);
+        services.register(
+            ServiceSpec {
+                name: "mqtt_bridge",
+                stack_size: 8000,
+                priority: 11,
+                depends_on: &["kasa_hub"],
+                liveness: Some(Duration::from_secs(90)),
+            },
+            move || mqtt::bridge_service(bridge, events, Duration::from_secs(30)),
+        );
     }
-    .set()
-    .unwrap();
 
-    let _e_thread = thread::Builder::new().stack_size(2000).spawn(move || {
-        let _ = BatteryMonitor::new().battery_service(device2, disp_tx.clone());
-    });
+    services.start()?;
+    //serves on its own task for as long as this is held
+    let _server = api::esp_server::start(hub)?;
 
     log::info!("Hello, after thread spawn");
 
+    let mut image_check = ota::esp_update::HealthCheck::new()?;
+    let mut sampler = diagnostics::Sampler::new();
     loop {
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-
-        if !_wifi.is_connected().unwrap() {
-            log::info!("wifi disconnected");
-            std::thread::sleep(std::time::Duration::from_secs(1)); //sleep a bit
-            if let Err(_status) = _wifi.connect() {
-                std::thread::sleep(std::time::Duration::from_secs(2)); //sleep a bit
-            }
-        }
+        std::thread::sleep(Duration::from_millis(1000));
+        service_manager::check();
+        #[cfg(not(feature = "threaded-runner"))]
+        module_runner::check_hooks(&hook_watch);
+        sampler.poll();
+        image_check.poll(wifi::connected() && service_manager::healthy());
     }
 }
+
+/// On a PC only the HTTP API runs, against the plugs in `target_ip`
+#[cfg(not(target_os = "espidf"))]
+fn main() -> Result<()> {
+    metrics::init();
+    kasa::transport::set_credentials(settings::Settings::default().kasa_credentials());
+    let hub = KasaHub::new(&kasa::device_addrs(), parse_scenes(CONFIG.scenes));
+    let poller = hub.clone();
+    std::thread::spawn(move || poller.poll_service(Duration::from_secs(10)));
+    api::host_server::serve("0.0.0.0:8080", &hub)
+}
@@ -0,0 +1,407 @@
+//Counters bumped from around the firmware and the Prometheus text rendering
+//of them for `/metrics`. Plain atomics so any thread can count without locking.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::diagnostics;
+use crate::kasa::hub::Device;
+use crate::peripheral_util::battery_monitor::BatteryState;
+
+/// Requests sent to Kasa devices
+pub static KASA_REQUESTS: AtomicU64 = AtomicU64::new(0);
+/// Requests that failed to connect, timed out or got an error back
+pub static KASA_FAILURES: AtomicU64 = AtomicU64::new(0);
+/// Display flushes
+pub static DISPLAY_FRAMES: AtomicU64 = AtomicU64::new(0);
+/// Display messages replaced by a newer one for the same region before drawing
+pub static DISPLAY_SUPERSEDED: AtomicU64 = AtomicU64::new(0);
+/// Display messages dropped because the queue was full
+pub static DISPLAY_DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Module frames discarded because that module was no longer on screen
+pub static DISPLAY_STALE: AtomicU64 = AtomicU64::new(0);
+/// Signal of the current access point, `NO_RSSI` while not associated
+pub static WIFI_RSSI: AtomicI32 = AtomicI32::new(NO_RSSI);
+pub const NO_RSSI: i32 = i32::MIN;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Mark the start of uptime, call once early in main
+pub fn init() {
+    START.get_or_init(Instant::now);
+}
+
+/// Time since `init`
+pub fn uptime() -> Duration {
+    START.get().map_or(Duration::ZERO, |start| start.elapsed())
+}
+
+pub fn count(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn set_rssi(rssi: Option<i8>) {
+    WIFI_RSSI.store(rssi.map_or(NO_RSSI, |r| r as i32), Ordering::Relaxed);
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+//one HELP/TYPE header followed by every sample of that metric
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+    let _ = writeln!(out, "# HELP {:} {:}", name, help);
+    let _ = writeln!(out, "# TYPE {:} {:}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{:} {:}", name, value);
+        } else {
+            let _ = writeln!(out, "{:}{{{:}}} {:}", name, labels, value);
+        }
+    }
+}
+
+/// Everything in the Prometheus text exposition format
+pub fn render(devices: &[Device], battery: &BatteryState) -> String {
+    let mut out = String::new();
+
+    let mut up = vec![];
+    let mut on = vec![];
+    let mut current = vec![];
+    let mut voltage = vec![];
+    let mut power = vec![];
+    let mut energy = vec![];
+    for (dev, device) in devices.iter().enumerate() {
+        let labels = format!("device=\"{:}\",addr=\"{:}\"", dev, escape(&device.addr));
+        up.push((labels, device.reachable as u8 as f64));
+        if !device.reachable {
+            continue;
+        }
+        for (idx, outlet) in device.outlets.iter().enumerate() {
+            let labels = format!(
+                "device=\"{:}\",outlet=\"{:}\",alias=\"{:}\"",
+                dev,
+                idx,
+                escape(&outlet.alias)
+            );
+            on.push((labels.clone(), outlet.on as u8 as f64));
+            if let Some(rt) = &outlet.realtime {
+                current.push((labels.clone(), rt.current_ma as f64 / 1000.0));
+                voltage.push((labels.clone(), rt.voltage_mv as f64 / 1000.0));
+                power.push((labels.clone(), rt.power_mw as f64 / 1000.0));
+                energy.push((labels, rt.total_wh as f64));
+            }
+        }
+    }
+    let mut families = vec![
+        (
+            "kasa_device_up",
+            "gauge",
+            "Device answered the last poll",
+            up,
+        ),
+        ("kasa_outlet_on", "gauge", "Relay state", on),
+        (
+            "kasa_outlet_current_amps",
+            "gauge",
+            "Outlet current",
+            current,
+        ),
+        (
+            "kasa_outlet_voltage_volts",
+            "gauge",
+            "Outlet voltage",
+            voltage,
+        ),
+        ("kasa_outlet_power_watts", "gauge", "Outlet power", power),
+        (
+            "kasa_outlet_energy_watt_hours_total",
+            "counter",
+            "Energy used since the outlet's meter was reset",
+            energy,
+        ),
+    ];
+
+    let single = |value: f64| vec![(String::new(), value)];
+    if battery.valid {
+        families.extend([
+            (
+                "remote_battery_soc_percent",
+                "gauge",
+                "Battery charge",
+                single(battery.soc as f64),
+            ),
+            (
+                "remote_battery_voltage_volts",
+                "gauge",
+                "Cell voltage",
+                single(battery.voltage as f64),
+            ),
+            (
+                "remote_battery_charging",
+                "gauge",
+                "On the charger",
+                single(battery.charging as u8 as f64),
+            ),
+        ]);
+    }
+    let rssi = WIFI_RSSI.load(Ordering::Relaxed);
+    if rssi != NO_RSSI {
+        families.push((
+            "remote_wifi_rssi_dbm",
+            "gauge",
+            "Wi-Fi signal",
+            single(rssi as f64),
+        ));
+    }
+    families.push((
+        "remote_uptime_seconds",
+        "gauge",
+        "Time since boot",
+        single(uptime().as_secs_f64()),
+    ));
+    if let Some(diag) = diagnostics::latest() {
+        families.extend([
+            (
+                "remote_heap_free_bytes",
+                "gauge",
+                "Free heap",
+                single(diag.heap.free as f64),
+            ),
+            (
+                "remote_heap_min_free_bytes",
+                "gauge",
+                "Lowest free heap since boot",
+                single(diag.heap.min_free as f64),
+            ),
+        ]);
+        let stacks: Vec<(String, f64)> = diag
+            .tasks
+            .iter()
+            .map(|t| {
+                (
+                    format!("task=\"{:}\"", escape(&t.name)),
+                    t.stack_free_min as f64,
+                )
+            })
+            .collect();
+        families.push((
+            "remote_task_stack_free_min_bytes",
+            "gauge",
+            "Least free stack a task has had",
+            stacks,
+        ));
+    }
+
+    let counters = [
+        (
+            "remote_kasa_requests_total",
+            "Requests sent to Kasa devices",
+            &KASA_REQUESTS,
+        ),
+        (
+            "remote_kasa_request_failures_total",
+            "Kasa requests that failed",
+            &KASA_FAILURES,
+        ),
+        (
+            "remote_display_frames_total",
+            "Display flushes",
+            &DISPLAY_FRAMES,
+        ),
+        (
+            "remote_display_superseded_total",
+            "Display messages replaced before they were drawn",
+            &DISPLAY_SUPERSEDED,
+        ),
+        (
+            "remote_display_dropped_total",
+            "Display messages dropped on a full queue",
+            &DISPLAY_DROPPED,
+        ),
+        (
+            "remote_display_stale_total",
+            "Module frames discarded after the module was switched away from",
+            &DISPLAY_STALE,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        let value = counter.load(Ordering::Relaxed) as f64;
+        families.push((name, "counter", help, single(value)));
+    }
+
+    for (name, kind, help, samples) in families {
+        family(&mut out, name, kind, help, &samples);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kasa::hub::Outlet;
+    use crate::kasa::light::DeviceKind;
+    use rust_kasa::models::Realtime;
+
+    fn battery(valid: bool) -> BatteryState {
+        BatteryState {
+            soc: 80.0,
+            voltage: 3.75,
+            rate: 0.0,
+            charging: false,
+            low: false,
+            remaining: None,
+            valid,
+        }
+    }
+
+    fn device(addr: &str, outlets: Vec<Outlet>, reachable: bool) -> Device {
+        Device {
+            addr: addr.to_string(),
+            alias: addr.to_string(),
+            model: String::new(),
+            kind: DeviceKind::Strip,
+            light: None,
+            outlets,
+            reachable,
+        }
+    }
+
+    fn outlet(alias: &str, on: bool, realtime: Option<Realtime>) -> Outlet {
+        Outlet {
+            id: None,
+            alias: alias.to_string(),
+            on,
+            realtime,
+        }
+    }
+
+    //a strip with a metered outlet whose alias needs escaping and a plain
+    //one, and a plug that didn't answer
+    fn devices() -> Vec<Device> {
+        let metered = Realtime {
+            current_ma: 250,
+            err_code: 0,
+            power_mw: 57_500,
+            slot_id: 0,
+            total_wh: 1234,
+            voltage_mv: 230_000,
+        };
+        vec![
+            device(
+                "10.0.0.2",
+                vec![
+                    outlet("desk \"lamp\"", true, Some(metered)),
+                    outlet("fan", false, None),
+                ],
+                true,
+            ),
+            device("10.0.0.3", vec![outlet("stale", true, None)], false),
+        ]
+    }
+
+    fn lines(text: &str, prefix: &str) -> Vec<String> {
+        text.lines()
+            .filter(|l| l.starts_with(prefix))
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[test]
+    fn families_have_headers() {
+        let text = render(&devices(), &battery(true));
+        for (name, kind) in [
+            ("kasa_device_up", "gauge"),
+            ("kasa_outlet_on", "gauge"),
+            ("kasa_outlet_current_amps", "gauge"),
+            ("kasa_outlet_voltage_volts", "gauge"),
+            ("kasa_outlet_power_watts", "gauge"),
+            ("kasa_outlet_energy_watt_hours_total", "counter"),
+            ("remote_battery_soc_percent", "gauge"),
+            ("remote_uptime_seconds", "gauge"),
+            ("remote_display_dropped_total", "counter"),
+        ] {
+            assert_eq!(lines(&text, &format!("# HELP {:} ", name)).len(), 1);
+            assert_eq!(
+                lines(&text, &format!("# TYPE {:} ", name)),
+                [format!("# TYPE {:} {:}", name, kind)]
+            );
+        }
+    }
+
+    #[test]
+    fn devices_and_outlets() {
+        let text = render(&devices(), &battery(true));
+        assert_eq!(
+            lines(&text, "kasa_device_up{"),
+            [
+                "kasa_device_up{device=\"0\",addr=\"10.0.0.2\"} 1",
+                "kasa_device_up{device=\"1\",addr=\"10.0.0.3\"} 0",
+            ]
+        );
+        //nothing from the plug that didn't answer, its state is stale
+        let lamp = "device=\"0\",outlet=\"0\",alias=\"desk \\\"lamp\\\"\"";
+        assert_eq!(
+            lines(&text, "kasa_outlet_on{"),
+            [
+                format!("kasa_outlet_on{{{:}}} 1", lamp),
+                "kasa_outlet_on{device=\"0\",outlet=\"1\",alias=\"fan\"} 0".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn meter_readings_are_in_base_units() {
+        let text = render(&devices(), &battery(true));
+        let lamp = "{device=\"0\",outlet=\"0\",alias=\"desk \\\"lamp\\\"\"}";
+        for (name, value) in [
+            ("kasa_outlet_current_amps", "0.25"),
+            ("kasa_outlet_voltage_volts", "230"),
+            ("kasa_outlet_power_watts", "57.5"),
+            ("kasa_outlet_energy_watt_hours_total", "1234"),
+        ] {
+            //only the metered outlet has a reading
+            assert_eq!(
+                lines(&text, &format!("{:}{{", name)),
+                [format!("{:}{:} {:}", name, lamp, value)]
+            );
+        }
+    }
+
+    #[test]
+    fn empty_families_are_left_out() {
+        let text = render(&[], &battery(false));
+        assert!(lines(&text, "kasa_").is_empty());
+        assert!(lines(&text, "# HELP kasa_").is_empty());
+        assert!(!text.contains("remote_battery"));
+        assert_eq!(lines(&text, "remote_uptime_seconds ").len(), 1);
+        assert_eq!(lines(&text, "remote_kasa_requests_total ").len(), 1);
+    }
+
+    #[test]
+    fn battery_when_the_gauge_answered() {
+        let text = render(&[], &battery(true));
+        assert_eq!(
+            lines(&text, "remote_battery_"),
+            [
+                "remote_battery_soc_percent 80",
+                "remote_battery_voltage_volts 3.75",
+                "remote_battery_charging 0",
+            ]
+        );
+    }
+}
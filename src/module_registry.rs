@@ -0,0 +1,80 @@
+use crate::module_runner::ModuleFactory;
+
+/// 8x8 monochrome icon, one byte per row, most significant bit on the left
+pub type Icon = [u8; 8];
+
+/// Used by the launcher to group modules
+#[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+pub enum Category {
+    Control,
+    Games,
+    Tools,
+}
+
+/// What a module tells the registry about itself
+#[derive(Copy, Clone)]
+pub struct ModuleInfo {
+    pub name: &'static str,
+    pub icon: Icon,
+    pub category: Category,
+}
+
+pub struct RegistryEntry {
+    pub info: ModuleInfo,
+    pub factory: ModuleFactory,
+    pub enabled: bool,
+}
+
+/// Every module the remote knows how to run, in launcher order
+#[derive(Default)]
+pub struct ModuleRegistry {
+    entries: Vec<RegistryEntry>,
+}
+
+impl ModuleRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a module, entries are kept grouped by category
+    pub fn register(&mut self, info: ModuleInfo, factory: ModuleFactory) {
+        let pos = self
+            .entries
+            .iter()
+            .position(|e| e.info.category > info.category)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(
+            pos,
+            RegistryEntry {
+                info,
+                factory,
+                enabled: true,
+            },
+        );
+    }
+
+    /// Disable every module whose name is in `disabled`, enable the rest
+    pub fn apply_disabled(&mut self, disabled: &[String]) {
+        for entry in &mut self.entries {
+            entry.enabled = !disabled.iter().any(|name| name == entry.info.name);
+        }
+    }
+
+    pub fn set_enabled(&mut self, idx: usize, enabled: bool) {
+        if let Some(entry) = self.entries.get_mut(idx) {
+            entry.enabled = enabled;
+        }
+    }
+
+    pub fn entries(&self) -> &[RegistryEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
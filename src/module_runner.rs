@@ -1,44 +1,256 @@
+use embedded_graphics::{
+    geometry::{Point, Size},
+    primitives::Rectangle,
+};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
 use std::mem::replace;
-use std::sync::mpsc;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::peripheral_util::display::DisplayMessage;
+use crate::event_log;
+use crate::kasa::transport;
+use crate::module_registry::ModuleRegistry;
+use crate::modules::{global_menu::GlobalMenu, launcher::Launcher};
+use crate::peripheral_util::display::{
+    display_clear, display_error, DisplayCommand, DisplayMessage, MessageType, SessionId,
+    StatusUpdate,
+};
+use crate::peripheral_util::display_queue::DisplaySender;
+use crate::peripheral_util::power::{self, PowerManager, PowerState, SystemClock};
+use crate::peripheral_util::screenshot;
+use crate::service_manager;
+use crate::settings::SharedSettings;
+
+/// Status sent to the active module to ask it to return from `run`
+pub const EXIT_STATUS: u32 = 10;
+
+/// How long a module thread gets to return after `EXIT_STATUS` before it is
+/// considered hung
+pub const EXIT_DEADLINE: Duration = Duration::from_millis(2000);
+
+/// How long the crash overlay stays up before the rebuilt module starts
+const RECOVERY_OVERLAY: Duration = Duration::from_millis(2000);
+
+/// Longest a module hook may run on the cooperative runner before the remote
+/// restarts, well inside the runner service's liveness window
+pub const HOOK_DEADLINE: Duration = Duration::from_secs(5);
+
+/// Module slot the launcher always occupies
+pub const LAUNCHER_IDX: usize = 0;
+/// Module slot of the global menu, registry entry `n` is slot `n + FIRST_MODULE_IDX`
+pub const OVERLAY_IDX: usize = 1;
+pub const FIRST_MODULE_IDX: usize = 2;
+
+/// Second press of the focus key within this opens the global menu
+const DOUBLE_PRESS: Duration = Duration::from_millis(400);
+/// Key that takes a screenshot when pressed together with the focus key
+const SCREENSHOT_KEY: usize = 8;
+/// How close together the focus key and `SCREENSHOT_KEY` count as a chord
+const CHORD: Duration = Duration::from_millis(200);
+
+/// Area below the status bar that modules draw into by default
+pub const MODULE_AREA: Rectangle = Rectangle::new(Point::new(0, 15), Size::new(128, 44));
 
 fn dummy_module() -> Box<dyn RemoteModule + Send> {
-    struct Dummy;
+    struct Dummy {
+        channels: ModuleChannels,
+    }
     impl RemoteModule for Dummy {
-        fn set_channel(
-            &mut self,
-            _receiver: mpsc::Receiver<RemoteMessage>,
-            _sender: mpsc::Sender<DisplayMessage>,
-        ) {
-        }
-        fn release_channel(&mut self) -> Option<mpsc::Receiver<RemoteMessage>> {
-            None
+        fn channels(&mut self) -> &mut ModuleChannels {
+            &mut self.channels
         }
         fn get_display_name(&self) -> String {
             "dummy".to_string()
         }
         fn run(&mut self) {}
     }
-    Box::new(Dummy)
+    Box::new(Dummy {
+        channels: ModuleChannels::default(),
+    })
 }
 
+/// Builds a fresh instance of a module, used at startup and to replace a
+/// module that crashed or hung
+pub type ModuleFactory = fn() -> Box<dyn RemoteModule + Send>;
+
 #[derive(Clone)]
 pub struct RemoteMessage {
     pub status: u32,
 }
+
+/// Things a module can ask of the runner
+pub enum RunnerRequest {
+    /// Switch to the module at this registry index
+    Open(usize),
+    /// Go back to the launcher
+    Launcher,
+    /// Leave the global menu for the module it was opened from
+    CloseOverlay,
+    /// Settings were changed, re-apply them
+    ReloadSettings,
+    /// Deep sleep right away instead of waiting for the idle timeout
+    Sleep,
+    Restart,
+    /// Battery is flat, save what's needed and power down
+    Shutdown,
+}
+
+/// Handles a module gets for the duration of a `tick`
+pub struct ModuleContext<'a> {
+    display: &'a DisplaySender,
+    requests: &'a mpsc::Sender<RunnerRequest>,
+}
+
+impl<'a> ModuleContext<'a> {
+    pub fn new(display: &'a DisplaySender, requests: &'a mpsc::Sender<RunnerRequest>) -> Self {
+        Self { display, requests }
+    }
+
+    /// Queue a message for the display service
+    pub fn send(&mut self, msg: DisplayMessage) {
+        let _ = self.display.send(msg);
+    }
+
+    /// Ask the runner to do something once this tick is over
+    pub fn request(&mut self, req: RunnerRequest) {
+        let _ = self.requests.send(req);
+    }
+}
+
+/// Which module hook the cooperative runner is in and since when, shared
+/// with the main task. Nothing can preempt a hook on the runner thread, so
+/// one that never returns can only be noticed from outside.
+#[derive(Clone, Default)]
+pub struct HookWatch(Arc<Mutex<Option<(String, Instant)>>>);
+
+impl HookWatch {
+    fn start(&self, name: String) {
+        *self.0.lock().unwrap() = Some((name, Instant::now()));
+    }
+
+    fn done(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    /// The module whose hook has been running for longer than `limit`
+    pub fn overdue(&self, limit: Duration) -> Option<String> {
+        match &*self.0.lock().unwrap() {
+            Some((name, since)) if since.elapsed() > limit => Some(name.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Polled from the main task while the cooperative runner is in use. A hook
+/// stuck for longer than `HOOK_DEADLINE` goes in the event log and the
+/// remote restarts, which is the only way to get the runner thread back.
+pub fn check_hooks(watch: &HookWatch) {
+    if let Some(name) = watch.overdue(HOOK_DEADLINE) {
+        log::error!("module {:} hung, restarting", name);
+        event_log::record(&format!("module {:} hung, restarting", name));
+        power::restart();
+    }
+}
+
+/// The button receiver and display sender a module owns while it is active
+/// on its own thread.
+#[derive(Default)]
+pub struct ModuleChannels {
+    pub receiver: Option<mpsc::Receiver<RemoteMessage>>,
+    pub sender: Option<DisplaySender>,
+    pub requests: Option<mpsc::Sender<RunnerRequest>>,
+}
+
+impl ModuleChannels {
+    /// Send a message to the display service, dropped if no sender is set
+    pub fn send(&self, msg: DisplayMessage) {
+        if let Some(tx) = &self.sender {
+            let _ = tx.send(msg);
+        }
+    }
+
+    /// Non-blocking read of the next button event
+    pub fn try_recv(&self) -> Option<RemoteMessage> {
+        self.receiver.as_ref().and_then(|rx| rx.try_recv().ok())
+    }
+}
+
+/// A module the runner can switch to.
+///
+/// Lifecycle:
+/// `resume` (only if previously suspended) -> `on_enter` -> `tick`... -> `on_exit` -> `suspend`
+///
+/// `tick` must not block, it gets every button event received since the last
+/// tick. The cooperative runner calls it directly from its loop; the threaded
+/// runner calls `run`, which by default drives `tick` on the module thread
+/// and returns when the runner sends `EXIT_STATUS`.
 pub trait RemoteModule {
-    fn set_channel(
-        &mut self,
-        chnl: mpsc::Receiver<RemoteMessage>,
-        sender: mpsc::Sender<DisplayMessage>,
-    );
-    fn release_channel(&mut self) -> Option<mpsc::Receiver<RemoteMessage>>;
+    fn channels(&mut self) -> &mut ModuleChannels;
     fn get_display_name(&self) -> String;
-    fn run(&mut self);
+
+    fn set_channel(&mut self, channels: ModuleChannels) {
+        log::info!("setting channel");
+        *self.channels() = channels;
+    }
+
+    fn release_channel(&mut self) -> Option<mpsc::Receiver<RemoteMessage>> {
+        let channels = self.channels();
+        channels.sender = None;
+        channels.requests = None;
+        channels.receiver.take()
+    }
+
+    /// Display regions the module draws in. Its frames are clipped to these
+    /// while it is on screen, and the runner clears them after the module is
+    /// switched away from.
+    fn regions(&self) -> Vec<Rectangle> {
+        vec![MODULE_AREA]
+    }
+
+    /// Module is becoming the active one
+    fn on_enter(&mut self) {}
+    /// Module is no longer the active one
+    fn on_exit(&mut self) {}
+    /// Module is being parked, anything it needs on return should be kept
+    fn suspend(&mut self) {}
+    /// Module is coming back after a `suspend`
+    fn resume(&mut self) {}
+
+    /// Periodic work, called every `tick_interval` with pending button events
+    fn tick(&mut self, _events: &[RemoteMessage], _ctx: &mut ModuleContext) {}
+    fn tick_interval(&self) -> Duration {
+        Duration::from_millis(50)
+    }
+
+    fn run(&mut self) {
+        let channels = self.channels();
+        let (sender, requests) = match (channels.sender.clone(), channels.requests.clone()) {
+            (Some(sender), Some(requests)) => (sender, requests),
+            _ => {
+                log::info!("no channel sender configured");
+                return;
+            }
+        };
+        let mut events = vec![];
+        loop {
+            std::thread::sleep(self.tick_interval());
+            if self.channels().receiver.is_none() {
+                log::info!("no channel receiver configured");
+                return;
+            }
+            events.clear();
+            while let Some(msg) = self.channels().try_recv() {
+                if msg.status == EXIT_STATUS {
+                    return;
+                }
+                events.push(msg);
+            }
+            self.tick(&events, &mut ModuleContext::new(&sender, &requests));
+        }
+    }
 }
 
 #[derive(PartialEq)]
@@ -55,70 +267,381 @@ pub enum SwitchDirection {
 }
 
 pub struct ModuleRunner {
-    focus: Focus, //inner vs outer
+    focus: Focus, //inner vs outer, special while the global menu is open
+    last_focus_press: Option<Instant>,
+    //where closing the global menu goes back to
+    overlay_return: usize,
+    //last title/focus pair sent to the status bar
+    published: Option<(usize, bool)>,
     btn_action: mpsc::Receiver<usize>,
     module_tx: mpsc::Sender<RemoteMessage>,
     module_rx: Option<mpsc::Receiver<RemoteMessage>>,
-    state_tx: mpsc::Sender<DisplayMessage>,
+    state_tx: DisplaySender,
+    //last session handed out, and the sender tagged with the current one
+    session: SessionId,
+    session_tx: Option<DisplaySender>,
+    request_tx: mpsc::Sender<RunnerRequest>,
+    request_rx: mpsc::Receiver<RunnerRequest>,
+    registry: ModuleRegistry,
+    settings: SharedSettings,
+    //launcher and global menu first, then one per registry entry
     modules: Vec<Box<dyn RemoteModule + Send>>,
     module_started: bool,
     module_idx: usize,
     last_module_idx: usize,
     module_handle: Option<thread::JoinHandle<Box<dyn RemoteModule + Send>>>,
+    //modules that have been run and parked, resumed instead of entered fresh
+    suspended: Vec<bool>,
+    //cooperative mode only, events waiting for the next tick and when it's due
+    pending: Vec<RemoteMessage>,
+    next_tick: Instant,
+    //set after a module was rebuilt, holds off restarting it so the overlay is readable
+    recovering_until: Option<Instant>,
+    hook_watch: HookWatch,
+    power: PowerManager<SystemClock>,
 }
 
 impl ModuleRunner {
     pub fn new(
         btn_channel: mpsc::Receiver<usize>,
-        disp_tx: mpsc::Sender<DisplayMessage>,
-        modules: Vec<Box<dyn RemoteModule + Send>>,
+        disp_tx: DisplaySender,
+        registry: ModuleRegistry,
+        settings: SharedSettings,
+        power: PowerManager<SystemClock>,
     ) -> Self {
         let (tx, rx) = mpsc::channel::<RemoteMessage>();
-        Self {
+        let (request_tx, request_rx) = mpsc::channel::<RunnerRequest>();
+        let mut modules: Vec<Box<dyn RemoteModule + Send>> = vec![
+            Box::new(Launcher::new(&registry)),
+            Box::new(GlobalMenu::new(&registry, settings.clone())),
+        ];
+        modules.extend(registry.entries().iter().map(|entry| (entry.factory)()));
+        let suspended = vec![false; modules.len()];
+        let mut runner = Self {
             focus: Focus::Outer,
+            last_focus_press: None,
+            overlay_return: LAUNCHER_IDX,
+            published: None,
             btn_action: btn_channel,
+            registry,
+            settings,
             modules,
             module_tx: tx,
             module_rx: Some(rx),
             state_tx: disp_tx,
+            session: 0,
+            session_tx: None,
+            request_tx,
+            request_rx,
             module_started: false,
-            module_idx: 0,
-            last_module_idx: 0,
+            module_idx: LAUNCHER_IDX,
+            last_module_idx: LAUNCHER_IDX,
             module_handle: None,
+            suspended,
+            pending: vec![],
+            next_tick: Instant::now(),
+            recovering_until: None,
+            hook_watch: HookWatch::default(),
+            power,
+        };
+        runner.apply_display_settings();
+        runner
+    }
+
+    /// Handle for services outside the module system to make requests
+    pub fn requests(&self) -> mpsc::Sender<RunnerRequest> {
+        self.request_tx.clone()
+    }
+
+    /// Handle for the main task to watch the cooperative runner's hooks
+    pub fn hook_watch(&self) -> HookWatch {
+        self.hook_watch.clone()
+    }
+
+    /// Reopen the module that was active when the remote went to sleep
+    pub fn restore_last_module(&mut self) {
+        let last = match self.settings.get().last_module {
+            Some(last) => last,
+            None => return,
+        };
+        if let Some(registry_idx) = self
+            .registry
+            .entries()
+            .iter()
+            .position(|entry| entry.info.name == last)
+        {
+            log::info!("restoring {:}", last);
+            self.open_module(registry_idx);
         }
     }
 
-    //toggle ui state
+    //toggle ui state, only the global menu leaves special focus
     pub fn move_focus(&mut self) {
         self.focus = match self.focus {
             Focus::Inner => Focus::Outer,
             Focus::Outer => Focus::Inner,
-            _ => Focus::Special,
+            Focus::Special => Focus::Special,
         }
     }
 
+    fn open_overlay(&mut self) {
+        if self.module_idx != OVERLAY_IDX {
+            self.overlay_return = self.module_idx;
+        }
+        self.module_idx = OVERLAY_IDX;
+        self.focus = Focus::Special;
+    }
+
+    fn close_overlay(&mut self, to: usize) {
+        self.focus = Focus::Outer;
+        //the module might have been disabled while the menu was open
+        self.module_idx = if self.ring().contains(&to) {
+            to
+        } else {
+            LAUNCHER_IDX
+        };
+    }
+
+    //slots that can be switched to, the launcher and every enabled module
+    fn ring(&self) -> Vec<usize> {
+        let enabled = self
+            .registry
+            .entries()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.enabled)
+            .map(|(idx, _)| idx + FIRST_MODULE_IDX);
+        std::iter::once(LAUNCHER_IDX).chain(enabled).collect()
+    }
+
     pub fn switch_module(&mut self, dir: SwitchDirection) {
-        let n_mod = self.modules.len();
-        match dir {
-            SwitchDirection::Previous => {
-                if self.module_idx > 0 {
-                    self.module_idx -= 1;
-                }
+        let ring = self.ring();
+        let pos = ring
+            .iter()
+            .position(|&idx| idx == self.module_idx)
+            .unwrap_or(0);
+        let pos = match dir {
+            SwitchDirection::Previous => (pos + ring.len() - 1) % ring.len(),
+            SwitchDirection::Next => (pos + 1) % ring.len(),
+            SwitchDirection::None => pos,
+        };
+        self.module_idx = ring[pos];
+    }
+
+    /// Jump straight to a registry entry, ignored if it is disabled
+    pub fn open_module(&mut self, registry_idx: usize) {
+        if let Some(entry) = self.registry.entries().get(registry_idx) {
+            if entry.enabled {
+                self.module_idx = registry_idx + FIRST_MODULE_IDX;
             }
-            SwitchDirection::Next => {
-                if self.module_idx < n_mod - 1 {
-                    self.module_idx += 1;
+        }
+    }
+
+    fn check_requests(&mut self) {
+        while let Ok(req) = self.request_rx.try_recv() {
+            match req {
+                RunnerRequest::Open(registry_idx) => self.open_module(registry_idx),
+                RunnerRequest::Launcher => self.close_overlay(LAUNCHER_IDX),
+                RunnerRequest::CloseOverlay => self.close_overlay(self.overlay_return),
+                RunnerRequest::ReloadSettings => self.reload_settings(),
+                RunnerRequest::Restart => {
+                    log::info!("restart requested");
+                    power::restart();
                 }
+                RunnerRequest::Sleep => self.sleep(),
+                RunnerRequest::Shutdown => self.shutdown(),
+            }
+        }
+    }
+
+    fn reload_settings(&mut self) {
+        let settings = self.settings.get();
+        self.registry.apply_disabled(&settings.disabled_modules);
+        transport::set_credentials(settings.kasa_credentials());
+        self.apply_display_settings();
+        //the launcher lists enabled modules, rebuild it unless it is running
+        if self.module_idx != LAUNCHER_IDX && self.last_module_idx != LAUNCHER_IDX {
+            self.modules[LAUNCHER_IDX] = self.build_module(LAUNCHER_IDX);
+            self.suspended[LAUNCHER_IDX] = false;
+        }
+    }
+
+    //contrast goes through the power manager, it dims relative to it
+    fn apply_display_settings(&mut self) {
+        let settings = self.settings.get();
+        self.power.set_contrast(settings.contrast);
+        for cmd in [
+            DisplayCommand::Invert(settings.invert_display),
+            DisplayCommand::Flip(settings.flip_display),
+        ] {
+            let _ = self.state_tx.send(DisplayMessage {
+                module_name: "runner".to_string(),
+                content: MessageType::Command(cmd),
+                status_line: false,
+                clear_rect: Rectangle::zero(),
+            });
+        }
+    }
+
+    //fresh instance of whatever lives in a module slot
+    fn build_module(&self, idx: usize) -> Box<dyn RemoteModule + Send> {
+        match idx {
+            LAUNCHER_IDX => Box::new(Launcher::new(&self.registry)),
+            OVERLAY_IDX => Box::new(GlobalMenu::new(&self.registry, self.settings.clone())),
+            _ => (self.registry.entries()[idx - FIRST_MODULE_IDX].factory)(),
+        }
+    }
+
+    /// Give the screen to the module in a slot under a new session. Frames
+    /// from the returned sender are clipped to the module's regions, and are
+    /// discarded once another session is granted or this one is revoked.
+    fn grant_display(&mut self, idx: usize) -> DisplaySender {
+        self.session = self.session.wrapping_add(1);
+        let _ = self.state_tx.send(DisplayMessage {
+            module_name: "runner".to_string(),
+            content: MessageType::Command(DisplayCommand::Grant {
+                session: self.session,
+                viewport: self.modules[idx].regions(),
+            }),
+            status_line: false,
+            clear_rect: Rectangle::zero(),
+        });
+        self.state_tx.for_session(self.session)
+    }
+
+    //whatever the outgoing module still has queued or sends late is dropped
+    fn revoke_display(&mut self) {
+        self.session_tx = None;
+        let _ = self.state_tx.send(DisplayMessage {
+            module_name: "runner".to_string(),
+            content: MessageType::Command(DisplayCommand::Revoke),
+            status_line: false,
+            clear_rect: Rectangle::zero(),
+        });
+    }
+
+    //keep the status bar title in step with the active module and focus
+    fn publish_status(&mut self) {
+        let state = (self.module_idx, self.focus != Focus::Outer);
+        if self.published == Some(state) {
+            return;
+        }
+        let name = match self.module_idx {
+            LAUNCHER_IDX => crate::modules::launcher::INFO.name,
+            OVERLAY_IDX => crate::modules::global_menu::INFO.name,
+            idx => self.registry.entries()[idx - FIRST_MODULE_IDX].info.name,
+        };
+        let _ = self.state_tx.send(DisplayMessage {
+            module_name: "runner".to_string(),
+            content: MessageType::Status(StatusUpdate::Title {
+                name: name.to_string(),
+                focused: state.1,
+            }),
+            status_line: true,
+            clear_rect: Rectangle::zero(),
+        });
+        self.published = Some(state);
+    }
+
+    //grab what's on screen for the API, the toast only goes up afterwards
+    fn take_screenshot(&mut self) {
+        let text = if screenshot::capture() {
+            "screenshot saved"
+        } else {
+            "nothing to capture"
+        };
+        let _ = self.state_tx.send(DisplayMessage {
+            module_name: "runner".to_string(),
+            content: MessageType::Toast(text.to_string()),
+            status_line: true,
+            clear_rect: Rectangle::zero(),
+        });
+    }
+
+    //dim, blank and finally deep sleep after enough time without a key press
+    fn check_power(&mut self) {
+        if self.power.poll() != Some(PowerState::Sleep) {
+            return;
+        }
+        self.save_last_module();
+        power::deep_sleep();
+    }
+
+    fn sleep(&mut self) {
+        self.save_last_module();
+        self.power.display_off();
+        //give the select key time to come back up, a held key wakes it right away
+        thread::sleep(Duration::from_millis(500));
+        power::deep_sleep();
+    }
+
+    fn shutdown(&mut self) {
+        self.save_last_module();
+        display_error(self.state_tx.clone(), "battery empty".to_string());
+        //leave the message up long enough to be read
+        thread::sleep(Duration::from_millis(3000));
+        self.power.display_off();
+        thread::sleep(Duration::from_millis(200));
+        power::shutdown_sleep();
+    }
+
+    //remember the open module so waking up can go straight back to it
+    fn save_last_module(&mut self) {
+        //the global menu itself isn't worth coming back to
+        let idx = if self.module_idx == OVERLAY_IDX {
+            self.overlay_return
+        } else {
+            self.module_idx
+        };
+        let last = match idx {
+            idx if idx >= FIRST_MODULE_IDX => {
+                Some(self.registry.entries()[idx - FIRST_MODULE_IDX].info.name)
             }
-            SwitchDirection::None => (),
+            _ => None,
         };
+        if let Err(e) = self
+            .settings
+            .update(|s| s.last_module = last.map(|name| name.to_string()))
+        {
+            log::error!("could not save last module: {:?}", e);
+        }
     }
 
     fn check_buttons(&mut self) {
         //98.999% of the time the buttons wont be pressed, let it time out quick
         if let Ok(event) = self.btn_action.recv_timeout(Duration::from_millis(10)) {
             log::info!("Press Registered: {:}", event);
+            //a press that turns the screen back on is only a wake up
+            if self.power.activity() {
+                return;
+            }
+            //the global menu gets every key
+            if self.focus == Focus::Special {
+                let _ = self.module_tx.send(RemoteMessage {
+                    status: event as u32,
+                });
+                return;
+            }
+            if event == SCREENSHOT_KEY && self.last_focus_press.is_some_and(|t| t.elapsed() < CHORD)
+            {
+                //the focus key was half of the chord, undo it
+                self.last_focus_press = None;
+                self.move_focus();
+                self.take_screenshot();
+                return;
+            }
             if event == 1 {
+                if self
+                    .last_focus_press
+                    .is_some_and(|t| t.elapsed() < DOUBLE_PRESS)
+                {
+                    //undo the first press before opening the menu
+                    self.last_focus_press = None;
+                    self.move_focus();
+                    self.open_overlay();
+                    return;
+                }
+                self.last_focus_press = Some(Instant::now());
                 self.move_focus();
                 if self.focus == Focus::Inner {
                     log::info!("inner")
@@ -150,6 +673,7 @@ impl ModuleRunner {
     }
 
     fn create_module_thread(&mut self) {
+        #[cfg(target_os = "espidf")]
         ThreadSpawnConfiguration {
             name: Some("cur_module\0".as_bytes()),
             stack_size: 10000,
@@ -161,56 +685,546 @@ impl ModuleRunner {
         //will need to remove from vec, lets replace it with a dummy for now
         //let replaced_name = self.modules[self.module_idx].get_display_name();
         let mut module = replace(&mut self.modules[self.module_idx], dummy_module());
+        let resume = replace(&mut self.suspended[self.module_idx], false);
         log::info!("creating thread");
         self.module_handle = Some(
             thread::Builder::new()
                 .stack_size(10000)
                 .spawn(move || {
+                    if resume {
+                        module.resume();
+                    }
+                    module.on_enter();
                     module.run();
+                    module.on_exit();
+                    module.suspend();
                     module
                 })
                 .unwrap(),
         );
     }
+
+    /// Collect the module thread after it was asked to exit. A module that
+    /// doesn't return within `deadline` is abandoned, one that panicked is
+    /// rebuilt; either way the runner keeps going.
+    fn join_module(&mut self, deadline: Duration) {
+        let idx = self.last_module_idx;
+        let handle = match self.module_handle.take() {
+            Some(handle) => handle,
+            None => return,
+        };
+        self.revoke_display();
+        let give_up = Instant::now() + deadline;
+        while !handle.is_finished() {
+            if Instant::now() > give_up {
+                //can't kill it, dropping the handle detaches the thread
+                self.recover_module(idx, "hung");
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        match handle.join() {
+            Ok(module) => {
+                self.modules[idx] = module;
+                self.suspended[idx] = true;
+                self.clear_module_regions(idx);
+                //release the channel's clone
+                self.module_rx = self.modules[idx].release_channel();
+            }
+            Err(_) => self.recover_module(idx, "crashed"),
+        }
+    }
+
+    /// Replace a module that crashed or hung with a fresh one from its factory
+    /// and put up an error overlay.
+    ///
+    /// Catching a panic needs panics to unwind, with `panic_abort` the chip
+    /// resets instead and this is never reached for crashes.
+    fn recover_module(&mut self, idx: usize, reason: &str) {
+        self.modules[idx] = self.build_module(idx);
+        let name = self.modules[idx].get_display_name();
+        log::error!("module {:} {:}, rebuilding it", name, reason);
+        self.suspended[idx] = false;
+        self.revoke_display();
+        //whatever held the old receiver is gone or stuck, start a new channel
+        let (tx, rx) = mpsc::channel::<RemoteMessage>();
+        self.module_tx = tx;
+        self.module_rx = Some(rx);
+        self.pending.clear();
+        self.clear_module_regions(idx);
+        display_error(
+            self.state_tx.clone(),
+            format!("{:} {:}\r\nrestarting...", name, reason),
+        );
+        self.recovering_until = Some(Instant::now() + RECOVERY_OVERLAY);
+        self.module_started = false;
+    }
+
+    //true while the crash overlay should be left alone
+    fn recovering(&mut self) -> bool {
+        match self.recovering_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                self.recovering_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    //run a hook of a module that lives on the runner thread, rebuilding it on
+    //panic. The hook watch lets the main task restart the remote if it hangs.
+    fn guarded(&mut self, idx: usize, hook: impl FnOnce(&mut ModuleRunner)) -> bool {
+        self.hook_watch.start(self.modules[idx].get_display_name());
+        let result = catch_unwind(AssertUnwindSafe(|| hook(self)));
+        self.hook_watch.done();
+        if result.is_err() {
+            self.recover_module(idx, "crashed");
+            return false;
+        }
+        true
+    }
+
+    //cooperative counterpart of create_module_thread, the module stays in place
+    fn enter_module(&mut self) -> bool {
+        let idx = self.module_idx;
+        self.pending.clear();
+        self.next_tick = Instant::now();
+        let resume = replace(&mut self.suspended[idx], false);
+        self.session_tx = Some(self.grant_display(idx));
+        self.guarded(idx, |mr| {
+            if resume {
+                mr.modules[idx].resume();
+            }
+            mr.modules[idx].on_enter();
+        })
+    }
+
+    fn leave_module(&mut self, idx: usize) {
+        self.revoke_display();
+        if self.guarded(idx, |mr| {
+            mr.modules[idx].on_exit();
+            mr.modules[idx].suspend();
+        }) {
+            self.suspended[idx] = true;
+            self.clear_module_regions(idx);
+        }
+    }
+
+    fn tick_module(&mut self) {
+        //collect whatever check_buttons forwarded since the last pass
+        if let Some(rx) = &self.module_rx {
+            self.pending.extend(rx.try_iter());
+        }
+        let now = Instant::now();
+        if now < self.next_tick {
+            return;
+        }
+        let idx = self.module_idx;
+        let ticked = self.guarded(idx, |mr| {
+            if let Some(display) = &mr.session_tx {
+                mr.modules[idx].tick(
+                    &mr.pending,
+                    &mut ModuleContext::new(display, &mr.request_tx),
+                );
+            }
+        });
+        if !ticked {
+            return;
+        }
+        //a slow tick holds up the keys, one that never returns is left to
+        //check_hooks
+        let took = now.elapsed();
+        if took > EXIT_DEADLINE {
+            log::warn!(
+                "{:} tick took {:}ms",
+                self.modules[idx].get_display_name(),
+                took.as_millis()
+            );
+        }
+        self.next_tick = now + self.modules[idx].tick_interval();
+        self.pending.clear();
+    }
+
+    //wipe whatever the outgoing module left on screen
+    fn clear_module_regions(&self, idx: usize) {
+        for region in self.modules[idx].regions() {
+            display_clear(&self.state_tx, region);
+        }
+    }
 }
 
 pub fn runner_service(mr: &mut ModuleRunner) {
     loop {
-        //check for any button events and respond
-        mr.check_buttons();
-
-        if !mr.module_started {
-            log::info!("module not started, lets try to start it");
-            //do we currently own the reciever in order to give it away
-            if mr.module_rx.is_some() {
-                //take runner's receiver
-                let rx = replace(&mut mr.module_rx, None).unwrap();
-                //give the receiver and sender to module that is being started
-                mr.modules[mr.module_idx].set_channel(rx, mr.state_tx.clone());
-                mr.module_rx = None; //is this redundant?
-            }
-            mr.create_module_thread();
-            mr.module_started = true;
-            mr.last_module_idx = mr.module_idx;
-        }
-        //running module but there's been a change
-        else if mr.module_started && mr.module_idx != mr.last_module_idx {
-            //send exit command
-            let _ = mr.module_tx.send(RemoteMessage { status: 10 });
-            //join thread that is returning, take will automatically
-            //replace mr.module_handle with None
-            mr.modules[mr.last_module_idx] = mr
-                .module_handle
-                .take()
-                .map(|x| x.join())
-                .unwrap()
-                .expect("Returning Module failed to join.");
+        service_manager::heartbeat();
+        runner_step(mr);
+    }
+}
+
+//one pass of the threaded runner's loop
+fn runner_step(mr: &mut ModuleRunner) {
+    //check for any button events and respond
+    mr.check_buttons();
+    mr.check_requests();
+    mr.check_power();
+    mr.publish_status();
+
+    if mr.recovering() {
+        return;
+    }
+
+    if !mr.module_started {
+        log::info!("module not started, lets try to start it");
+        let display = mr.grant_display(mr.module_idx);
+        //do we currently own the reciever in order to give it away
+        if let Some(rx) = mr.module_rx.take() {
+            //give the receiver and senders to module that is being started
+            mr.modules[mr.module_idx].set_channel(ModuleChannels {
+                receiver: Some(rx),
+                sender: Some(display),
+                requests: Some(mr.request_tx.clone()),
+            });
+        }
+        mr.create_module_thread();
+        mr.module_started = true;
+        mr.last_module_idx = mr.module_idx;
+    }
+    //running module but there's been a change
+    else if mr.module_idx != mr.last_module_idx {
+        //send exit command, unless the module already returned on its own
+        if mr.module_handle.is_some() {
+            let _ = mr.module_tx.send(RemoteMessage {
+                status: EXIT_STATUS,
+            });
+        }
+        mr.join_module(EXIT_DEADLINE);
+        if mr.module_started {
             mr.module_started = false;
             log::info!("module stopped");
-            //release the channel's clone
-            mr.module_rx = mr.modules[mr.last_module_idx].release_channel();
-        } else {
-            //log::info!("everything being skipped");
         }
     }
+    //module thread ended without being asked to, see if it died
+    else if mr
+        .module_handle
+        .as_ref()
+        .is_some_and(|handle| handle.is_finished())
+    {
+        mr.join_module(Duration::ZERO);
+        //a crash was already handled, a module that just returned is as much
+        //use and gets rebuilt the same way instead of leaving a blank screen
+        if mr.module_started {
+            mr.recover_module(mr.module_idx, "exited");
+        }
+    }
+}
+
+/// Drives the active module's `tick` from the runner thread instead of
+/// giving each module a thread of its own.
+pub fn cooperative_service(mr: &mut ModuleRunner) {
+    loop {
+        service_manager::heartbeat();
+        cooperative_step(mr);
+    }
+}
+
+//one pass of the cooperative runner's loop
+fn cooperative_step(mr: &mut ModuleRunner) {
+    //check for any button events, this also paces the loop
+    mr.check_buttons();
+    mr.check_requests();
+    mr.check_power();
+    mr.publish_status();
+
+    if mr.recovering() {
+        return;
+    }
+
+    if !mr.module_started {
+        mr.module_started = mr.enter_module();
+        mr.last_module_idx = mr.module_idx;
+    } else if mr.module_idx != mr.last_module_idx {
+        mr.leave_module(mr.last_module_idx);
+        mr.module_started = false;
+        log::info!("module stopped");
+    } else {
+        mr.tick_module();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module_registry::{Category, ModuleInfo};
+    use crate::peripheral_util::display_queue::{display_queue, DisplayReceiver};
+    use crate::peripheral_util::power::{IdleTimeouts, IdleTimer};
+    use crate::settings::SettingsStore;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    const INFO: ModuleInfo = ModuleInfo {
+        name: "faulty",
+        icon: [0; 8],
+        category: Category::Tools,
+    };
+    //first slot after the launcher and the global menu
+    const FAULTY_IDX: usize = FIRST_MODULE_IDX;
+    const CRASH_KEY: usize = 5;
+
+    //panics on CRASH_KEY, from tick or on its own thread
+    struct Panicky {
+        channels: ModuleChannels,
+    }
+
+    impl RemoteModule for Panicky {
+        fn channels(&mut self) -> &mut ModuleChannels {
+            &mut self.channels
+        }
+
+        fn get_display_name(&self) -> String {
+            INFO.name.to_string()
+        }
+
+        fn tick(&mut self, events: &[RemoteMessage], _ctx: &mut ModuleContext) {
+            if events.iter().any(|e| e.status == CRASH_KEY as u32) {
+                panic!("crash key pressed");
+            }
+        }
+    }
+
+    //ignores EXIT_STATUS until the test lets it go
+    struct Hangs {
+        channels: ModuleChannels,
+    }
+
+    static HANG_RELEASE: AtomicBool = AtomicBool::new(false);
+
+    impl RemoteModule for Hangs {
+        fn channels(&mut self) -> &mut ModuleChannels {
+            &mut self.channels
+        }
+
+        fn get_display_name(&self) -> String {
+            INFO.name.to_string()
+        }
+
+        fn run(&mut self) {
+            while !HANG_RELEASE.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    //returns from run straight away
+    struct Quits {
+        channels: ModuleChannels,
+    }
+
+    impl RemoteModule for Quits {
+        fn channels(&mut self) -> &mut ModuleChannels {
+            &mut self.channels
+        }
+
+        fn get_display_name(&self) -> String {
+            INFO.name.to_string()
+        }
+
+        fn run(&mut self) {}
+    }
+
+    //never returns from tick until the test lets it go
+    struct Stuck {
+        channels: ModuleChannels,
+    }
+
+    static STUCK_RELEASE: AtomicBool = AtomicBool::new(false);
+
+    impl RemoteModule for Stuck {
+        fn channels(&mut self) -> &mut ModuleChannels {
+            &mut self.channels
+        }
+
+        fn get_display_name(&self) -> String {
+            INFO.name.to_string()
+        }
+
+        fn tick(&mut self, _events: &[RemoteMessage], _ctx: &mut ModuleContext) {
+            while !STUCK_RELEASE.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    //each test counts the builds of its own factory, tests run in parallel
+    static COOPERATIVE_BUILDS: AtomicUsize = AtomicUsize::new(0);
+    static THREADED_BUILDS: AtomicUsize = AtomicUsize::new(0);
+    static HANG_BUILDS: AtomicUsize = AtomicUsize::new(0);
+    static QUIT_BUILDS: AtomicUsize = AtomicUsize::new(0);
+
+    fn runner(factory: ModuleFactory) -> (ModuleRunner, mpsc::Sender<usize>, DisplayReceiver) {
+        let (btn_tx, btn_rx) = mpsc::channel();
+        let (disp_tx, disp_rx) = display_queue();
+        let mut registry = ModuleRegistry::new();
+        registry.register(INFO, factory);
+        let hour = 3600;
+        let timeouts = IdleTimeouts::from_secs(hour, hour, hour, hour);
+        let power = PowerManager::new(IdleTimer::new(SystemClock, timeouts), disp_tx.clone());
+        let settings = SharedSettings::new(SettingsStore::default());
+        let mut mr = ModuleRunner::new(btn_rx, disp_tx, registry, settings, power);
+        mr.open_module(0);
+        (mr, btn_tx, disp_rx)
+    }
+
+    fn drain(rx: &DisplayReceiver) -> Vec<DisplayMessage> {
+        std::iter::from_fn(|| rx.try_recv().ok().map(|(_, msg)| msg)).collect()
+    }
+
+    //the overlay recover_module puts up, if one was sent
+    fn overlay(messages: &[DisplayMessage]) -> Option<String> {
+        messages.iter().find_map(|msg| match &msg.content {
+            MessageType::Lines(lines) if msg.module_name == "display_err" => {
+                lines.first().map(|l| l.line.clone())
+            }
+            _ => None,
+        })
+    }
+
+    fn assert_recovered(mr: &mut ModuleRunner, rx: &DisplayReceiver, reason: &str) {
+        assert_eq!(
+            overlay(&drain(rx)),
+            Some(format!("faulty {:}\r\nrestarting...", reason))
+        );
+        assert!(!mr.module_started);
+        assert!(!mr.suspended[FAULTY_IDX]);
+        assert!(mr.recovering());
+    }
+
+    #[test]
+    fn cooperative_panic_rebuilds_the_module() {
+        fn factory() -> Box<dyn RemoteModule + Send> {
+            COOPERATIVE_BUILDS.fetch_add(1, Ordering::SeqCst);
+            Box::new(Panicky {
+                channels: ModuleChannels::default(),
+            })
+        }
+        let (mut mr, btn_tx, rx) = runner(factory);
+        assert_eq!(COOPERATIVE_BUILDS.load(Ordering::SeqCst), 1);
+
+        cooperative_step(&mut mr);
+        assert!(mr.module_started);
+        assert_eq!(mr.module_idx, FAULTY_IDX);
+        drain(&rx);
+
+        btn_tx.send(CRASH_KEY).unwrap();
+        cooperative_step(&mut mr);
+        assert_eq!(COOPERATIVE_BUILDS.load(Ordering::SeqCst), 2);
+        assert_recovered(&mut mr, &rx, "crashed");
+        //the fresh module is the one the runner goes back to
+        assert_eq!(mr.module_idx, FAULTY_IDX);
+    }
+
+    #[test]
+    fn threaded_panic_rebuilds_the_module() {
+        fn factory() -> Box<dyn RemoteModule + Send> {
+            THREADED_BUILDS.fetch_add(1, Ordering::SeqCst);
+            Box::new(Panicky {
+                channels: ModuleChannels::default(),
+            })
+        }
+        let (mut mr, btn_tx, rx) = runner(factory);
+        runner_step(&mut mr);
+        assert!(mr.module_handle.is_some());
+        drain(&rx);
+
+        btn_tx.send(CRASH_KEY).unwrap();
+        let give_up = Instant::now() + Duration::from_secs(5);
+        while mr.module_started && Instant::now() < give_up {
+            runner_step(&mut mr);
+        }
+        assert_eq!(THREADED_BUILDS.load(Ordering::SeqCst), 2);
+        assert!(mr.module_handle.is_none());
+        assert_recovered(&mut mr, &rx, "crashed");
+    }
+
+    #[test]
+    fn hung_module_is_abandoned_and_rebuilt() {
+        fn factory() -> Box<dyn RemoteModule + Send> {
+            HANG_BUILDS.fetch_add(1, Ordering::SeqCst);
+            Box::new(Hangs {
+                channels: ModuleChannels::default(),
+            })
+        }
+        let (mut mr, _btn_tx, rx) = runner(factory);
+        runner_step(&mut mr);
+        assert!(mr.module_handle.is_some());
+        drain(&rx);
+
+        //switching away asks it to exit, which it never does
+        mr.switch_module(SwitchDirection::Previous);
+        assert_eq!(mr.module_idx, LAUNCHER_IDX);
+        let started = Instant::now();
+        runner_step(&mut mr);
+        assert!(started.elapsed() >= EXIT_DEADLINE);
+        assert_eq!(HANG_BUILDS.load(Ordering::SeqCst), 2);
+        assert!(mr.module_handle.is_none());
+        assert_recovered(&mut mr, &rx, "hung");
+        HANG_RELEASE.store(true, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn hung_tick_shows_up_in_the_hook_watch() {
+        fn factory() -> Box<dyn RemoteModule + Send> {
+            Box::new(Stuck {
+                channels: ModuleChannels::default(),
+            })
+        }
+        let (mut mr, _btn_tx, _rx) = runner(factory);
+        let watch = mr.hook_watch();
+        cooperative_step(&mut mr);
+        assert!(mr.module_started);
+        assert_eq!(watch.overdue(Duration::ZERO), None);
+
+        //the runner thread is stuck in tick, the watch is read from outside
+        let hung = thread::scope(|s| {
+            let ticking = s.spawn(|| cooperative_step(&mut mr));
+            let give_up = Instant::now() + Duration::from_secs(5);
+            let mut hung = None;
+            while hung.is_none() && Instant::now() < give_up {
+                thread::sleep(Duration::from_millis(10));
+                hung = watch.overdue(Duration::from_millis(100));
+            }
+            STUCK_RELEASE.store(true, Ordering::Relaxed);
+            ticking.join().unwrap();
+            hung
+        });
+        assert_eq!(hung.as_deref(), Some("faulty"));
+        assert_eq!(watch.overdue(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn module_that_returns_is_rebuilt_and_restarted() {
+        fn factory() -> Box<dyn RemoteModule + Send> {
+            QUIT_BUILDS.fetch_add(1, Ordering::SeqCst);
+            Box::new(Quits {
+                channels: ModuleChannels::default(),
+            })
+        }
+        let (mut mr, _btn_tx, rx) = runner(factory);
+        runner_step(&mut mr);
+        assert!(mr.module_handle.is_some());
+        drain(&rx);
+
+        let give_up = Instant::now() + Duration::from_secs(5);
+        while mr.module_started && Instant::now() < give_up {
+            runner_step(&mut mr);
+        }
+        assert_eq!(QUIT_BUILDS.load(Ordering::SeqCst), 2);
+        assert!(mr.module_handle.is_none());
+        assert_recovered(&mut mr, &rx, "exited");
+
+        //once the overlay has been up long enough it runs again
+        mr.recovering_until = Some(Instant::now());
+        runner_step(&mut mr);
+        assert!(mr.module_started);
+        assert!(mr.module_handle.is_some());
+        assert_eq!(mr.module_idx, FAULTY_IDX);
+    }
 }
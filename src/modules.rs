@@ -1,3 +1,8 @@
+pub mod diagnostics;
+pub mod energy;
+pub mod global_menu;
 pub mod kasa_control;
+pub mod launcher;
+pub mod log_viewer;
 pub mod snake;
 pub mod test;
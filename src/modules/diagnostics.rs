@@ -0,0 +1,144 @@
+use crate::diagnostics::{self, format_uptime, Snapshot};
+use crate::module_registry::{Category, ModuleInfo};
+use crate::module_runner::{
+    ModuleChannels, ModuleContext, RemoteMessage, RemoteModule, MODULE_AREA,
+};
+use crate::peripheral_util::display::{DisplayLine, DisplayMessage, MessageType, TextSize};
+
+use std::time::{Duration, Instant};
+
+pub const INFO: ModuleInfo = ModuleInfo {
+    name: "Diag",
+    icon: [
+        0b00000000, 0b00000010, 0b00000100, 0b01001000, 0b10110000, 0b00000000, 0b11111111,
+        0b00000000,
+    ],
+    category: Category::Tools,
+};
+
+const SAMPLE_KEY: u32 = 3;
+//the main loop samples every 10s, no point redrawing much faster
+const REDRAW: Duration = Duration::from_secs(2);
+const TASKS_PER_PAGE: usize = 3;
+
+/// Uptime and heap on the first page, then tasks with the least free stack
+/// first. Keys 1/3 page through, key 4 takes a fresh sample.
+pub struct Diagnostics {
+    channels: ModuleChannels,
+    snapshot: Option<Snapshot>,
+    page: usize,
+    drawn_at: Option<Instant>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self {
+            channels: ModuleChannels::default(),
+            snapshot: None,
+            page: 0,
+            drawn_at: None,
+        }
+    }
+
+    fn pages(&self) -> usize {
+        let tasks = self.snapshot.as_ref().map_or(0, |s| s.tasks.len());
+        1 + (tasks + TASKS_PER_PAGE - 1) / TASKS_PER_PAGE
+    }
+
+    fn step(&mut self, forward: bool) {
+        let pages = self.pages();
+        self.page = if forward {
+            (self.page + 1) % pages
+        } else {
+            (self.page + pages - 1) % pages
+        };
+        self.drawn_at = None;
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return vec!["no sample yet".to_string()],
+        };
+        if self.page == 0 {
+            return vec![
+                format!("up {:}", format_uptime(snapshot.uptime)),
+                format!("heap  {:>7}", snapshot.heap.free),
+                format!("min   {:>7}", snapshot.heap.min_free),
+                format!("block {:>7}", snapshot.heap.largest_block),
+            ];
+        }
+        let mut lines = vec![format!("tasks {:}/{:}", self.page, self.pages() - 1)];
+        let first = (self.page - 1) * TASKS_PER_PAGE;
+        for task in snapshot.tasks.iter().skip(first).take(TASKS_PER_PAGE) {
+            let cpu = match task.cpu_percent {
+                Some(cpu) => format!("{:>3.0}%", cpu),
+                None => "   -".to_string(),
+            };
+            let name: String = task.name.chars().take(9).collect();
+            lines.push(format!("{:<9} {:>5} {:}", name, task.stack_free_min, cpu));
+        }
+        lines
+    }
+
+    fn display_page(&self) -> DisplayMessage {
+        DisplayMessage {
+            module_name: INFO.name.to_string(),
+            content: MessageType::Lines(
+                self.lines()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, line)| DisplayLine {
+                        line,
+                        size: TextSize::Normal,
+                        x_offset: 0,
+                        y_offset: MODULE_AREA.top_left.y + idx as i32 * 11,
+                    })
+                    .collect(),
+            ),
+            status_line: false,
+            clear_rect: MODULE_AREA,
+        }
+    }
+}
+
+impl RemoteModule for Diagnostics {
+    fn channels(&mut self) -> &mut ModuleChannels {
+        &mut self.channels
+    }
+
+    fn get_display_name(&self) -> String {
+        INFO.name.to_string()
+    }
+
+    fn on_enter(&mut self) {
+        self.page = 0;
+        self.drawn_at = None;
+    }
+
+    fn tick_interval(&self) -> Duration {
+        Duration::from_millis(100)
+    }
+
+    fn tick(&mut self, events: &[RemoteMessage], ctx: &mut ModuleContext) {
+        for msg in events {
+            match msg.status {
+                0 => self.step(false),
+                2 => self.step(true),
+                SAMPLE_KEY => {
+                    self.snapshot = Some(diagnostics::sample());
+                    self.drawn_at = None;
+                }
+                _ => (),
+            }
+        }
+        if self.drawn_at.map_or(true, |t| t.elapsed() > REDRAW) {
+            if let Some(latest) = diagnostics::latest() {
+                self.snapshot = Some(latest);
+            }
+            self.page = self.page.min(self.pages() - 1);
+            ctx.send(self.display_page());
+            self.drawn_at = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use crate::kasa::{self, energy, energy::EnergyStats};
+use crate::module_registry::{Category, ModuleInfo};
+use crate::module_runner::{
+    ModuleChannels, ModuleContext, RemoteMessage, RemoteModule, MODULE_AREA,
+};
+use crate::peripheral_util::display::{DisplayLine, DisplayMessage, MessageType, TextSize};
+use crate::CONFIG;
+use anyhow::{anyhow, Result};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const INFO: ModuleInfo = ModuleInfo {
+    name: "Energy",
+    icon: [
+        0b00001100, 0b00011000, 0b00110000, 0b01111110, 0b00001100, 0b00011000, 0b00110000,
+        0b00100000,
+    ],
+    category: Category::Control,
+};
+
+//the meter history only changes slowly
+const REFRESH: Duration = Duration::from_secs(300);
+const REFRESH_KEY: u32 = 3;
+
+type Outlets = Vec<(String, Option<String>)>;
+type FetchResult = Result<(Outlets, EnergyStats)>;
+
+/// Today, this week and this month for the whole strip or one outlet,
+/// keys 1/3 step through them, key 4 refreshes
+pub struct Energy {
+    channels: ModuleChannels,
+    outlets: Outlets,
+    //0 is the whole device, n is outlet n-1
+    selection: usize,
+    stats: Option<EnergyStats>,
+    error: Option<String>,
+    fetch: Option<mpsc::Receiver<FetchResult>>,
+    //the selection the fetch in flight is for
+    fetching: usize,
+    //the selection changed while a fetch was running
+    queued: bool,
+    fetched_at: Option<Instant>,
+    update: bool,
+}
+
+impl Energy {
+    pub fn new() -> Self {
+        Self {
+            channels: ModuleChannels::default(),
+            outlets: vec![],
+            selection: 0,
+            stats: None,
+            error: None,
+            fetch: None,
+            fetching: 0,
+            queued: false,
+            fetched_at: None,
+            update: true,
+        }
+    }
+
+    //talking to the strip takes a dozen round trips for the whole device,
+    //do it off the runner thread. Only one fetch runs at a time, key presses
+    //meanwhile just queue another for whatever is selected once it's back.
+    fn start_fetch(&mut self) {
+        self.update = true;
+        if self.fetch.is_some() {
+            self.queued = true;
+            return;
+        }
+        //also on failure, so tick retries every REFRESH rather than every pass
+        self.fetched_at = Some(Instant::now());
+        let addr = match kasa::device_addrs().into_iter().next() {
+            Some(addr) => addr,
+            None => {
+                self.error = Some("no device".to_string());
+                return;
+            }
+        };
+        let selection = self.selection;
+        let (tx, rx) = mpsc::channel();
+        let spawned = thread::Builder::new().stack_size(8000).spawn(move || {
+            let _ = tx.send(Energy::fetch(&addr, selection));
+        });
+        match spawned {
+            Ok(_) => {
+                self.fetch = Some(rx);
+                self.fetching = selection;
+            }
+            Err(e) => self.error = Some(format!("{:}", e)),
+        }
+    }
+
+    fn fetch(addr: &str, selection: usize) -> FetchResult {
+        let today = energy::today().ok_or_else(|| anyhow!("no time yet"))?;
+        let outlets = energy::outlets(addr)?;
+        let ids: Vec<Option<String>> = match selection {
+            0 => outlets.iter().map(|(_, id)| id.clone()).collect(),
+            n => vec![outlets
+                .get(n - 1)
+                .ok_or_else(|| anyhow!("no outlet {:}", n))?
+                .1
+                .clone()],
+        };
+        let stats = energy::stats(addr, &ids, today)?;
+        Ok((outlets, stats))
+    }
+
+    fn check_fetch(&mut self) {
+        let result = match self.fetch.as_ref().map(|rx| rx.try_recv()) {
+            Some(Ok(result)) => result,
+            Some(Err(mpsc::TryRecvError::Empty)) | None => return,
+            Some(Err(mpsc::TryRecvError::Disconnected)) => Err(anyhow!("fetch failed")),
+        };
+        self.fetch = None;
+        //totals for a selection that has since been left are of no use
+        let current = self.fetching == self.selection;
+        match result {
+            Ok((outlets, stats)) => {
+                self.outlets = outlets;
+                if current {
+                    self.stats = Some(stats);
+                    self.error = None;
+                }
+            }
+            Err(e) => {
+                log::warn!("energy fetch failed: {:?}", e);
+                if current {
+                    self.error = Some(format!("{:}", e));
+                }
+            }
+        }
+        self.update = true;
+        if self.queued {
+            self.queued = false;
+            self.start_fetch();
+        }
+    }
+
+    fn select(&mut self, forward: bool) {
+        let count = self.outlets.len() + 1;
+        self.selection = if forward {
+            (self.selection + 1) % count
+        } else {
+            (self.selection + count - 1) % count
+        };
+        self.stats = None;
+        self.start_fetch();
+    }
+
+    fn title(&self) -> String {
+        match self.selection {
+            0 => "All outlets".to_string(),
+            n => match self.outlets.get(n - 1) {
+                Some((alias, _)) => format!("{:}:{:}", n, alias),
+                None => format!("Outlet {:}", n),
+            },
+        }
+    }
+
+    fn energy_line(label: &str, wh: u32) -> String {
+        let amount = if wh >= 10_000 {
+            format!("{:.1}kWh", wh as f32 / 1000.0)
+        } else {
+            format!("{:}Wh", wh)
+        };
+        match energy::tariff() {
+            Some(tariff) => format!(
+                "{:<5} {:>7} {:}{:.2}",
+                label,
+                amount,
+                CONFIG.currency,
+                wh as f32 / 1000.0 * tariff
+            ),
+            None => format!("{:<5} {:>7}", label, amount),
+        }
+    }
+
+    fn display_stats(&self) -> DisplayMessage {
+        let mut lines = vec![self.title().chars().take(21).collect::<String>()];
+        match (&self.stats, &self.error) {
+            (Some(stats), _) => {
+                lines.push(Energy::energy_line("Today", stats.today));
+                lines.push(Energy::energy_line("Week", stats.week));
+                lines.push(Energy::energy_line("Month", stats.month));
+            }
+            (None, Some(e)) => lines.push(e.chars().take(21).collect()),
+            (None, None) => lines.push("loading...".to_string()),
+        }
+        DisplayMessage {
+            module_name: INFO.name.to_string(),
+            content: MessageType::Lines(
+                lines
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, line)| DisplayLine {
+                        line,
+                        size: TextSize::Normal,
+                        x_offset: 0,
+                        y_offset: MODULE_AREA.top_left.y + idx as i32 * 11,
+                    })
+                    .collect(),
+            ),
+            status_line: false,
+            clear_rect: MODULE_AREA,
+        }
+    }
+}
+
+impl RemoteModule for Energy {
+    fn channels(&mut self) -> &mut ModuleChannels {
+        &mut self.channels
+    }
+
+    fn get_display_name(&self) -> String {
+        INFO.name.to_string()
+    }
+
+    fn on_enter(&mut self) {
+        self.update = true;
+        if self.fetched_at.map_or(true, |t| t.elapsed() > REFRESH) {
+            self.start_fetch();
+        }
+    }
+
+    fn tick_interval(&self) -> Duration {
+        Duration::from_millis(100)
+    }
+
+    fn tick(&mut self, events: &[RemoteMessage], ctx: &mut ModuleContext) {
+        for msg in events {
+            match msg.status {
+                0 => self.select(false),
+                2 => self.select(true),
+                REFRESH_KEY => self.start_fetch(),
+                _ => (),
+            }
+        }
+        if self.fetch.is_none() && self.fetched_at.map_or(true, |t| t.elapsed() > REFRESH) {
+            self.start_fetch();
+        }
+        self.check_fetch();
+        if self.update {
+            ctx.send(self.display_stats());
+            self.update = false;
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::module_registry::{Category, ModuleInfo, ModuleRegistry};
+use crate::module_runner::{
+    ModuleChannels, ModuleContext, RemoteMessage, RemoteModule, RunnerRequest, MODULE_AREA,
+};
+use crate::peripheral_util::display::{DisplayLine, DisplayMessage, MessageType, TextSize};
+use crate::settings::SharedSettings;
+
+pub const INFO: ModuleInfo = ModuleInfo {
+    name: "Menu",
+    icon: [
+        0b00000000, 0b01111110, 0b00000000, 0b01111110, 0b00000000, 0b01111110, 0b00000000,
+        0b00000000,
+    ],
+    category: Category::Tools,
+};
+
+//four lines of the normal font fit in the module area
+const VISIBLE_LINES: usize = 4;
+//contrast steps selecting the contrast line goes through
+const CONTRAST_LEVELS: [u8; 5] = [0x10, 0x40, 0x80, 0xc0, 0xff];
+
+#[derive(Copy, Clone, PartialEq)]
+enum MenuItem {
+    Back,
+    Home,
+    Settings,
+    Display,
+    Sleep,
+    Restart,
+}
+
+const MAIN_MENU: [MenuItem; 6] = [
+    MenuItem::Back,
+    MenuItem::Home,
+    MenuItem::Settings,
+    MenuItem::Display,
+    MenuItem::Sleep,
+    MenuItem::Restart,
+];
+
+impl MenuItem {
+    fn label(&self) -> &'static str {
+        match self {
+            MenuItem::Back => "Back",
+            MenuItem::Home => "Home",
+            MenuItem::Settings => "Settings",
+            MenuItem::Display => "Display",
+            MenuItem::Sleep => "Sleep",
+            MenuItem::Restart => "Restart",
+        }
+    }
+}
+
+enum Page {
+    Main,
+    //one line per registered module plus a back line at the top
+    Settings,
+    //back, contrast, invert and flip
+    Display,
+}
+
+/// Overlay opened from any module with a double press of the focus key.
+/// Keys 1/3 move the cursor, key 2 selects.
+pub struct GlobalMenu {
+    channels: ModuleChannels,
+    settings: SharedSettings,
+    module_names: Vec<&'static str>,
+    page: Page,
+    cursor: usize,
+    update: bool,
+}
+
+impl GlobalMenu {
+    pub fn new(registry: &ModuleRegistry, settings: SharedSettings) -> Self {
+        Self {
+            channels: ModuleChannels::default(),
+            settings,
+            module_names: registry.entries().iter().map(|e| e.info.name).collect(),
+            page: Page::Main,
+            cursor: 0,
+            update: true,
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        match self.page {
+            Page::Main => MAIN_MENU.iter().map(|i| i.label().to_string()).collect(),
+            Page::Settings => {
+                let settings = self.settings.get();
+                let mut lines = vec!["Back".to_string()];
+                lines.extend(self.module_names.iter().map(|name| {
+                    let mark = if settings.module_enabled(name) {
+                        "x"
+                    } else {
+                        " "
+                    };
+                    format!("[{:}] {:}", mark, name)
+                }));
+                lines
+            }
+            Page::Display => {
+                let settings = self.settings.get();
+                let mark = |on| if on { "x" } else { " " };
+                vec![
+                    "Back".to_string(),
+                    format!("Contrast {:}", settings.contrast),
+                    format!("[{:}] Invert", mark(settings.invert_display)),
+                    format!("[{:}] Flip", mark(settings.flip_display)),
+                ]
+            }
+        }
+    }
+
+    fn change_display(&mut self, ctx: &mut ModuleContext) {
+        let cursor = self.cursor;
+        let res = self.settings.update(|s| match cursor {
+            1 => {
+                s.contrast = CONTRAST_LEVELS
+                    .into_iter()
+                    .find(|level| *level > s.contrast)
+                    .unwrap_or(CONTRAST_LEVELS[0]);
+            }
+            2 => s.invert_display = !s.invert_display,
+            _ => s.flip_display = !s.flip_display,
+        });
+        if let Err(e) = res {
+            log::error!("could not save settings: {:?}", e);
+        }
+        ctx.request(RunnerRequest::ReloadSettings);
+    }
+
+    fn select(&mut self, ctx: &mut ModuleContext) {
+        match self.page {
+            Page::Main => match MAIN_MENU[self.cursor] {
+                MenuItem::Back => ctx.request(RunnerRequest::CloseOverlay),
+                MenuItem::Home => ctx.request(RunnerRequest::Launcher),
+                MenuItem::Settings => {
+                    self.page = Page::Settings;
+                    self.cursor = 0;
+                }
+                MenuItem::Display => {
+                    self.page = Page::Display;
+                    self.cursor = 0;
+                }
+                MenuItem::Sleep => ctx.request(RunnerRequest::Sleep),
+                MenuItem::Restart => ctx.request(RunnerRequest::Restart),
+            },
+            Page::Settings | Page::Display if self.cursor == 0 => {
+                self.page = Page::Main;
+                self.cursor = 0;
+            }
+            Page::Display => self.change_display(ctx),
+            Page::Settings => {
+                let name = self.module_names[self.cursor - 1];
+                let res = self.settings.update(|s| {
+                    let enabled = s.module_enabled(name);
+                    s.set_module_enabled(name, !enabled);
+                });
+                if let Err(e) = res {
+                    log::error!("could not save settings: {:?}", e);
+                }
+                ctx.request(RunnerRequest::ReloadSettings);
+            }
+        }
+    }
+
+    fn display_menu(&self) -> DisplayMessage {
+        let lines = self.lines();
+        //keep the cursor on screen
+        let first = self.cursor.saturating_sub(VISIBLE_LINES - 1);
+        DisplayMessage {
+            module_name: INFO.name.to_string(),
+            content: MessageType::Lines(
+                lines
+                    .iter()
+                    .enumerate()
+                    .skip(first)
+                    .take(VISIBLE_LINES)
+                    .map(|(idx, line)| {
+                        let marker = if idx == self.cursor { ">" } else { " " };
+                        DisplayLine {
+                            line: format!("{:}{:}", marker, line),
+                            size: TextSize::Normal,
+                            x_offset: 4,
+                            y_offset: MODULE_AREA.top_left.y + ((idx - first) as i32 * 11),
+                        }
+                    })
+                    .collect(),
+            ),
+            status_line: false,
+            clear_rect: MODULE_AREA,
+        }
+    }
+}
+
+impl RemoteModule for GlobalMenu {
+    fn channels(&mut self) -> &mut ModuleChannels {
+        &mut self.channels
+    }
+
+    fn get_display_name(&self) -> String {
+        INFO.name.to_string()
+    }
+
+    //always open on the main page
+    fn on_enter(&mut self) {
+        self.page = Page::Main;
+        self.cursor = 0;
+        self.update = true;
+    }
+
+    fn tick(&mut self, events: &[RemoteMessage], ctx: &mut ModuleContext) {
+        for msg in events {
+            let n_lines = self.lines().len();
+            match msg.status {
+                0 => self.cursor = (self.cursor + n_lines - 1) % n_lines,
+                2 => self.cursor = (self.cursor + 1) % n_lines,
+                1 => self.select(ctx),
+                _ => continue,
+            }
+            self.update = true;
+        }
+        if self.update {
+            ctx.send(self.display_menu());
+            self.update = false;
+        }
+    }
+}
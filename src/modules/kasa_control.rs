@@ -1,67 +1,345 @@
-use crate::module_runner::{RemoteMessage, RemoteModule};
+use crate::kasa::{
+    hub::{KasaHub, Outlet},
+    light::{DeviceKind, LightChange, LightState, TEMP_RANGE},
+};
+use crate::module_registry::{Category, ModuleInfo};
+use crate::module_runner::{
+    ModuleChannels, ModuleContext, RemoteMessage, RemoteModule, MODULE_AREA,
+};
 use crate::peripheral_util::display::{DisplayLine, DisplayMessage, MessageType, TextSize};
-use crate::CONFIG;
 use embedded_graphics::{
     geometry::{Point, Size},
     primitives::Rectangle,
 };
-use rust_kasa::{kasa_protocol, models::Realtime};
-use std::mem::replace;
-use std::net::TcpStream;
+use rust_kasa::models::Realtime;
 use std::sync::mpsc;
+use std::thread;
+
+pub const INFO: ModuleInfo = ModuleInfo {
+    name: "Kasa",
+    icon: [
+        0b00100100, 0b00100100, 0b01111110, 0b01111110, 0b00111100, 0b00011000, 0b00011000,
+        0b00011000,
+    ],
+    category: Category::Control,
+};
 
 enum BoolDir {
     Next,
     Prev,
 }
 
+//key hints at the bottom, descenders run past the module area
+const HINT_LINE: Rectangle = Rectangle::new(Point::new(0, 50), Size::new(128, 10));
+
+const TOGGLE_KEY: u32 = 3;
+const DOWN_KEY: u32 = 4;
+const UP_KEY: u32 = 5;
+
+//the module only drives the first configured device
+const DEVICE: usize = 0;
+
+/// What the worker does on the device, so the runner never waits on the network
+enum Command {
+    Outlet(usize),
+    Light(LightChange),
+}
+
+/// What the up/down keys adjust on a light
+#[derive(Copy, Clone, PartialEq)]
+enum LightPage {
+    Brightness,
+    Temp,
+    Hue,
+    Saturation,
+}
+
+impl LightPage {
+    fn pages(kind: DeviceKind) -> Vec<LightPage> {
+        match kind {
+            DeviceKind::Dimmer => vec![LightPage::Brightness],
+            DeviceKind::Bulb(features) => {
+                let mut pages = vec![];
+                if features.dimmable {
+                    pages.push(LightPage::Brightness);
+                }
+                if features.color_temp {
+                    pages.push(LightPage::Temp);
+                }
+                if features.color {
+                    pages.extend([LightPage::Hue, LightPage::Saturation]);
+                }
+                pages
+            }
+            _ => vec![],
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            LightPage::Brightness => "Bright",
+            LightPage::Temp => "Temp",
+            LightPage::Hue => "Hue",
+            LightPage::Saturation => "Sat",
+        }
+    }
+
+    fn value(&self, state: &LightState) -> String {
+        match self {
+            LightPage::Brightness => format!("{:}%", state.brightness),
+            LightPage::Temp if state.color_temp == 0 => "color".to_string(),
+            LightPage::Temp => format!("{:}K", state.color_temp),
+            LightPage::Hue => format!("{:}deg", state.hue),
+            LightPage::Saturation => format!("{:}%", state.saturation),
+        }
+    }
+
+    //one key press worth of change, hue wraps around the color wheel
+    fn step(&self, state: &LightState, up: bool) -> LightChange {
+        let dir = if up { 1 } else { -1 };
+        let mut change = LightChange::default();
+        match self {
+            LightPage::Brightness => {
+                change.brightness = Some((state.brightness as i32 + dir * 10).clamp(1, 100) as u8)
+            }
+            LightPage::Temp => {
+                let current = state.color_temp.max(TEMP_RANGE.0) as i32;
+                change.color_temp = Some(
+                    (current + dir * 250).clamp(TEMP_RANGE.0 as i32, TEMP_RANGE.1 as i32) as u16,
+                )
+            }
+            LightPage::Hue => change.hue = Some((state.hue as i32 + 360 + dir * 15) as u16 % 360),
+            LightPage::Saturation => {
+                change.saturation = Some((state.saturation as i32 + dir * 10).clamp(0, 100) as u8)
+            }
+        }
+        change
+    }
+}
+
+/// Outlet power of a strip, or brightness and color of a bulb or dimmer,
+/// whichever the first device turns out to be
 pub struct KasaControl {
-    receiver: Option<mpsc::Receiver<RemoteMessage>>,
-    sender: Option<mpsc::Sender<DisplayMessage>>,
-    stats: Vec<Realtime>,
+    channels: ModuleChannels,
+    //commands go out here, a () comes back on `done` once each is through
+    worker: Option<(mpsc::Sender<Command>, mpsc::Receiver<()>)>,
+    //commands sent but not through yet, the previewed state stands until then
+    pending: usize,
+    outlets: Vec<Outlet>,
     monitor_idx: usize,
     update: bool,
+    poll_counter: usize,
+    //None until the hub has reached the device, strip controls until then
+    kind: Option<DeviceKind>,
+    alias: String,
+    light: LightState,
+    light_page: usize,
 }
 
 impl KasaControl {
     pub fn new() -> Self {
         Self {
-            receiver: None,
-            sender: None,
-            stats: vec![
-                Realtime {
-                    current_ma: 0,
-                    err_code: 0,
-                    power_mw: 0,
-                    slot_id: 0,
-                    total_wh: 0,
-                    voltage_mv: 0,
-                };
-                7
-            ],
+            channels: ModuleChannels::default(),
+            worker: None,
+            pending: 0,
+            outlets: vec![],
             monitor_idx: 0,
             update: true,
+            poll_counter: 0,
+            kind: None,
+            alias: String::new(),
+            light: LightState::default(),
+            light_page: 0,
         }
     }
-    pub fn get_target_stat(idx: u8) -> Option<Realtime> {
-        let app_config = CONFIG;
-        let mut stream = TcpStream::connect(format!("{:}:9999", app_config.target_ip)).ok()?;
-        Some(kasa_protocol::get_realtime_by_idx(&mut stream, idx.into()).ok()?)
+
+    //state as of the hub's last poll, it does the talking to the device
+    fn refresh(&mut self) {
+        let device = match KasaHub::shared().and_then(|hub| hub.devices().into_iter().nth(DEVICE)) {
+            Some(device) if device.reachable => device,
+            _ => return,
+        };
+        if self.kind != Some(device.kind) {
+            self.light_page = 0;
+        }
+        self.kind = Some(device.kind);
+        self.alias = device.alias;
+        if let Some(state) = device.light {
+            self.light = state;
+        }
+        self.outlets = device.outlets;
+        self.update = true;
     }
 
-    pub fn get_all_stats() -> Option<Realtime> {
-        let app_config = CONFIG;
-        let mut stream = TcpStream::connect(format!("{:}:9999", app_config.target_ip)).ok()?;
-        let stats_vec = kasa_protocol::get_all_realtime(&mut stream).ok()?;
-        Some(Realtime {
-            current_ma: stats_vec.iter().fold(0u32, |sum, rt| sum + rt.current_ma),
-            err_code: 0,
-            power_mw: stats_vec.iter().fold(0u32, |sum, rt| sum + rt.power_mw),
-            slot_id: 0,
-            total_wh: stats_vec.iter().fold(0u32, |sum, rt| sum + rt.total_wh),
-            voltage_mv: (stats_vec.iter().fold(0u32, |sum, rt| sum + rt.voltage_mv))
-                / stats_vec.len() as u32,
-        })
+    //one worker thread per module, it quits once the module drops its sender
+    fn send(&mut self, command: Command) {
+        if self.worker.is_none() {
+            let hub = match KasaHub::shared() {
+                Some(hub) => hub,
+                None => return,
+            };
+            let (tx, rx) = mpsc::channel::<Command>();
+            let (done_tx, done_rx) = mpsc::channel();
+            let spawned = thread::Builder::new().stack_size(8000).spawn(move || {
+                for command in rx {
+                    let result = match command {
+                        Command::Outlet(outlet) => hub.set_outlet(DEVICE, outlet, None).map(|_| ()),
+                        Command::Light(change) => hub.set_light(DEVICE, &change).map(|_| ()),
+                    };
+                    if let Err(e) = result {
+                        log::warn!("kasa command failed: {:?}", e);
+                    }
+                    let _ = done_tx.send(());
+                }
+            });
+            match spawned {
+                Ok(_) => self.worker = Some((tx, done_rx)),
+                Err(e) => {
+                    log::warn!("kasa worker failed to start: {:?}", e);
+                    return;
+                }
+            }
+        }
+        if let Some((tx, _)) = &self.worker {
+            if tx.send(command).is_ok() {
+                self.pending += 1;
+            }
+        }
+    }
+
+    //pick up what the worker has finished, the hub's state is only taken
+    //once the last queued command is through
+    fn check_worker(&mut self) {
+        let (mut finished, mut gone) = (false, false);
+        if let Some((_, done)) = &self.worker {
+            loop {
+                match done.try_recv() {
+                    Ok(()) => {
+                        self.pending = self.pending.saturating_sub(1);
+                        finished = true;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        gone = true;
+                        break;
+                    }
+                }
+            }
+        }
+        if gone {
+            log::warn!("kasa worker exited");
+            self.worker = None;
+            self.pending = 0;
+        }
+        if finished && self.pending == 0 {
+            self.refresh();
+        }
+    }
+
+    fn light_kind(&self) -> Option<DeviceKind> {
+        self.kind.filter(|kind| kind.is_light())
+    }
+
+    fn light_key(&mut self, kind: DeviceKind, key: u32) {
+        let pages = LightPage::pages(kind);
+        let page = pages.get(self.light_page).copied();
+        let change = match (key, page) {
+            (0, _) if !pages.is_empty() => {
+                self.light_page = (self.light_page + pages.len() - 1) % pages.len();
+                self.update = true;
+                return;
+            }
+            (2, _) if !pages.is_empty() => {
+                self.light_page = (self.light_page + 1) % pages.len();
+                self.update = true;
+                return;
+            }
+            (TOGGLE_KEY, _) => LightChange::power(!self.light.on),
+            (DOWN_KEY, Some(page)) => page.step(&self.light, false),
+            (UP_KEY, Some(page)) => page.step(&self.light, true),
+            _ => return,
+        };
+        self.preview(&change);
+        self.send(Command::Light(change));
+    }
+
+    //show a change right away and let the next key press build on it, the
+    //hub has the device's own word on it once the worker is through
+    fn preview(&mut self, change: &LightChange) {
+        let state = &mut self.light;
+        if let Some(on) = change.on {
+            state.on = on;
+        }
+        if let Some(brightness) = change.brightness {
+            state.brightness = brightness;
+        }
+        if let Some(color_temp) = change.color_temp {
+            state.color_temp = color_temp;
+        }
+        if let Some(hue) = change.hue {
+            state.hue = hue;
+        }
+        if let Some(saturation) = change.saturation {
+            state.saturation = saturation;
+        }
+        self.update = true;
+    }
+
+    fn light_display(&self, kind: DeviceKind) -> DisplayMessage {
+        let pages = LightPage::pages(kind);
+        let mut lines = vec![DisplayLine {
+            line: format!(
+                "{:<15} {:>3}",
+                self.alias.chars().take(15).collect::<String>(),
+                if self.light.on { "ON" } else { "OFF" }
+            ),
+            size: TextSize::Normal,
+            x_offset: 0,
+            y_offset: 18,
+        }];
+        if let Some(page) = pages.get(self.light_page) {
+            lines.push(DisplayLine {
+                line: format!("{:<6} {:>8}", page.label(), page.value(&self.light)),
+                size: TextSize::Normal,
+                x_offset: 0,
+                y_offset: 32,
+            });
+        }
+        lines.push(DisplayLine {
+            line: if pages.is_empty() {
+                "4:on/off".to_string()
+            } else {
+                "1/3:page 4:on/off 5:- 6:+".to_string()
+            },
+            size: TextSize::Small,
+            x_offset: 0,
+            y_offset: HINT_LINE.top_left.y,
+        });
+        DisplayMessage {
+            module_name: self.get_display_name(),
+            content: MessageType::Lines(lines),
+            status_line: false,
+            clear_rect: Rectangle::new(Point::new(0, 15), Size::new(128, 44)),
+        }
+    }
+
+    //zeros for an outlet without a reading yet
+    fn stat(&self, idx: usize) -> Realtime {
+        match self
+            .outlets
+            .get(idx)
+            .and_then(|outlet| outlet.realtime.clone())
+        {
+            Some(stat) => stat,
+            None => Realtime {
+                current_ma: 0,
+                err_code: 0,
+                power_mw: 0,
+                slot_id: 0,
+                total_wh: 0,
+                voltage_mv: 0,
+            },
+        }
     }
 
     fn display_line_builder(&mut self) -> DisplayMessage {
@@ -71,7 +349,7 @@ impl KasaControl {
                 DisplayLine {
                     //line: "line 1".to_string(),
                     line: {
-                        let cur_stats = &self.stats[self.monitor_idx];
+                        let cur_stats = self.stat(self.monitor_idx);
                         format!(
                             "I:{:>4}mA   P: {:>4}mW\r\n\r\nPt: {:>3}Wh",
                             cur_stats.current_ma, cur_stats.power_mw, cur_stats.total_wh,
@@ -96,7 +374,7 @@ impl KasaControl {
                     },
                     size: TextSize::Small,
                     x_offset: 28,
-                    y_offset: 50,
+                    y_offset: HINT_LINE.top_left.y,
                 },
             ]),
             status_line: false,
@@ -104,12 +382,9 @@ impl KasaControl {
         }
     }
 
-    fn toggle_by_idx(btn_idx: u32) {
-        let app_config = CONFIG;
-        if btn_idx > 2 && btn_idx < 9 {
-            if let Ok(mut stream) = TcpStream::connect(format!("{:}:9999", app_config.target_ip)) {
-                let _res = kasa_protocol::toggle_relay_by_idx(&mut stream, (btn_idx - 3) as usize);
-            }
+    fn toggle_by_idx(&mut self, btn_idx: u32) {
+        if btn_idx > 2 && btn_idx < 9 && ((btn_idx - 3) as usize) < self.outlets.len() {
+            self.send(Command::Outlet((btn_idx - 3) as usize));
         }
     }
 
@@ -123,70 +398,67 @@ impl KasaControl {
 }
 
 impl RemoteModule for KasaControl {
-    fn set_channel(
-        &mut self,
-        receiver: mpsc::Receiver<RemoteMessage>,
-        sender: mpsc::Sender<DisplayMessage>,
-    ) {
-        log::info!("setting channel");
-        self.receiver = Some(receiver);
-        self.sender = Some(sender);
+    fn channels(&mut self) -> &mut ModuleChannels {
+        &mut self.channels
     }
 
-    fn release_channel(&mut self) -> Option<mpsc::Receiver<RemoteMessage>> {
-        let rec = replace(&mut self.receiver, None);
-        let _send = replace(&mut self.sender, None);
-        return rec;
+    fn get_display_name(&self) -> String {
+        return INFO.name.to_string();
     }
 
-    fn get_display_name(&self) -> String {
-        return "Kasa".to_string();
+    fn regions(&self) -> Vec<Rectangle> {
+        vec![MODULE_AREA, HINT_LINE]
     }
 
-    fn run(&mut self) {
-        let mut poll_counter: usize = 0;
+    fn on_enter(&mut self) {
         //redraw the display at load
         self.update = true;
+        self.poll_counter = 0;
+        self.refresh();
+    }
 
-        loop {
-            std::thread::sleep(std::time::Duration::from_millis(50));
-            poll_counter += 1;
-            if poll_counter == 100 {
-                //every 5 seconds with 100mili loop delay unless toggle takes time
-                poll_counter = 0;
-                if let Some(stat) = KasaControl::get_target_stat(self.monitor_idx as u8) {
-                    self.stats[self.monitor_idx] = stat;
-                    self.update = true;
-                }
+    fn tick(&mut self, events: &[RemoteMessage], ctx: &mut ModuleContext) {
+        if let Some(kind) = self.light_kind() {
+            for msg in events {
+                self.light_key(kind, msg.status);
             }
-            if let Some(rx) = &self.receiver {
-                match rx.try_recv() {
-                    Ok(msg) => {
-                        if msg.status == 10 {
-                            self.update = true;
-                            log::info!("kc exiting");
-                            return;
-                        } else if msg.status == 0 && self.monitor_idx > 0 {
-                            self.update_idx(BoolDir::Prev);
-                            log::info!("{:}", self.monitor_idx);
-                        } else if msg.status == 2 && self.monitor_idx < 7 {
-                            self.update_idx(BoolDir::Next);
-                            log::info!("{:}", self.monitor_idx);
-                        } else {
-                            KasaControl::toggle_by_idx(msg.status);
-                        }
-                    }
-                    _ => (),
-                }
-                if self.update {
-                    let msg = self.display_line_builder();
-                    if let Some(tx) = &self.sender {
-                        //log::info!("is this sending");
-                        let _ = tx.send(msg);
-                    }
-                    self.update = false;
-                }
+            self.check_worker();
+            self.poll_counter += 1;
+            if self.poll_counter >= 100 && self.pending == 0 {
+                self.poll_counter = 0;
+                self.refresh();
+            }
+            //the device may have turned out to be something else, the strip
+            //path draws it next tick
+            if let (true, Some(kind)) = (self.update, self.light_kind()) {
+                ctx.send(self.light_display(kind));
+                self.update = false;
             }
+            return;
+        }
+
+        for msg in events {
+            if msg.status == 0 && self.monitor_idx > 0 {
+                self.update_idx(BoolDir::Prev);
+                log::info!("{:}", self.monitor_idx);
+            } else if msg.status == 2 && self.monitor_idx < 7 {
+                self.update_idx(BoolDir::Next);
+                log::info!("{:}", self.monitor_idx);
+            } else {
+                self.toggle_by_idx(msg.status);
+            }
+        }
+
+        self.check_worker();
+        self.poll_counter += 1;
+        if self.poll_counter >= 100 && self.pending == 0 {
+            //every 5 seconds with 50mili tick, the hub itself polls every 10
+            self.poll_counter = 0;
+            self.refresh();
+        }
+        if self.update {
+            ctx.send(self.display_line_builder());
+            self.update = false;
         }
     }
 }
@@ -0,0 +1,198 @@
+use crate::module_registry::{Category, Icon, ModuleInfo, ModuleRegistry};
+use crate::module_runner::{
+    ModuleChannels, ModuleContext, RemoteMessage, RemoteModule, RunnerRequest, MODULE_AREA,
+};
+use crate::peripheral_util::bitmap::{Bitmap, BlitMode};
+use crate::peripheral_util::display::{DisplayLine, DisplayMessage, DrawOp, MessageType, TextSize};
+use embedded_graphics::{
+    geometry::{Point, Size},
+    primitives::Rectangle,
+};
+
+pub const INFO: ModuleInfo = ModuleInfo {
+    name: "Home",
+    icon: [
+        0b00011000, 0b00111100, 0b01111110, 0b11111111, 0b01100110, 0b01100110, 0b01111110,
+        0b00000000,
+    ],
+    category: Category::Tools,
+};
+
+//the grid mirrors the bottom two rows of the keypad, buttons 4-9
+const FIRST_KEY: u32 = 3;
+const COLUMNS: usize = 3;
+const ROWS: usize = 2;
+const PER_PAGE: usize = COLUMNS * ROWS;
+const CELL: Size = Size::new(42, 22);
+//where each cell's icon and name go, relative to the cell
+const ICON_OFFSET: Point = Point::new(17, 1);
+const LABEL_OFFSET: Point = Point::new(0, 10);
+//page number in the top right corner, between the last icon of the first row
+//and the edge of the screen and above that cell's name
+const PAGE_LABEL: Rectangle = Rectangle::new(
+    Point::new(
+        2 * CELL.width as i32 + ICON_OFFSET.x + 9,
+        MODULE_AREA.top_left.y,
+    ),
+    Size::new(18, 10),
+);
+
+struct LauncherEntry {
+    registry_idx: usize,
+    info: ModuleInfo,
+}
+
+/// Home screen listing the enabled modules, opened with the key under each cell
+pub struct Launcher {
+    channels: ModuleChannels,
+    entries: Vec<LauncherEntry>,
+    page: usize,
+    update: bool,
+}
+
+impl Launcher {
+    pub fn new(registry: &ModuleRegistry) -> Self {
+        let entries = registry
+            .entries()
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.enabled)
+            .map(|(registry_idx, e)| LauncherEntry {
+                registry_idx,
+                info: e.info,
+            })
+            .collect();
+        Self {
+            channels: ModuleChannels::default(),
+            entries,
+            page: 0,
+            update: true,
+        }
+    }
+
+    fn pages(&self) -> usize {
+        (self.entries.len() + PER_PAGE - 1) / PER_PAGE
+    }
+
+    fn cell_origin(slot: usize) -> Point {
+        let col = (slot % COLUMNS) as i32;
+        let row = (slot / COLUMNS) as i32;
+        MODULE_AREA.top_left + Point::new(col * CELL.width as i32, row * CELL.height as i32)
+    }
+
+    fn icon_op(icon: &Icon, at: Point) -> DrawOp {
+        DrawOp::Blit {
+            //icons are already packed rows, one byte each
+            bitmap: Bitmap::from_bytes(Size::new(8, 8), icon.to_vec()).unwrap(),
+            at,
+            mode: BlitMode::Copy,
+        }
+    }
+
+    fn display_grid(&self) -> Vec<DisplayMessage> {
+        let visible = self
+            .entries
+            .iter()
+            .skip(self.page * PER_PAGE)
+            .take(PER_PAGE);
+        let mut icons = vec![];
+        let mut labels = vec![];
+        for (slot, entry) in visible.enumerate() {
+            let origin = Launcher::cell_origin(slot);
+            icons.push(Launcher::icon_op(&entry.info.icon, origin + ICON_OFFSET));
+            labels.push(DisplayLine {
+                line: entry.info.name.chars().take(7).collect(),
+                size: TextSize::Normal,
+                x_offset: origin.x + LABEL_OFFSET.x,
+                y_offset: origin.y + LABEL_OFFSET.y,
+            });
+        }
+        if self.pages() > 1 {
+            labels.push(DisplayLine {
+                line: format!("{:}/{:}", self.page + 1, self.pages()),
+                size: TextSize::Normal,
+                x_offset: PAGE_LABEL.top_left.x,
+                y_offset: PAGE_LABEL.top_left.y,
+            });
+        }
+        vec![
+            DisplayMessage {
+                module_name: INFO.name.to_string(),
+                content: MessageType::Draw(icons),
+                status_line: false,
+                clear_rect: MODULE_AREA,
+            },
+            //labels go on top of the icons, don't clear them again
+            DisplayMessage {
+                module_name: INFO.name.to_string(),
+                content: MessageType::Lines(labels),
+                status_line: false,
+                clear_rect: Rectangle::zero(),
+            },
+        ]
+    }
+}
+
+impl RemoteModule for Launcher {
+    fn channels(&mut self) -> &mut ModuleChannels {
+        &mut self.channels
+    }
+
+    fn get_display_name(&self) -> String {
+        INFO.name.to_string()
+    }
+
+    fn on_enter(&mut self) {
+        self.update = true;
+    }
+
+    fn tick(&mut self, events: &[RemoteMessage], ctx: &mut ModuleContext) {
+        for msg in events {
+            match msg.status {
+                0 if self.page > 0 => self.page -= 1,
+                2 if self.page + 1 < self.pages() => self.page += 1,
+                key if key >= FIRST_KEY => {
+                    let idx = self.page * PER_PAGE + (key - FIRST_KEY) as usize;
+                    if let Some(entry) = self.entries.get(idx) {
+                        ctx.request(RunnerRequest::Open(entry.registry_idx));
+                    }
+                }
+                _ => continue,
+            }
+            self.update = true;
+        }
+        if self.update {
+            for msg in self.display_grid() {
+                ctx.send(msg);
+            }
+            self.update = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABEL: Size = Size::new(42, 10);
+    const ICON: Size = Size::new(8, 8);
+
+    #[test]
+    fn page_label_is_clear_of_every_cell() {
+        assert!(MODULE_AREA.contains(PAGE_LABEL.top_left));
+        assert!(MODULE_AREA.contains(PAGE_LABEL.bottom_right().unwrap()));
+        for slot in 0..PER_PAGE {
+            let origin = Launcher::cell_origin(slot);
+            for used in [
+                Rectangle::new(origin + ICON_OFFSET, ICON),
+                Rectangle::new(origin + LABEL_OFFSET, LABEL),
+            ] {
+                assert!(
+                    PAGE_LABEL.intersection(&used).is_zero_sized(),
+                    "page label overlaps slot {:}",
+                    slot
+                );
+            }
+        }
+    }
+}
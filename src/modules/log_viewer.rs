@@ -0,0 +1,131 @@
+use crate::event_log;
+use crate::module_registry::{Category, ModuleInfo};
+use crate::module_runner::{
+    ModuleChannels, ModuleContext, RemoteMessage, RemoteModule, MODULE_AREA,
+};
+use crate::peripheral_util::display::{DisplayLine, DisplayMessage, MessageType, TextSize};
+
+use std::time::Duration;
+
+pub const INFO: ModuleInfo = ModuleInfo {
+    name: "Log",
+    icon: [
+        0b01111110, 0b01000010, 0b01011010, 0b01000010, 0b01011010, 0b01000010, 0b01011010,
+        0b01111110,
+    ],
+    category: Category::Tools,
+};
+
+const NEWEST_KEY: u32 = 3;
+//what fits in the module area in the normal font
+const COLUMNS: usize = 21;
+const ROWS: usize = 4;
+
+/// The persistent event log, one entry at a time. Keys 1/3 step to older and
+/// newer entries, key 4 jumps back to the newest.
+pub struct LogViewer {
+    channels: ModuleChannels,
+    entries: Vec<String>,
+    //counted back from the newest entry
+    position: usize,
+    update: bool,
+}
+
+impl LogViewer {
+    pub fn new() -> Self {
+        Self {
+            channels: ModuleChannels::default(),
+            entries: vec![],
+            position: 0,
+            update: true,
+        }
+    }
+
+    fn step(&mut self, older: bool) {
+        if older {
+            self.position = (self.position + 1).min(self.entries.len().saturating_sub(1));
+        } else {
+            self.position = self.position.saturating_sub(1);
+        }
+        self.update = true;
+    }
+
+    //header with where in the log this is, then the message wrapped
+    fn lines(&self) -> Vec<String> {
+        let count = self.entries.len();
+        let entry = match count.checked_sub(self.position + 1) {
+            Some(idx) => &self.entries[idx],
+            None => return vec!["log is empty".to_string()],
+        };
+        let (boot, stamp, message) = event_log::parse(entry);
+        let mut lines = vec![format!("{:}/{:} {:}", count - self.position, count, stamp)];
+        let message = format!("{:} {:}", boot, message);
+        let chars: Vec<char> = message.chars().collect();
+        lines.extend(
+            chars
+                .chunks(COLUMNS)
+                .take(ROWS - 1)
+                .map(|chunk| chunk.iter().collect()),
+        );
+        lines
+    }
+
+    fn display_entry(&self) -> DisplayMessage {
+        DisplayMessage {
+            module_name: INFO.name.to_string(),
+            content: MessageType::Lines(
+                self.lines()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, line)| DisplayLine {
+                        line: line.chars().take(COLUMNS).collect(),
+                        size: TextSize::Normal,
+                        x_offset: 0,
+                        y_offset: MODULE_AREA.top_left.y + idx as i32 * 11,
+                    })
+                    .collect(),
+            ),
+            status_line: false,
+            clear_rect: MODULE_AREA,
+        }
+    }
+}
+
+impl RemoteModule for LogViewer {
+    fn channels(&mut self) -> &mut ModuleChannels {
+        &mut self.channels
+    }
+
+    fn get_display_name(&self) -> String {
+        INFO.name.to_string()
+    }
+
+    fn on_enter(&mut self) {
+        self.entries = event_log::entries();
+        self.position = 0;
+        self.update = true;
+    }
+
+    fn tick_interval(&self) -> Duration {
+        Duration::from_millis(100)
+    }
+
+    fn tick(&mut self, events: &[RemoteMessage], ctx: &mut ModuleContext) {
+        for msg in events {
+            match msg.status {
+                0 => self.step(true),
+                2 => self.step(false),
+                NEWEST_KEY => {
+                    self.entries = event_log::entries();
+                    self.position = 0;
+                    self.update = true;
+                }
+                _ => (),
+            }
+        }
+        if self.update {
+            ctx.send(self.display_entry());
+            self.update = false;
+        }
+    }
+}
@@ -1,21 +1,30 @@
-use crate::module_runner::{RemoteMessage, RemoteModule};
-use crate::peripheral_util::display::{
-    DisplayBuffer, DisplayLine, DisplayMessage, MessageType, TextSize,
-};
+use crate::module_registry::{Category, ModuleInfo};
+use crate::module_runner::{ModuleChannels, ModuleContext, RemoteMessage, RemoteModule};
+use crate::peripheral_util::bitmap::{Bitmap, BlitMode};
+use crate::peripheral_util::display::{DisplayLine, DisplayMessage, DrawOp, MessageType, TextSize};
 use embedded_graphics::pixelcolor::BinaryColor;
 //use crate::CONFIG;
 use embedded_graphics::{
     geometry::{Point, Size},
     primitives::Rectangle,
 };
-use std::mem::replace;
-use std::sync::mpsc;
-
-use esp_idf_svc::hal::sys::rand;
+use std::time::Duration;
+
+pub const INFO: ModuleInfo = ModuleInfo {
+    name: "snake",
+    icon: [
+        0b00000000, 0b01111110, 0b01000000, 0b01111110, 0b00000010, 0b00000010, 0b01100110,
+        0b00000000,
+    ],
+    category: Category::Games,
+};
 
 //each segment of the snake will be n x n
 const SEGMENT_SIZE: u32 = 5;
 const STEP_SIZE: i32 = SEGMENT_SIZE as i32;
+//SEGMENT_SIZE square diamond
+const FOOD: [u8; SEGMENT_SIZE as usize] =
+    [0b00100000, 0b01110000, 0b11111000, 0b01110000, 0b00100000];
 const X_MAX: i32 = 128 - STEP_SIZE;
 const X_MIN: i32 = 0;
 const Y_MAX: i32 = 64 - STEP_SIZE;
@@ -43,13 +52,13 @@ pub struct Board {
 }
 
 pub struct Snake {
-    receiver: Option<mpsc::Receiver<RemoteMessage>>,
-    sender: Option<mpsc::Sender<DisplayMessage>>,
-    update: bool,
+    channels: ModuleChannels,
 
     player: Player,
     board: Board,
     paused: bool,
+    //set when suspend did the pausing, so resume leaves a paused game paused
+    suspend_paused: bool,
     score_rect: Rectangle,
 }
 
@@ -69,12 +78,11 @@ fn get_random_point() -> Point {
     //display area is 129x64
     //this could use some work properly defining where a new point can be
     //this works but only barely
-    let randint: i32;
+    let mut seed = [0u8; 4];
     let modulo = 118 * (64 - (STEP_SIZE * 2));
-    //yucky yucky but I don't need to bring in the PAC crate
-    unsafe {
-        randint = rand();
-    }
+    //the hardware RNG on the remote, the OS one on a PC
+    let _ = getrandom::getrandom(&mut seed);
+    let randint = (u32::from_le_bytes(seed) >> 1) as i32;
     let wrapped_coor = randint % modulo;
     //add 10 to y to account for status
     let y = (wrapped_coor / 118) + 10;
@@ -86,9 +94,7 @@ impl Snake {
     pub fn new() -> Self {
         let head_start = get_random_point();
         Self {
-            receiver: None,
-            sender: None,
-            update: true,
+            channels: ModuleChannels::default(),
             player: Player {
                 head: head_start,
                 size: Size::new(SEGMENT_SIZE, SEGMENT_SIZE),
@@ -101,7 +107,9 @@ impl Snake {
                 board_rect: Rectangle::new(Point::new(0, 10), Size::new(128, 54)),
             },
             paused: false,
-            score_rect: Rectangle::new(Point::new(10, 0), Size::new(90, 10)),
+            suspend_paused: false,
+            //between the status bar title and the battery readout
+            score_rect: Rectangle::new(Point::new(52, 0), Size::new(46, 10)),
         }
     }
 
@@ -225,29 +233,29 @@ impl Snake {
     }
 
     fn display_board(&mut self) -> DisplayMessage {
-        let size = self.player.size.clone();
-        let mut board_buffer: Vec<DisplayBuffer> = vec![];
-
-        // push the player segments in first, this gets squirrely with the iterator and appending
-        board_buffer.extend(self.player.segments.iter().map(|s| DisplayBuffer {
-            buf: [BinaryColor::On; 400].to_vec(),
-            offset: *s,
-            size,
-        }));
-
-        //push the food in if it exists
+        let size = self.player.size;
+        let mut ops: Vec<DrawOp> = self
+            .player
+            .segments
+            .iter()
+            .map(|s| DrawOp::Fill {
+                rect: Rectangle::new(*s, size),
+                color: BinaryColor::On,
+            })
+            .collect();
+
+        //food is a sprite rather than a block, so it stands out
         if let Some(food) = self.board.food {
-            let food_buffer = DisplayBuffer {
-                buf: [BinaryColor::On; 400].to_vec(),
-                offset: food,
-                size,
-            };
-            board_buffer.push(food_buffer);
+            ops.push(DrawOp::Blit {
+                bitmap: Bitmap::from_bytes(size, FOOD.to_vec()).unwrap(),
+                at: food,
+                mode: BlitMode::Mask,
+            });
         }
 
         DisplayMessage {
             module_name: self.get_display_name(),
-            content: MessageType::Buffer(board_buffer),
+            content: MessageType::Draw(ops),
             status_line: false,
             clear_rect: self.board.board_rect,
         }
@@ -259,91 +267,53 @@ impl Snake {
             content: MessageType::Lines(vec![DisplayLine {
                 line: { format!("Score: {:}", self.player.score) },
                 size: TextSize::Normal,
-                x_offset: 10,
+                x_offset: self.score_rect.top_left.x,
                 y_offset: 0,
             }]),
             status_line: true,
             clear_rect: self.score_rect,
         }
     }
-
-    fn clear_score(&mut self) {
-        if let Some(tx) = &self.sender {
-            //log::info!("is this sending");
-            let _ = tx.send(DisplayMessage {
-                module_name: self.get_display_name(),
-                content: MessageType::Lines(vec![DisplayLine {
-                    line: "".to_string(),
-                    size: TextSize::Normal,
-                    x_offset: 10,
-                    y_offset: 0,
-                }]),
-                status_line: true,
-                clear_rect: self.score_rect,
-            });
-        }
-    }
 }
 
 impl RemoteModule for Snake {
-    fn set_channel(
-        &mut self,
-        receiver: mpsc::Receiver<RemoteMessage>,
-        sender: mpsc::Sender<DisplayMessage>,
-    ) {
-        log::info!("setting channel");
-        self.receiver = Some(receiver);
-        self.sender = Some(sender);
+    fn channels(&mut self) -> &mut ModuleChannels {
+        &mut self.channels
     }
 
-    fn release_channel(&mut self) -> Option<mpsc::Receiver<RemoteMessage>> {
-        let rec = replace(&mut self.receiver, None);
-        let _send = replace(&mut self.sender, None);
-        return rec;
+    fn get_display_name(&self) -> String {
+        return INFO.name.to_string();
     }
 
-    fn get_display_name(&self) -> String {
-        return "snake".to_string();
+    fn regions(&self) -> Vec<Rectangle> {
+        vec![self.board.board_rect, self.score_rect]
     }
 
-    fn run(&mut self) {
-        let mut poll_counter: usize = 0;
-        loop {
-            std::thread::sleep(std::time::Duration::from_millis((1000 / 48) as u64));
-            poll_counter += 1;
-            if poll_counter == 8 {
-                //about every 170ms, timing is a little loose
-                poll_counter = 0;
-                self.update = true;
-                self.step();
-            }
+    //game state is kept while parked, just stop stepping
+    fn suspend(&mut self) {
+        log::info!("snake paused");
+        self.suspend_paused = !self.paused;
+        self.paused = true;
+    }
 
-            if let Some(rx) = &self.receiver {
-                match rx.try_recv() {
-                    Ok(msg) => {
-                        if msg.status == 10 {
-                            //self.update = true;
-                            log::info!("snake exiting");
-                            self.paused = true;
-                            self.clear_score();
-                            return;
-                        } else {
-                            self.handle_control_event(msg.status)
-                        }
-                    }
-                    _ => (),
-                }
+    fn resume(&mut self) {
+        if self.suspend_paused {
+            self.paused = false;
+            self.suspend_paused = false;
+        }
+    }
 
-                if self.update {
-                    let msgs = vec![self.display_board(), self.display_score()];
-                    if let Some(tx) = &self.sender {
-                        for msg in msgs {
-                            let _ = tx.send(msg);
-                        }
-                    }
-                    self.update = false;
-                }
-            }
+    //about every 170ms, timing is a little loose
+    fn tick_interval(&self) -> Duration {
+        Duration::from_millis(8 * 1000 / 48)
+    }
+
+    fn tick(&mut self, events: &[RemoteMessage], ctx: &mut ModuleContext) {
+        for event in events {
+            self.handle_control_event(event.status);
         }
+        self.step();
+        ctx.send(self.display_board());
+        ctx.send(self.display_score());
     }
 }
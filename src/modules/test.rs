@@ -1,84 +1,71 @@
-use crate::module_runner::{RemoteMessage, RemoteModule};
+use crate::module_registry::{Category, ModuleInfo};
+use crate::module_runner::{
+    ModuleChannels, ModuleContext, RemoteMessage, RemoteModule, MODULE_AREA,
+};
 use crate::peripheral_util::display::{DisplayLine, DisplayMessage, MessageType, TextSize};
 
-use embedded_graphics::{
-    geometry::{Point, Size},
-    primitives::Rectangle,
+use std::time::Duration;
+
+pub const INFO: ModuleInfo = ModuleInfo {
+    name: "Test",
+    icon: [
+        0b11111111, 0b10000001, 0b10000011, 0b10000110, 0b11001101, 0b10111001, 0b10010001,
+        0b11111111,
+    ],
+    category: Category::Tools,
 };
-use std::mem::replace;
-use std::sync::mpsc;
 
 pub struct TestModule {
     member: u32,
-    receiver: Option<mpsc::Receiver<RemoteMessage>>,
-    sender: Option<mpsc::Sender<DisplayMessage>>,
+    channels: ModuleChannels,
 }
 
 impl TestModule {
     pub fn new() -> Self {
         Self {
             member: 0,
-            receiver: None,
-            sender: None,
+            channels: ModuleChannels::default(),
         }
     }
 }
 
 impl RemoteModule for TestModule {
-    fn set_channel(
-        &mut self,
-        receiver: mpsc::Receiver<RemoteMessage>,
-        sender: mpsc::Sender<DisplayMessage>,
-    ) {
-        log::info!("setting channel");
-        self.receiver = Some(receiver);
-        self.sender = Some(sender);
-    }
-
-    fn release_channel(&mut self) -> Option<mpsc::Receiver<RemoteMessage>> {
-        let rec = replace(&mut self.receiver, None);
-        let _send = replace(&mut self.sender, None);
-        rec
+    fn channels(&mut self) -> &mut ModuleChannels {
+        &mut self.channels
     }
 
     fn get_display_name(&self) -> String {
-        "Test".to_string()
+        INFO.name.to_string()
     }
 
-    fn run(&mut self) {
+    fn on_enter(&mut self) {
         self.member = 0;
-        loop {
-            if let Some(rx) = &self.receiver {
-                if let Ok(msg) = rx.try_recv() {
-                    if msg.status == 10 {
-                        log::info!("returning via command");
-                        log::info!("member count up to: {:}", self.member);
-                        return;
-                    } else {
-                        log::info!("got button event: {:}", msg.status);
-                    }
-                }
-            } else {
-                log::info!("no channel receiver configured");
-                return;
-            }
-            self.member += 1;
-            std::thread::sleep(std::time::Duration::from_millis(20));
-            if let Some(tx) = &self.sender {
-                let _ = tx.send(DisplayMessage {
-                    module_name: self.get_display_name(),
-                    content: MessageType::Lines(vec![DisplayLine {
-                        line: format!("counter: {:}", self.member),
-                        size: TextSize::Normal,
-                        x_offset: 20,
-                        y_offset: 20,
-                    }]),
-                    status_line: false,
-                    clear_rect: Rectangle::new(Point::new(0, 15), Size::new(128, 44)),
-                });
-            }
+    }
 
-            std::thread::sleep(std::time::Duration::from_millis(100));
-        } //service loop
+    fn on_exit(&mut self) {
+        log::info!("member count up to: {:}", self.member);
+    }
+
+    fn tick_interval(&self) -> Duration {
+        Duration::from_millis(120)
+    }
+
+    fn tick(&mut self, events: &[RemoteMessage], ctx: &mut ModuleContext) {
+        for msg in events {
+            log::info!("got button event: {:}", msg.status);
+        }
+        self.member += 1;
+        let msg = DisplayMessage {
+            module_name: self.get_display_name(),
+            content: MessageType::Lines(vec![DisplayLine {
+                line: format!("counter: {:}", self.member),
+                size: TextSize::Normal,
+                x_offset: 20,
+                y_offset: 20,
+            }]),
+            status_line: false,
+            clear_rect: MODULE_AREA,
+        };
+        ctx.send(msg);
     }
 }
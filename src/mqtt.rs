@@ -0,0 +1,457 @@
+//MQTT bridge for the Kasa hub. Everything topic and payload related lives
+//here behind `MqttSink`, the esp client only moves bytes, so the bridge can
+//be pointed at a stand-in broker off target.
+
+#[cfg(target_os = "espidf")]
+pub mod esp_client;
+
+use anyhow::Result;
+use serde_json::json;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::kasa::hub::KasaHub;
+use crate::peripheral_util::battery_monitor::battery_state;
+use crate::service_manager;
+
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Where the bridge sends to, the esp client on target
+pub trait MqttSink {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<()>;
+    fn subscribe(&mut self, topic: &str) -> Result<()>;
+}
+
+/// What the client hands the bridge from its callback
+pub enum BridgeEvent {
+    /// (Re)connected to the broker, subscriptions and discovery need redoing
+    Connected,
+    Message {
+        topic: String,
+        data: Vec<u8>,
+    },
+}
+
+/// Topic the client's last will goes to, so it can be set before connecting
+pub fn status_topic(prefix: &str) -> String {
+    format!("{:}/status", prefix)
+}
+
+pub struct MqttBridge<S: MqttSink> {
+    sink: S,
+    hub: KasaHub,
+    /// Topic prefix, also used as the Home Assistant node id
+    prefix: String,
+}
+
+impl<S: MqttSink> MqttBridge<S> {
+    pub fn new(sink: S, hub: KasaHub, prefix: &str) -> Self {
+        Self {
+            sink,
+            hub,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn outlet_topic(&self, dev: usize, outlet: usize, leaf: &str) -> String {
+        format!("{:}/{:}/{:}/{:}", self.prefix, dev, outlet, leaf)
+    }
+
+    pub fn on_connected(&mut self) -> Result<()> {
+        self.sink
+            .publish(&status_topic(&self.prefix), ONLINE.as_bytes(), true)?;
+        self.sink.subscribe(&format!("{:}/+/+/set", self.prefix))?;
+        self.publish_discovery()?;
+        self.publish_state()
+    }
+
+    //device, outlet and `None` for a toggle, from a set topic and its payload
+    fn parse_set(&self, topic: &str, data: &[u8]) -> Option<(usize, usize, Option<bool>)> {
+        let rest = topic.strip_prefix(&format!("{:}/", self.prefix))?;
+        let parts: Vec<&str> = rest.split('/').collect();
+        let (dev, outlet) = match parts.as_slice() {
+            [dev, outlet, "set"] => (dev.parse().ok()?, outlet.parse().ok()?),
+            _ => return None,
+        };
+        let on = match std::str::from_utf8(data).map(|s| s.trim().to_uppercase()) {
+            Ok(cmd) if cmd == "ON" => Some(true),
+            Ok(cmd) if cmd == "OFF" => Some(false),
+            Ok(cmd) if cmd == "TOGGLE" => None,
+            _ => {
+                log::warn!("mqtt: bad command on {:}", topic);
+                return None;
+            }
+        };
+        Some((dev, outlet, on))
+    }
+
+    /// Handle `<prefix>/<dev>/<outlet>/set` with ON, OFF or TOGGLE
+    pub fn on_message(&mut self, topic: &str, data: &[u8]) {
+        let (dev, outlet, on) = match self.parse_set(topic, data) {
+            Some(command) => command,
+            None => return,
+        };
+        match self.hub.set_outlet(dev, outlet, on) {
+            Ok(on) => {
+                let state = self.outlet_topic(dev, outlet, "state");
+                if let Err(e) = self.sink.publish(&state, on_off(on), true) {
+                    log::warn!("mqtt: {:?}", e);
+                }
+            }
+            Err(e) => log::warn!("mqtt: set {:}.{:} failed: {:?}", dev, outlet, e),
+        }
+    }
+
+    /// Availability, relay state and readings of every outlet, plus the battery
+    pub fn publish_state(&mut self) -> Result<()> {
+        for (dev, device) in self.hub.devices().iter().enumerate() {
+            let available = if device.reachable { ONLINE } else { OFFLINE };
+            let topic = format!("{:}/{:}/available", self.prefix, dev);
+            self.sink.publish(&topic, available.as_bytes(), true)?;
+            if !device.reachable {
+                continue;
+            }
+            for (idx, outlet) in device.outlets.iter().enumerate() {
+                let topic = self.outlet_topic(dev, idx, "state");
+                self.sink.publish(&topic, on_off(outlet.on), true)?;
+                if let Some(rt) = &outlet.realtime {
+                    let payload = json!({
+                        "current_ma": rt.current_ma,
+                        "voltage_mv": rt.voltage_mv,
+                        "power_mw": rt.power_mw,
+                        "total_wh": rt.total_wh,
+                    });
+                    let topic = self.outlet_topic(dev, idx, "realtime");
+                    self.sink
+                        .publish(&topic, payload.to_string().as_bytes(), false)?;
+                }
+            }
+        }
+        let battery = battery_state();
+        if battery.valid {
+            let payload = json!({
+                "soc": battery.soc,
+                "voltage": battery.voltage,
+                "charging": battery.charging,
+                "low": battery.low,
+            });
+            let topic = format!("{:}/battery", self.prefix);
+            self.sink
+                .publish(&topic, payload.to_string().as_bytes(), false)?;
+        }
+        Ok(())
+    }
+
+    /// Home Assistant discovery, a switch per outlet plus power sensors
+    pub fn publish_discovery(&mut self) -> Result<()> {
+        let node = self.prefix.replace(['/', '-'], "_");
+        let status = status_topic(&self.prefix);
+        let remote = json!({ "identifiers": [node], "name": "Kasa remote" });
+        for (dev, device) in self.hub.devices().iter().enumerate() {
+            let available = format!("{:}/{:}/available", self.prefix, dev);
+            for (idx, outlet) in device.outlets.iter().enumerate() {
+                let id = format!("{:}_{:}_{:}", node, dev, idx);
+                let switch = json!({
+                    "name": outlet.alias,
+                    "unique_id": id,
+                    "command_topic": self.outlet_topic(dev, idx, "set"),
+                    "state_topic": self.outlet_topic(dev, idx, "state"),
+                    "availability": [{ "topic": status }, { "topic": available }],
+                    "availability_mode": "all",
+                    "device": remote,
+                });
+                let topic = format!("{:}/switch/{:}/config", DISCOVERY_PREFIX, id);
+                self.sink
+                    .publish(&topic, switch.to_string().as_bytes(), true)?;
+                if outlet.realtime.is_none() {
+                    continue;
+                }
+                let power = json!({
+                    "name": format!("{:} power", outlet.alias),
+                    "unique_id": format!("{:}_power", id),
+                    "state_topic": self.outlet_topic(dev, idx, "realtime"),
+                    "value_template": "{{ value_json.power_mw / 1000 }}",
+                    "unit_of_measurement": "W",
+                    "device_class": "power",
+                    "state_class": "measurement",
+                    "availability": [{ "topic": status }, { "topic": available }],
+                    "availability_mode": "all",
+                    "device": remote,
+                });
+                let topic = format!("{:}/sensor/{:}_power/config", DISCOVERY_PREFIX, id);
+                self.sink
+                    .publish(&topic, power.to_string().as_bytes(), true)?;
+            }
+        }
+        let battery = json!({
+            "name": "Remote battery",
+            "unique_id": format!("{:}_battery", node),
+            "state_topic": format!("{:}/battery", self.prefix),
+            "value_template": "{{ value_json.soc | round(0) }}",
+            "unit_of_measurement": "%",
+            "device_class": "battery",
+            "availability_topic": status,
+            "device": remote,
+        });
+        let topic = format!("{:}/sensor/{:}_battery/config", DISCOVERY_PREFIX, node);
+        self.sink
+            .publish(&topic, battery.to_string().as_bytes(), true)
+    }
+}
+
+fn on_off(on: bool) -> &'static [u8] {
+    if on {
+        b"ON"
+    } else {
+        b"OFF"
+    }
+}
+
+/// Publish state every `interval` and act on whatever the client forwards
+pub fn bridge_service<S: MqttSink>(
+    mut bridge: MqttBridge<S>,
+    events: mpsc::Receiver<BridgeEvent>,
+    interval: Duration,
+) {
+    let mut next_publish = Instant::now() + interval;
+    loop {
+        service_manager::heartbeat();
+        let wait = next_publish.saturating_duration_since(Instant::now());
+        match events.recv_timeout(wait) {
+            Ok(BridgeEvent::Connected) => {
+                if let Err(e) = bridge.on_connected() {
+                    log::warn!("mqtt: {:?}", e);
+                }
+            }
+            Ok(BridgeEvent::Message { topic, data }) => bridge.on_message(&topic, &data),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if let Err(e) = bridge.publish_state() {
+                    log::warn!("mqtt: {:?}", e);
+                }
+                next_publish = Instant::now() + interval;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                log::error!("mqtt client gone, bridge stopping");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kasa::hub::{Device, Outlet};
+    use crate::kasa::light::DeviceKind;
+    use rust_kasa::models::Realtime;
+    use serde_json::Value;
+
+    //everything the bridge sent, payloads as text
+    #[derive(Default)]
+    struct Recorder {
+        published: Vec<(String, String, bool)>,
+        subscribed: Vec<String>,
+    }
+
+    impl MqttSink for Recorder {
+        fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<()> {
+            let payload = String::from_utf8(payload.to_vec()).unwrap();
+            self.published.push((topic.to_string(), payload, retain));
+            Ok(())
+        }
+
+        fn subscribe(&mut self, topic: &str) -> Result<()> {
+            self.subscribed.push(topic.to_string());
+            Ok(())
+        }
+    }
+
+    fn outlet(alias: &str, on: bool, power_mw: Option<u32>) -> Outlet {
+        Outlet {
+            id: None,
+            alias: alias.to_string(),
+            on,
+            realtime: power_mw.map(|power_mw| Realtime {
+                current_ma: 100,
+                err_code: 0,
+                power_mw,
+                slot_id: 0,
+                total_wh: 5,
+                voltage_mv: 230_000,
+            }),
+        }
+    }
+
+    //a strip with a metered and a plain outlet, and a plug that is offline
+    fn bridge() -> MqttBridge<Recorder> {
+        let device = |alias: &str, kind, outlets, reachable| Device {
+            addr: format!("{:}.local", alias),
+            alias: alias.to_string(),
+            model: String::new(),
+            kind,
+            light: None,
+            outlets,
+            reachable,
+        };
+        let hub = KasaHub::from_devices(vec![
+            device(
+                "strip",
+                DeviceKind::Strip,
+                vec![outlet("lamp", true, Some(1500)), outlet("fan", false, None)],
+                true,
+            ),
+            device(
+                "plug",
+                DeviceKind::Plug,
+                vec![outlet("plug", false, None)],
+                false,
+            ),
+        ]);
+        MqttBridge::new(Recorder::default(), hub, "kasa-remote")
+    }
+
+    fn topics(bridge: &MqttBridge<Recorder>) -> Vec<&str> {
+        bridge
+            .sink
+            .published
+            .iter()
+            .map(|(t, _, _)| t.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn parses_set_commands() {
+        let bridge = bridge();
+        let parse = |topic: &str, data: &str| bridge.parse_set(topic, data.as_bytes());
+        assert_eq!(parse("kasa-remote/0/1/set", "ON"), Some((0, 1, Some(true))));
+        assert_eq!(
+            parse("kasa-remote/0/1/set", "off\n"),
+            Some((0, 1, Some(false)))
+        );
+        assert_eq!(parse("kasa-remote/2/0/set", "Toggle"), Some((2, 0, None)));
+
+        for (topic, data) in [
+            ("kasa-remote/0/1/set", "DIM"),
+            ("kasa-remote/0/1/set", ""),
+            ("kasa-remote/0/1/state", "ON"),
+            ("kasa-remote/0/set", "ON"),
+            ("kasa-remote/0/1/set/x", "ON"),
+            ("kasa-remote/a/1/set", "ON"),
+            ("kasa-remote/0/-1/set", "ON"),
+            ("other/0/1/set", "ON"),
+            ("kasa-remote0/1/set", "ON"),
+        ] {
+            assert_eq!(parse(topic, data), None, "{:} {:}", topic, data);
+        }
+    }
+
+    #[test]
+    fn ignores_bad_messages() {
+        let mut bridge = bridge();
+        bridge.on_message("kasa-remote/0/0/set", b"SOMETIMES");
+        bridge.on_message("kasa-remote/x/0/set", b"ON");
+        bridge.on_message("elsewhere/0/0/set", b"ON");
+        //well formed, but there is no device 5 to switch
+        bridge.on_message("kasa-remote/5/0/set", b"ON");
+        assert!(bridge.sink.published.is_empty());
+    }
+
+    #[test]
+    fn announces_itself_on_connect() {
+        let mut bridge = bridge();
+        bridge.on_connected().unwrap();
+        assert_eq!(bridge.sink.subscribed, ["kasa-remote/+/+/set"]);
+        let first = &bridge.sink.published[0];
+        assert_eq!(
+            first,
+            &("kasa-remote/status".to_string(), "online".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn discovery_payloads() {
+        let mut bridge = bridge();
+        bridge.publish_discovery().unwrap();
+        assert_eq!(
+            topics(&bridge),
+            [
+                "homeassistant/switch/kasa_remote_0_0/config",
+                "homeassistant/sensor/kasa_remote_0_0_power/config",
+                "homeassistant/switch/kasa_remote_0_1/config",
+                "homeassistant/switch/kasa_remote_1_0/config",
+                "homeassistant/sensor/kasa_remote_battery/config",
+            ]
+        );
+        assert!(bridge.sink.published.iter().all(|(_, _, retain)| *retain));
+
+        let payload =
+            |idx: usize| -> Value { serde_json::from_str(&bridge.sink.published[idx].1).unwrap() };
+        let switch = payload(0);
+        assert_eq!(switch["name"], "lamp");
+        assert_eq!(switch["unique_id"], "kasa_remote_0_0");
+        assert_eq!(switch["command_topic"], "kasa-remote/0/0/set");
+        assert_eq!(switch["state_topic"], "kasa-remote/0/0/state");
+        assert_eq!(
+            switch["availability"],
+            serde_json::json!([
+                { "topic": "kasa-remote/status" },
+                { "topic": "kasa-remote/0/available" },
+            ])
+        );
+        assert_eq!(switch["availability_mode"], "all");
+        assert_eq!(switch["device"]["identifiers"][0], "kasa_remote");
+
+        let power = payload(1);
+        assert_eq!(power["name"], "lamp power");
+        assert_eq!(power["state_topic"], "kasa-remote/0/0/realtime");
+        assert_eq!(power["device_class"], "power");
+        assert_eq!(power["unit_of_measurement"], "W");
+
+        assert_eq!(
+            payload(3)["availability"][1]["topic"],
+            "kasa-remote/1/available"
+        );
+        let battery = payload(4);
+        assert_eq!(battery["state_topic"], "kasa-remote/battery");
+        assert_eq!(battery["availability_topic"], "kasa-remote/status");
+    }
+
+    #[test]
+    fn state_availability_and_retain() {
+        let mut bridge = bridge();
+        bridge.publish_state().unwrap();
+        let published: Vec<(&str, &str, bool)> = bridge
+            .sink
+            .published
+            .iter()
+            .map(|(t, p, r)| (t.as_str(), p.as_str(), *r))
+            .filter(|(t, _, _)| !t.ends_with("/realtime"))
+            .collect();
+        //the offline plug only gets its availability, no stale state
+        assert_eq!(
+            published,
+            [
+                ("kasa-remote/0/available", "online", true),
+                ("kasa-remote/0/0/state", "ON", true),
+                ("kasa-remote/0/1/state", "OFF", true),
+                ("kasa-remote/1/available", "offline", true),
+            ]
+        );
+
+        //readings go out unretained, and only for metered outlets
+        let realtime: Vec<_> = bridge
+            .sink
+            .published
+            .iter()
+            .filter(|(t, _, _)| t.ends_with("/realtime"))
+            .collect();
+        assert_eq!(realtime.len(), 1);
+        let (topic, payload, retain) = realtime[0];
+        assert_eq!(topic, "kasa-remote/0/0/realtime");
+        assert!(!retain);
+        let payload: Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["power_mw"], 1500);
+    }
+}
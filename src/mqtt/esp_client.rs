@@ -0,0 +1,67 @@
+use anyhow::Result;
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+use std::sync::mpsc;
+
+use super::{status_topic, BridgeEvent, MqttSink};
+
+pub struct EspMqttSink {
+    client: EspMqttClient<'static>,
+}
+
+impl MqttSink for EspMqttSink {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<()> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, retain, payload)?;
+        Ok(())
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<()> {
+        self.client.subscribe(topic, QoS::AtLeastOnce)?;
+        Ok(())
+    }
+}
+
+/// Connect to the broker at `url`. The client reconnects on its own and
+/// reports each connection and incoming message on the returned receiver.
+pub fn connect(
+    url: &str,
+    user: &str,
+    password: &str,
+    prefix: &str,
+) -> Result<(EspMqttSink, mpsc::Receiver<BridgeEvent>)> {
+    let (tx, rx) = mpsc::channel();
+    let will = status_topic(prefix);
+    let config = MqttClientConfiguration {
+        client_id: Some(prefix),
+        username: (!user.is_empty()).then_some(user),
+        password: (!password.is_empty()).then_some(password),
+        lwt: Some(LwtConfiguration {
+            topic: &will,
+            payload: b"offline",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
+        ..Default::default()
+    };
+    let client = EspMqttClient::new_cb(url, &config, move |event| match event.payload() {
+        EventPayload::Connected(_) => {
+            log::info!("mqtt connected");
+            let _ = tx.send(BridgeEvent::Connected);
+        }
+        EventPayload::Received {
+            topic: Some(topic),
+            data,
+            ..
+        } => {
+            let _ = tx.send(BridgeEvent::Message {
+                topic: topic.to_string(),
+                data: data.to_vec(),
+            });
+        }
+        EventPayload::Disconnected => log::warn!("mqtt disconnected"),
+        _ => (),
+    })?;
+    Ok((EspMqttSink { client }, rx))
+}
@@ -0,0 +1,215 @@
+//Firmware updates into the spare OTA slot, downloaded from `ota_url` or
+//streamed in over the HTTP API. Only images signed with the key whose public
+//half is `ota_public_key` are installed, and only for requests carrying
+//`ota_token`. This part keeps track of progress and checks the image, writing
+//it out and switching slots is in `esp_update`.
+
+#[cfg(target_os = "espidf")]
+pub mod esp_update;
+
+use anyhow::{anyhow, bail, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+use crate::CONFIG;
+
+/// Where the last or current update is at
+#[derive(Clone, Debug, PartialEq)]
+pub enum OtaStatus {
+    Idle,
+    /// Bytes written so far, out of `size` when the sender said
+    Running {
+        written: usize,
+        size: Option<usize>,
+    },
+    Failed(String),
+    /// Image checked out and is the boot slot now, restarting into it
+    Rebooting,
+}
+
+static STATUS: Mutex<OtaStatus> = Mutex::new(OtaStatus::Idle);
+
+pub fn status() -> OtaStatus {
+    STATUS.lock().unwrap().clone()
+}
+
+fn set_status(status: OtaStatus) {
+    *STATUS.lock().unwrap() = status;
+}
+
+/// Claim the updater, false while another update is running
+pub fn begin(size: Option<usize>) -> bool {
+    let mut status = STATUS.lock().unwrap();
+    match *status {
+        OtaStatus::Running { .. } | OtaStatus::Rebooting => false,
+        _ => {
+            *status = OtaStatus::Running { written: 0, size };
+            true
+        }
+    }
+}
+
+/// Parse `N` bytes of hex, anything after them is ignored
+pub fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim().get(..N * 2)?;
+    let mut bytes = [0u8; N];
+    for (idx, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Signature over an image's SHA-256, as hex
+pub fn parse_signature(hex: &str) -> Result<Signature> {
+    parse_hex::<64>(hex)
+        .map(|bytes| Signature::from_bytes(&bytes))
+        .ok_or_else(|| anyhow!("bad signature"))
+}
+
+/// The key images have to be signed with, from `ota_public_key`
+pub fn public_key() -> Result<VerifyingKey> {
+    let bytes = parse_hex::<32>(CONFIG.ota_public_key)
+        .ok_or_else(|| anyhow!("no ota_public_key configured"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+//compares every byte whatever the first mismatch, an empty token matches nothing
+fn token_matches(expected: &str, given: Option<&str>) -> bool {
+    let given = given.unwrap_or("");
+    !expected.is_empty()
+        && expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// True if `token` is `ota_token`, updates are refused while that is unset
+pub fn authorized(token: Option<&str>) -> bool {
+    token_matches(CONFIG.ota_token, token)
+}
+
+/// Running size and SHA-256 of an image as it comes in, once it's all there
+/// the hash has to carry `signature` by `key`
+pub struct ImageCheck {
+    size: Option<usize>,
+    key: VerifyingKey,
+    signature: Signature,
+    hasher: Sha256,
+    written: usize,
+}
+
+impl ImageCheck {
+    pub fn new(size: Option<usize>, key: VerifyingKey, signature: Signature) -> Self {
+        Self {
+            size,
+            key,
+            signature,
+            hasher: Sha256::new(),
+            written: 0,
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) -> Result<()> {
+        self.written += chunk.len();
+        if let Some(size) = self.size {
+            if self.written > size {
+                bail!("image longer than the {:} bytes announced", size);
+            }
+        }
+        self.hasher.update(chunk);
+        set_status(OtaStatus::Running {
+            written: self.written,
+            size: self.size,
+        });
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if let Some(size) = self.size {
+            if self.written != size {
+                bail!("image cut short at {:} of {:} bytes", self.written, size);
+            }
+        }
+        let digest: [u8; 32] = self.hasher.finalize().into();
+        if self.key.verify_strict(&digest, &self.signature).is_err() {
+            bail!("image signature doesn't match");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const IMAGE: &[u8] = b"\xe9 pretend firmware image";
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn sign(key: &SigningKey, image: &[u8]) -> Signature {
+        key.sign(&Sha256::digest(image))
+    }
+
+    fn check(size: Option<usize>, signature: Signature, chunks: &[&[u8]]) -> Result<()> {
+        let mut check = ImageCheck::new(size, signing_key(1).verifying_key(), signature);
+        for chunk in chunks {
+            check.update(chunk)?;
+        }
+        check.finish()
+    }
+
+    #[test]
+    fn accepts_a_signed_image() {
+        let signature = sign(&signing_key(1), IMAGE);
+        let (head, tail) = IMAGE.split_at(5);
+        assert!(check(Some(IMAGE.len()), signature, &[head, tail]).is_ok());
+        assert!(check(None, signature, &[IMAGE]).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_images_and_keys() {
+        let signature = sign(&signing_key(1), IMAGE);
+        let mut tampered = IMAGE.to_vec();
+        tampered[3] ^= 1;
+        assert!(check(None, signature, &[&tampered]).is_err());
+        let foreign = sign(&signing_key(2), IMAGE);
+        assert!(check(None, foreign, &[IMAGE]).is_err());
+    }
+
+    #[test]
+    fn rejects_the_wrong_size() {
+        let signature = sign(&signing_key(1), IMAGE);
+        assert!(check(Some(IMAGE.len() + 1), signature, &[IMAGE]).is_err());
+        assert!(check(Some(4), signature, &[IMAGE]).is_err());
+    }
+
+    #[test]
+    fn parses_signatures() {
+        let signature = sign(&signing_key(1), IMAGE);
+        let hex: String = signature
+            .to_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(parse_signature(&format!("{:}\n", hex)).unwrap(), signature);
+        assert!(parse_signature(&hex[..100]).is_err());
+        assert!(parse_signature(&"zz".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn token_has_to_match() {
+        assert!(token_matches("s3cret", Some("s3cret")));
+        assert!(!token_matches("s3cret", Some("s3cre")));
+        assert!(!token_matches("s3cret", Some("s3creT")));
+        assert!(!token_matches("s3cret", None));
+        //no token configured means no updates over the network at all
+        assert!(!token_matches("", Some("")));
+        assert!(!token_matches("", None));
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::Write;
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate, SlotState};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{begin, parse_signature, public_key, set_status, ImageCheck, OtaStatus};
+
+const CHUNK: usize = 4096;
+//long enough to be sure the new image can run the remote, the keys, display
+//and Wi-Fi all have to be up by then
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+//a new image that can't get on the network by now rolls back
+const GIVE_UP_AFTER: Duration = Duration::from_secs(300);
+
+fn copy(
+    read: &mut impl FnMut(&mut [u8]) -> Result<usize>,
+    check: &mut ImageCheck,
+    update: &mut EspOtaUpdate,
+) -> Result<()> {
+    let mut buf = vec![0u8; CHUNK];
+    loop {
+        let n = read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        check.update(&buf[..n])?;
+        update.write_all(&buf[..n])?;
+    }
+}
+
+/// Write an image into the spare slot and make it the boot slot, `read` is
+/// called until it returns 0. Only switches if the whole image checks out.
+pub fn write_image(
+    mut read: impl FnMut(&mut [u8]) -> Result<usize>,
+    mut check: ImageCheck,
+) -> Result<()> {
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let written = copy(&mut read, &mut check, &mut update).and_then(|_| check.finish());
+    match written {
+        Ok(()) => {
+            update.complete()?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = update.abort();
+            Err(e)
+        }
+    }
+}
+
+fn fail(e: anyhow::Error) -> anyhow::Error {
+    log::warn!("ota failed: {:?}", e);
+    set_status(OtaStatus::Failed(format!("{:}", e)));
+    e
+}
+
+fn connection() -> Result<EspHttpConnection> {
+    Ok(EspHttpConnection::new(&Configuration {
+        buffer_size: Some(CHUNK),
+        timeout: Some(Duration::from_secs(30)),
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?)
+}
+
+fn get(conn: &mut EspHttpConnection, url: &str) -> Result<()> {
+    conn.initiate_request(Method::Get, url, &[])?;
+    conn.initiate_response()?;
+    if conn.status() != 200 {
+        bail!("GET {:} answered {:}", url, conn.status());
+    }
+    Ok(())
+}
+
+//`<image url>.sig` next to the image, the signature over its SHA-256 as hex
+fn fetch_signature(url: &str) -> Result<ed25519_dalek::Signature> {
+    let mut conn = connection()?;
+    let url = format!("{:}.sig", url);
+    get(&mut conn, &url)?;
+    let mut buf = [0u8; 128];
+    let mut len = 0;
+    while len < buf.len() {
+        let n = conn.read(&mut buf[len..])?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    parse_signature(&String::from_utf8_lossy(&buf[..len]))
+        .map_err(|e| anyhow!("{:} in {:}", e, url))
+}
+
+fn download(url: &str) -> Result<()> {
+    let key = public_key()?;
+    let signature = fetch_signature(url)?;
+    let mut conn = connection()?;
+    get(&mut conn, url)?;
+    let size = conn.header("Content-Length").and_then(|l| l.parse().ok());
+    log::info!("ota: downloading {:} ({:?} bytes)", url, size);
+    write_image(
+        |buf| Ok(conn.read(buf)?),
+        ImageCheck::new(size, key, signature),
+    )
+}
+
+/// Download and install an image on a thread of its own, then restart into
+/// it. It has to be signed, the signature comes from `<url>.sig`.
+pub fn start_download(url: &str) -> Result<()> {
+    //refuse right away rather than after the download
+    public_key()?;
+    if !begin(None) {
+        bail!("an update is already running");
+    }
+    let url = url.to_string();
+    let spawned = thread::Builder::new().stack_size(8000).spawn(move || {
+        if download(&url).map_err(fail).is_ok() {
+            restart_soon();
+        }
+    });
+    if let Err(e) = spawned {
+        return Err(fail(e.into()));
+    }
+    Ok(())
+}
+
+/// Install a signed image streamed in by the HTTP server, `signature` is hex.
+/// The caller restarts once it has answered.
+pub fn upload(
+    read: impl FnMut(&mut [u8]) -> Result<usize>,
+    size: Option<usize>,
+    signature: &str,
+) -> Result<()> {
+    let key = public_key()?;
+    let signature = parse_signature(signature)?;
+    if !begin(size) {
+        bail!("an update is already running");
+    }
+    write_image(read, ImageCheck::new(size, key, signature)).map_err(fail)
+}
+
+/// Restart into the new image after giving the HTTP response a moment
+pub fn restart_soon() {
+    set_status(OtaStatus::Rebooting);
+    log::info!("ota: image installed, restarting");
+    thread::sleep(Duration::from_secs(1));
+    esp_idf_svc::hal::reset::restart();
+}
+
+/// Keeps a freshly installed image on probation: it's only marked valid once
+/// the remote has run with Wi-Fi up for a while, otherwise the bootloader goes
+/// back to the previous slot
+pub struct HealthCheck {
+    pending: bool,
+    started: Instant,
+    connected_since: Option<Instant>,
+}
+
+impl HealthCheck {
+    pub fn new() -> Result<Self> {
+        let pending = EspOta::new()?.get_running_slot()?.state == SlotState::Unverified;
+        if pending {
+            log::info!("ota: running a new image, checking it before keeping it");
+        }
+        Ok(Self {
+            pending,
+            started: Instant::now(),
+            connected_since: None,
+        })
+    }
+
+    /// Call regularly from the main loop
+    pub fn poll(&mut self, wifi_connected: bool) {
+        if !self.pending {
+            return;
+        }
+        if !wifi_connected {
+            self.connected_since = None;
+        } else if self.connected_since.is_none() {
+            self.connected_since = Some(Instant::now());
+        }
+        let result = if self
+            .connected_since
+            .is_some_and(|since| since.elapsed() > HEALTHY_AFTER)
+        {
+            log::info!("ota: new image is healthy");
+            self.pending = false;
+            EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid())
+        } else if self.started.elapsed() > GIVE_UP_AFTER {
+            log::warn!("ota: new image never got healthy, rolling back");
+            //only comes back if it couldn't
+            EspOta::new().and_then(|mut ota| Err(ota.mark_running_slot_invalid_and_reboot()))
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            log::warn!("ota: {:?}", e);
+        }
+    }
+}
@@ -1,5 +1,11 @@
 pub mod battery_monitor;
+pub mod bitmap;
+#[cfg(target_os = "espidf")]
 pub mod buttons;
 pub mod display;
+pub mod display_queue;
+pub mod power;
 pub mod rotary;
+pub mod screenshot;
+#[cfg(target_os = "espidf")]
 pub mod wifi;
@@ -1,59 +1,346 @@
-use crate::peripheral_util::display::{DisplayLine, DisplayMessage, TextSize};
-use anyhow::Result;
-use embedded_graphics::{
-    geometry::{Point, Size},
-    primitives::Rectangle,
-};
-use std::sync::mpsc;
+use crate::health::ServiceError;
+use crate::module_runner::RunnerRequest;
+use crate::peripheral_util::display::{DisplayMessage, StatusUpdate};
+use crate::peripheral_util::display_queue::DisplaySender;
+use crate::service_manager;
+use anyhow::{anyhow, Result};
+use embedded_graphics::primitives::Rectangle;
+use embedded_hal::i2c::Error as _;
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
 
 use max170xx::Max17048;
 
 use super::display::MessageType;
 
+const GAUGE_ADDR: u8 = 0x36;
+const REG_CONFIG: u8 = 0x0C;
+//low byte of CONFIG: ALRT flag and the 5 bit empty alert threshold
+const CONFIG_ALRT: u8 = 1 << 5;
+const CONFIG_ATHD_MASK: u8 = 0x1F;
+//voltage alert window, min in the high byte and max in the low, 20mV steps
+const REG_VALRT: u8 = 0x14;
+const VALRT_STEP: f32 = 0.02;
+//alert flags live in the high byte of STATUS, EnVr is the only other bit
+const REG_STATUS: u8 = 0x1A;
+const STATUS_FLAGS_MASK: u8 = 0x3F;
+
+//below either of these the cell is close to browning out the regulator
+const CRITICAL_VOLTAGE: f32 = 3.3;
+//how far over the voltage it was left at the cell has to go to wake the
+//remote. A charger in constant current gets there within seconds, while
+//rebound after the load is gone can too and is caught by `charger_confirmed`.
+const CHARGER_MARGIN: f32 = 0.15;
+//lowest charger wake, a flat cell spends most of its charge well below 4V
+const CHARGER_VOLTAGE: f32 = CRITICAL_VOLTAGE + CHARGER_MARGIN;
+//how long to watch the cell after a charger wake before believing it
+const CHARGER_CONFIRM: Duration = Duration::from_secs(2);
+//smallest rise over `CHARGER_CONFIRM` that isn't just noise
+const CHARGER_VOLTAGE_RISE: f32 = 0.01;
+
+//the charger only shows up as a positive rate, ignore noise around zero
+const CHARGING_RATE: f32 = 0.5;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+//back off a little while the bus is misbehaving
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+//reads in a row that can fail before the service gives up and gets restarted
+const MAX_FAILURES: u32 = 3;
+
+/// Last reading from the fuel gauge
+#[derive(Copy, Clone, Debug)]
+pub struct BatteryState {
+    /// State of charge in percent
+    pub soc: f32,
+    /// Cell voltage in volts
+    pub voltage: f32,
+    /// Percent per hour, positive while charging
+    pub rate: f32,
+    pub charging: bool,
+    /// The gauge's low battery alert has fired
+    pub low: bool,
+    /// Time to empty, or to full while charging, None if the rate is too
+    /// small to say
+    pub remaining: Option<Duration>,
+    /// False until the first reading, and while the gauge can't be reached
+    pub valid: bool,
+}
+
+impl BatteryState {
+    const fn empty() -> Self {
+        Self {
+            soc: 0.0,
+            voltage: 0.0,
+            rate: 0.0,
+            charging: false,
+            low: false,
+            remaining: None,
+            valid: false,
+        }
+    }
+}
+
+static BATTERY_STATE: Mutex<BatteryState> = Mutex::new(BatteryState::empty());
+
+/// Copy of the latest battery reading, readable from any thread
+pub fn battery_state() -> BatteryState {
+    *BATTERY_STATE.lock().unwrap()
+}
+
+fn estimate_remaining(soc: f32, rate: f32, charging: bool) -> Option<Duration> {
+    if rate.abs() < 0.1 {
+        return None;
+    }
+    let left = if charging { 100.0 - soc } else { soc };
+    let hours = (left / rate.abs()).max(0.0);
+    Some(Duration::from_secs_f32(hours * 3600.0))
+}
+
 pub struct BatteryMonitor {
-    last_soc: i32,
-    clear_rect: Rectangle,
+    last_shown: Option<(u8, bool, bool)>,
+    /// Empty alert threshold in percent, 1-32
+    alert_pct: u8,
+    /// Charge at which the remote shuts itself down
+    critical_pct: u8,
 }
 
 impl BatteryMonitor {
-    pub fn new() -> Self {
+    pub fn new(alert_pct: u8, critical_pct: u8) -> Self {
         BatteryMonitor {
-            last_soc: 0,
-            clear_rect: Rectangle::new(Point::new(100, 0), Size::new(30, 10)),
+            last_shown: None,
+            alert_pct: alert_pct.clamp(1, 32),
+            critical_pct,
+        }
+    }
+
+    fn read_config<I2C>(i2c: &mut I2C) -> Result<[u8; 2]>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        let mut config = [0u8; 2];
+        i2c.write_read(GAUGE_ADDR, &[REG_CONFIG], &mut config)
+            .map_err(|e| anyhow!("gauge config read: {:?}", e.kind()))?;
+        Ok(config)
+    }
+
+    /// Program the empty alert threshold and clear any pending alert,
+    /// RCOMP in the high byte is left alone
+    fn configure_alert<I2C>(i2c: &mut I2C, alert_pct: u8) -> Result<()>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        let [rcomp, low] = Self::read_config(i2c)?;
+        let athd = (32 - alert_pct) & CONFIG_ATHD_MASK;
+        let low = (low & !(CONFIG_ALRT | CONFIG_ATHD_MASK)) | athd;
+        i2c.write(GAUGE_ADDR, &[REG_CONFIG, rcomp, low])
+            .map_err(|e| anyhow!("gauge config write: {:?}", e.kind()))?;
+        Ok(())
+    }
+
+    /// Set the voltage alert window back to the whole range and clear any
+    /// alert left over from a shutdown
+    fn reset_voltage_alert<I2C>(i2c: &mut I2C) -> Result<()>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        Self::write_valrt(i2c, 0x00, 0xFF)?;
+        Self::clear_status(i2c)
+    }
+
+    /// Make ALRT fire once the cell goes `CHARGER_MARGIN` over `voltage`,
+    /// and never below `CHARGER_VOLTAGE`, which is what plugging in the
+    /// charger looks like from here. Arming above the current voltage keeps
+    /// a cell that is already over `CHARGER_VOLTAGE` from waking straight away.
+    fn arm_charger_alert<I2C>(i2c: &mut I2C, voltage: f32) -> Result<()>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        let wake = CHARGER_VOLTAGE.max(voltage + CHARGER_MARGIN);
+        let max = (wake / VALRT_STEP).ceil().clamp(0.0, 255.0) as u8;
+        Self::write_valrt(i2c, 0x00, max)?;
+        Self::clear_status(i2c)?;
+        //writing the config also clears the ALRT bit so the pin releases,
+        //and the lowest empty threshold keeps it from firing again right away
+        Self::configure_alert(i2c, 1)
+    }
+
+    fn write_valrt<I2C>(i2c: &mut I2C, min: u8, max: u8) -> Result<()>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        i2c.write(GAUGE_ADDR, &[REG_VALRT, min, max])
+            .map_err(|e| anyhow!("gauge valrt write: {:?}", e.kind()))
+    }
+
+    fn clear_status<I2C>(i2c: &mut I2C) -> Result<()>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        let mut status = [0u8; 2];
+        i2c.write_read(GAUGE_ADDR, &[REG_STATUS], &mut status)
+            .map_err(|e| anyhow!("gauge status read: {:?}", e.kind()))?;
+        i2c.write(
+            GAUGE_ADDR,
+            &[REG_STATUS, status[0] & !STATUS_FLAGS_MASK, status[1]],
+        )
+        .map_err(|e| anyhow!("gauge status write: {:?}", e.kind()))
+    }
+
+    fn critical(&self, state: &BatteryState) -> bool {
+        state.valid
+            && !state.charging
+            && (state.soc <= self.critical_pct as f32 || state.voltage <= CRITICAL_VOLTAGE)
+    }
+
+    fn read_state<I2C>(sensor: &mut Max17048<I2C>) -> Result<BatteryState>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        let soc = sensor.soc().map_err(|e| anyhow!("soc: {:?}", e))?;
+        let voltage = sensor.voltage().map_err(|e| anyhow!("voltage: {:?}", e))?;
+        let rate = sensor
+            .charge_rate()
+            .map_err(|e| anyhow!("charge rate: {:?}", e))?;
+        //the MCP73831 STAT line isn't wired to the ESP, go by the rate
+        let charging = rate > CHARGING_RATE;
+        Ok(BatteryState {
+            soc,
+            voltage,
+            rate,
+            charging,
+            low: false,
+            remaining: estimate_remaining(soc, rate, charging),
+            valid: true,
+        })
+    }
+
+    /// After the gauge's ALRT woke a flat remote, check that it is really
+    /// charging: a positive rate, or the voltage still climbing a moment
+    /// later. If not the charger alert is armed again and false returned,
+    /// the caller goes straight back to `shutdown_sleep`.
+    pub fn charger_confirmed<I2C, RAW>(i2c: I2C, mut raw_i2c: RAW) -> bool
+    where
+        I2C: embedded_hal::i2c::I2c,
+        RAW: embedded_hal::i2c::I2c,
+    {
+        let mut sensor = Max17048::new(i2c);
+        let first = match Self::read_state(&mut sensor) {
+            Ok(state) => state,
+            Err(e) => {
+                //come up and let the battery service report it
+                log::warn!("could not check for a charger: {:?}", e);
+                return true;
+            }
+        };
+        let charging = first.charging || {
+            std::thread::sleep(CHARGER_CONFIRM);
+            match Self::read_state(&mut sensor) {
+                Ok(second) => {
+                    second.charging || second.voltage >= first.voltage + CHARGER_VOLTAGE_RISE
+                }
+                Err(e) => {
+                    log::warn!("could not check for a charger: {:?}", e);
+                    return true;
+                }
+            }
+        };
+        if !charging {
+            log::warn!("woken at {:}V without a charger", first.voltage);
+            //rebound got it this far, only wake for the charger from here on
+            if let Err(e) = Self::arm_charger_alert(&mut raw_i2c, first.voltage) {
+                log::error!("could not arm charger wake: {:?}", e);
+            }
+        }
+        charging
+    }
+
+    fn show(&mut self, state: &BatteryState, disp_tx: &DisplaySender) {
+        let soc = state.soc.clamp(0.0, 100.0) as u8;
+        let low = state.low || soc <= self.alert_pct;
+        let shown = (soc, state.charging, low);
+        if self.last_shown == Some(shown) {
+            return;
         }
+        let msg = DisplayMessage {
+            module_name: "bat".to_string(),
+            content: MessageType::Status(StatusUpdate::Battery {
+                soc,
+                charging: state.charging,
+                low,
+            }),
+            status_line: true,
+            clear_rect: Rectangle::zero(),
+        };
+        let _ = disp_tx.send(msg);
+        self.last_shown = Some(shown);
     }
 
-    pub fn battery_service<I2C>(
+    /// `raw_i2c` is a second handle on the same bus, the driver doesn't
+    /// know about the alert bits so those are handled directly.
+    ///
+    /// Returns after asking the runner to shut down on a flat battery, or
+    /// with an error once the gauge has stopped answering.
+    pub fn battery_service<I2C, RAW>(
         &mut self,
         i2c: I2C,
-        disp_tx: mpsc::Sender<DisplayMessage>,
-    ) -> Result<()>
+        mut raw_i2c: RAW,
+        disp_tx: &DisplaySender,
+        runner: &mpsc::Sender<RunnerRequest>,
+    ) -> Result<(), ServiceError>
     where
         I2C: embedded_hal::i2c::I2c,
+        RAW: embedded_hal::i2c::I2c,
     {
+        if let Err(e) = Self::reset_voltage_alert(&mut raw_i2c) {
+            log::warn!("could not reset battery voltage alert: {:?}", e);
+        }
+        if let Err(e) = Self::configure_alert(&mut raw_i2c, self.alert_pct) {
+            log::warn!("could not set battery alert: {:?}", e);
+        }
         let mut sensor = Max17048::new(i2c);
+        let mut failures = 0;
 
         loop {
-            let soc = sensor.soc().unwrap() as i32;
-            //log::info!("state of charge: {:}", soc);
-            //log::info!("last: {:}", last);
-            if self.last_soc != soc {
-                let msg = DisplayMessage {
-                    module_name: "bat".to_string(),
-                    content: MessageType::Lines(vec![DisplayLine {
-                        line: { format!("{:}%", soc) },
-                        size: TextSize::Normal,
-                        x_offset: 100,
-                        y_offset: 0,
-                    }]),
-                    status_line: true,
-                    clear_rect: self.clear_rect.clone(),
-                };
-
-                let _ = disp_tx.send(msg);
-                self.last_soc = soc;
+            service_manager::heartbeat();
+            match Self::read_state(&mut sensor) {
+                Ok(mut state) => {
+                    failures = 0;
+                    match Self::read_config(&mut raw_i2c) {
+                        Ok([_, low]) => state.low = low & CONFIG_ALRT != 0,
+                        Err(e) => log::warn!("{:?}", e),
+                    }
+                    //a charge clears the alert, re-arm it for the next discharge
+                    if state.low && state.charging {
+                        if let Err(e) = Self::configure_alert(&mut raw_i2c, self.alert_pct) {
+                            log::warn!("could not re-arm battery alert: {:?}", e);
+                        }
+                    }
+                    *BATTERY_STATE.lock().unwrap() = state;
+                    self.show(&state, disp_tx);
+                    if self.critical(&state) {
+                        log::warn!("battery critical at {:}V", state.voltage);
+                        //without this nothing would wake the remote once charging
+                        if let Err(e) = Self::arm_charger_alert(&mut raw_i2c, state.voltage) {
+                            log::error!("could not arm charger wake: {:?}", e);
+                        }
+                        let _ = runner.send(RunnerRequest::Shutdown);
+                        return Ok(());
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+                Err(e) => {
+                    log::warn!("battery read failed: {:?}", e);
+                    BATTERY_STATE.lock().unwrap().valid = false;
+                    failures += 1;
+                    if failures >= MAX_FAILURES {
+                        return Err(ServiceError::Bus {
+                            device: "fuel gauge",
+                            detail: format!("{:}", e),
+                        });
+                    }
+                    std::thread::sleep(RETRY_INTERVAL);
+                }
             }
-            std::thread::sleep(std::time::Duration::from_millis(30000));
         }
     }
 }
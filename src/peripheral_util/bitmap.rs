@@ -0,0 +1,243 @@
+//Packed one bit per pixel images. Modules send them as sprites, and the display
+//service draws every frame into one before it goes out to the panel, which is
+//what lets sprites be XORed against whatever is already on screen.
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle};
+use std::convert::Infallible;
+
+/// How a bitmap combines with what is already on screen
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BlitMode {
+    /// Replace everything under the bitmap, clear bits turn pixels off
+    Copy,
+    /// Only set bits are drawn, clear bits leave the screen alone
+    Mask,
+    /// Set bits invert whatever is under them, drawing it twice undoes it
+    Xor,
+}
+
+/// 1 bit per pixel image. Rows start on a byte boundary and the most
+/// significant bit is the leftmost pixel, the same layout as module icons.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Bitmap {
+    size: Size,
+    stride: usize,
+    data: Vec<u8>,
+}
+
+impl Bitmap {
+    /// Bitmap with every pixel off
+    pub fn new(size: Size) -> Self {
+        let stride = (size.width as usize + 7) / 8;
+        Self {
+            size,
+            stride,
+            data: vec![0; stride * size.height as usize],
+        }
+    }
+
+    /// Bitmap from packed rows, `None` unless `data` holds exactly one row
+    /// per line of `size`
+    pub fn from_bytes(size: Size, data: Vec<u8>) -> Option<Self> {
+        let stride = (size.width as usize + 7) / 8;
+        if data.len() != stride * size.height as usize {
+            return None;
+        }
+        Some(Self { size, stride, data })
+    }
+
+    //byte and bit of a pixel, None outside the bitmap
+    fn index(&self, p: Point) -> Option<(usize, u8)> {
+        if p.x < 0 || p.y < 0 || p.x as u32 >= self.size.width || p.y as u32 >= self.size.height {
+            return None;
+        }
+        Some((
+            p.y as usize * self.stride + p.x as usize / 8,
+            0x80 >> (p.x % 8),
+        ))
+    }
+
+    /// Whether a pixel is on, anything outside the bitmap is off
+    pub fn get(&self, p: Point) -> bool {
+        self.index(p)
+            .is_some_and(|(byte, bit)| self.data[byte] & bit != 0)
+    }
+
+    /// Turn a pixel on or off, anything outside the bitmap is ignored
+    pub fn set(&mut self, p: Point, on: bool) {
+        if let Some((byte, bit)) = self.index(p) {
+            if on {
+                self.data[byte] |= bit;
+            } else {
+                self.data[byte] &= !bit;
+            }
+        }
+    }
+
+    /// Draw `src` with its top left corner at `at`, leaving everything
+    /// outside `clip` alone
+    pub fn blit(&mut self, src: &Bitmap, at: Point, mode: BlitMode, clip: &Rectangle) {
+        let area = Rectangle::new(at, src.size)
+            .intersection(clip)
+            .intersection(&self.bounding_box());
+        for p in area.points() {
+            let on = src.get(p - at);
+            match mode {
+                BlitMode::Copy => self.set(p, on),
+                BlitMode::Mask if on => self.set(p, true),
+                BlitMode::Xor if on => self.set(p, !self.get(p)),
+                _ => (),
+            }
+        }
+    }
+
+    /// Packed rows, top to bottom
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.data.chunks(self.stride.max(1))
+    }
+
+    /// Every pixel row by row, the order `fill_contiguous` expects
+    pub fn pixels(&self) -> impl Iterator<Item = BinaryColor> + '_ {
+        self.bounding_box()
+            .points()
+            .map(|p| BinaryColor::from(self.get(p)))
+    }
+}
+
+impl OriginDimensions for Bitmap {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for Bitmap {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(p, color) in pixels {
+            self.set(p, color.is_on());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //'#' is on, anything else off
+    fn bitmap(rows: &[&str]) -> Bitmap {
+        let size = Size::new(rows[0].len() as u32, rows.len() as u32);
+        let mut bitmap = Bitmap::new(size);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                bitmap.set(Point::new(x as i32, y as i32), c == '#');
+            }
+        }
+        bitmap
+    }
+
+    fn picture(bitmap: &Bitmap) -> Vec<String> {
+        (0..bitmap.size.height as i32)
+            .map(|y| {
+                (0..bitmap.size.width as i32)
+                    .map(|x| {
+                        if bitmap.get(Point::new(x, y)) {
+                            '#'
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn everywhere(bitmap: &Bitmap) -> Rectangle {
+        bitmap.bounding_box()
+    }
+
+    #[test]
+    fn rows_start_on_a_byte() {
+        let mut wide = Bitmap::new(Size::new(9, 2));
+        assert_eq!(wide.stride, 2);
+        wide.set(Point::new(0, 0), true);
+        wide.set(Point::new(8, 0), true);
+        wide.set(Point::new(7, 1), true);
+        let rows: Vec<&[u8]> = wide.rows().collect();
+        assert_eq!(rows, [[0x80, 0x80], [0x01, 0x00]]);
+    }
+
+    #[test]
+    fn from_bytes_wants_whole_rows() {
+        let size = Size::new(9, 2);
+        assert!(Bitmap::from_bytes(size, vec![0; 3]).is_none());
+        assert!(Bitmap::from_bytes(size, vec![0; 5]).is_none());
+        let bitmap = Bitmap::from_bytes(size, vec![0x00, 0x80, 0x40, 0x00]).unwrap();
+        assert!(bitmap.get(Point::new(8, 0)));
+        assert!(bitmap.get(Point::new(1, 1)));
+        assert!(!bitmap.get(Point::new(9, 0)));
+    }
+
+    #[test]
+    fn copy_replaces_what_is_under_it() {
+        let mut screen = bitmap(&["##########", "##########"]);
+        let sprite = bitmap(&["#.#", ".#."]);
+        let clip = everywhere(&screen);
+        //at x 6 the sprite straddles the screen's first byte boundary
+        screen.blit(&sprite, Point::new(6, 0), BlitMode::Copy, &clip);
+        assert_eq!(picture(&screen), ["#######.##", "######.#.#"]);
+    }
+
+    #[test]
+    fn mask_only_draws_set_bits() {
+        let mut screen = bitmap(&["#.........", ".........#"]);
+        let sprite = bitmap(&["#.#", ".#."]);
+        let clip = everywhere(&screen);
+        screen.blit(&sprite, Point::new(3, 0), BlitMode::Mask, &clip);
+        assert_eq!(picture(&screen), ["#..#.#....", "....#....#"]);
+    }
+
+    #[test]
+    fn xor_inverts_and_undoes_itself() {
+        let before = bitmap(&["###.......", "..........", "##########"]);
+        let sprite = bitmap(&["###", "#.#"]);
+        let clip = everywhere(&before);
+        let mut screen = before.clone();
+        screen.blit(&sprite, Point::new(1, 0), BlitMode::Xor, &clip);
+        assert_eq!(picture(&screen), ["#..#......", ".#.#......", "##########"]);
+        screen.blit(&sprite, Point::new(1, 0), BlitMode::Xor, &clip);
+        assert_eq!(screen, before);
+    }
+
+    #[test]
+    fn blits_stop_at_the_right_and_bottom_edges() {
+        let mut screen = Bitmap::new(Size::new(10, 3));
+        let sprite = bitmap(&["####", "####", "####"]);
+        let clip = everywhere(&screen);
+        screen.blit(&sprite, Point::new(8, 1), BlitMode::Copy, &clip);
+        assert_eq!(picture(&screen), ["..........", "........##", "........##"]);
+    }
+
+    #[test]
+    fn blits_stop_at_the_left_and_top_edges() {
+        let mut screen = Bitmap::new(Size::new(4, 3));
+        let sprite = bitmap(&["#..", ".#.", "..#"]);
+        let clip = everywhere(&screen);
+        screen.blit(&sprite, Point::new(-1, -1), BlitMode::Copy, &clip);
+        assert_eq!(picture(&screen), ["#...", ".#..", "...."]);
+    }
+
+    #[test]
+    fn blits_stay_inside_the_clip() {
+        let mut screen = Bitmap::new(Size::new(10, 3));
+        let sprite = bitmap(&["####", "####", "####"]);
+        let clip = Rectangle::new(Point::new(2, 1), Size::new(3, 1));
+        screen.blit(&sprite, Point::new(1, 0), BlitMode::Copy, &clip);
+        assert_eq!(picture(&screen), ["..........", "..###.....", ".........."]);
+    }
+}
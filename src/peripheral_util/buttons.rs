@@ -1,6 +1,11 @@
 use esp_idf_svc::hal::gpio;
 use std::sync::mpsc::Sender;
 
+use crate::health::ServiceError;
+use crate::service_manager;
+
+pub type ButtonPin = gpio::PinDriver<'static, gpio::AnyIOPin, gpio::Input>;
+
 #[derive(Copy, Clone)]
 struct Button {
     last_state: bool,
@@ -20,44 +25,56 @@ impl Buttons {
     }
 }
 
-fn button_action_generic(btn_idx: usize, btn_state: &Buttons) {
+fn button_action_generic(btn_idx: usize, btn_state: &Buttons) -> Result<(), ServiceError> {
     if let Some(tx) = &btn_state.action_tx {
         log::info!("sending from buttons");
-        tx.send(btn_idx).unwrap();
+        tx.send(btn_idx)
+            .map_err(|_| ServiceError::Disconnected("buttons"))?;
     }
+    Ok(())
 }
 
-pub fn button_service(btn_gpio: Vec<impl gpio::IOPin + 'static>, but_tx: Sender<usize>) {
-    let mut btns = Buttons::new(Some(but_tx));
-
+/// Turn the nine key pins into inputs with their pullups on
+pub fn setup(btn_gpio: Vec<impl gpio::IOPin + 'static>) -> Result<Vec<ButtonPin>, ServiceError> {
     if btn_gpio.len() != 9 {
-        return;
+        return Err(ServiceError::Config("expected 9 button pins"));
     }
 
     //take the vector of pins and make them into something useful
-    let mut buttons: Vec<_> = btn_gpio
-        .into_iter()
-        .map(|x| gpio::PinDriver::input(x.downgrade()).unwrap())
-        .collect::<Vec<_>>();
-
-    //set them all to internal pullup
-    for b in &mut buttons {
-        b.set_pull(gpio::Pull::Up).unwrap();
+    let mut buttons = vec![];
+    for pin in btn_gpio {
+        let mut button = gpio::PinDriver::input(pin.downgrade())
+            .map_err(|e| ServiceError::Gpio(format!("{:?}", e)))?;
+        //internal pullup, the keys pull to ground
+        button
+            .set_pull(gpio::Pull::Up)
+            .map_err(|e| ServiceError::Gpio(format!("{:?}", e)))?;
+        buttons.push(button);
     }
+    Ok(buttons)
+}
+
+/// Scan the keys and send the index of every press, only returns once nobody
+/// is listening anymore
+pub fn button_service(buttons: &[ButtonPin], but_tx: Sender<usize>) -> Result<(), ServiceError> {
+    let mut btns = Buttons::new(Some(but_tx));
 
     loop {
+        service_manager::heartbeat();
         for (idx, button) in buttons.iter().enumerate() {
             if button.is_low() {
                 if !btns.btns[idx].last_state {
                     btns.btns[idx].last_state = true;
                     log::info!("button {:} pressed", idx);
 
-                    button_action_generic(idx, &btns);
+                    button_action_generic(idx, &btns)?;
                 }
                 std::thread::sleep(std::time::Duration::from_millis(50));
             } else {
                 btns.btns[idx].last_state = false;
             }
         }
+        //yield between scans instead of spinning, still well under a press
+        std::thread::sleep(std::time::Duration::from_millis(10));
     }
 }
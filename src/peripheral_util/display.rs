@@ -1,14 +1,22 @@
-use anyhow::Result;
 use embedded_graphics::{
     mono_font::{ascii::FONT_5X8, ascii::FONT_6X10, MonoTextStyle, MonoTextStyleBuilder},
     pixelcolor::BinaryColor,
     prelude::*,
-    primitives::Rectangle,
+    primitives::{Circle, Line, PrimitiveStyle, Rectangle},
     text::{Baseline, Text},
 };
 use sh1106::{displayrotation::DisplayRotation, prelude::*, Builder};
 use std::sync::mpsc;
 
+use super::bitmap::{Bitmap, BlitMode};
+use super::display_queue::{DisplayReceiver, DisplaySender};
+use super::screenshot;
+
+use crate::health::ServiceError;
+use crate::kasa::energy;
+use crate::{metrics, service_manager};
+use std::time::{Duration, Instant};
+
 pub enum TextSize {
     Small,
     Normal,
@@ -31,26 +39,150 @@ pub struct XY {
     pub y: u8,
 }
 
-/// Display a rectangular chunk of a buffer
-pub struct DisplayBuffer {
-    /// 2d array buf binaryColors that should wrap in the rectangle
-    pub buf: Vec<BinaryColor>,
-    /// Rectangle size
-    pub size: Size,
-    /// Upper left start of Rectangle
-    pub offset: Point,
+/// A drawing primitive, drawn in order after the message's `clear_rect`
+pub enum DrawOp {
+    /// Bitmap with its top left corner at `at`
+    Blit {
+        bitmap: Bitmap,
+        at: Point,
+        mode: BlitMode,
+    },
+    Fill {
+        rect: Rectangle,
+        color: BinaryColor,
+    },
+    /// One pixel outline
+    Rect {
+        rect: Rectangle,
+        color: BinaryColor,
+    },
+    Line {
+        from: Point,
+        to: Point,
+        color: BinaryColor,
+    },
+    Circle {
+        center: Point,
+        diameter: u32,
+        filled: bool,
+        color: BinaryColor,
+    },
+}
+
+/// Panel size in pixels
+pub const SCREEN: Size = Size::new(128, 64);
+
+/// Status bar area holding the active module's name
+pub const TITLE_AREA: Rectangle = Rectangle::new(Point::new(0, 0), Size::new(50, 10));
+/// Status bar area a toast covers, the title and whatever is next to it
+pub const TOAST_AREA: Rectangle = Rectangle::new(Point::new(0, 0), Size::new(98, 10));
+
+/// Status bar area for the Wi-Fi signal bars
+pub const WIFI_AREA: Rectangle = Rectangle::new(Point::new(99, 0), Size::new(11, 10));
+/// Status bar area for the battery glyph, body plus the nub on the right
+pub const BATTERY_AREA: Rectangle = Rectangle::new(Point::new(111, 0), Size::new(16, 10));
+
+//battery body outline and the part of it that fills with charge
+const BATTERY_BODY: Rectangle = Rectangle::new(Point::new(111, 0), Size::new(14, 10));
+const BATTERY_FILL: Rectangle = Rectangle::new(Point::new(113, 2), Size::new(10, 6));
+const BATTERY_NUB: Rectangle = Rectangle::new(Point::new(125, 3), Size::new(2, 4));
+//6x6 lightning bolt, XORed onto the fill
+const BOLT: [u8; 6] = [
+    0b00011000, 0b00110000, 0b01111000, 0b00110000, 0b01100000, 0b01000000,
+];
+const BOLT_ORIGIN: Point = Point::new(115, 2);
+
+/// How often the low battery glyph blinks and the charging fill steps
+const STATUS_FRAME: Duration = Duration::from_millis(500);
+//how long to wait for a message before looking at the animations again
+const IDLE_WAIT: Duration = Duration::from_millis(20);
+//messages drawn at most per flush, so the status bar still keeps up
+const MAX_BATCH: usize = 8;
+
+/// How long a toast stays up before the title comes back
+const TOAST_TIME: Duration = Duration::from_secs(4);
+
+/// How often the picture moves by a pixel, so the status bar doesn't burn in
+const SHIFT_PERIOD: Duration = Duration::from_secs(120);
+//offsets cycled through, the picture never moves more than a pixel away
+const SHIFTS: [Point; 4] = [
+    Point::new(0, 0),
+    Point::new(1, 0),
+    Point::new(1, 1),
+    Point::new(0, 1),
+];
+/// How often the screensaver clock moves
+const SAVER_STEP: Duration = Duration::from_secs(5);
+//"HH:MM" in the normal font
+const SAVER_TEXT: Size = Size::new(30, 10);
+
+/// Status bar items the display service lays out itself
+pub enum StatusUpdate {
+    /// Active module name, drawn inverted while the module has focus
+    Title { name: String, focused: bool },
+    /// Battery glyph, `low` makes it blink, `charging` adds a bolt and
+    /// animates the fill
+    Battery { soc: u8, charging: bool, low: bool },
+    /// Wi-Fi signal strength in bars 0-4, 0 while disconnected
+    Wifi { bars: u8 },
+}
+
+#[derive(Copy, Clone, PartialEq)]
+struct BatteryGlyph {
+    soc: u8,
+    charging: bool,
+    low: bool,
+}
+
+/// Issued by the module runner each time it puts a module on screen
+pub type SessionId = u32;
+
+/// Changes to the display itself rather than what's drawn on it
+pub enum DisplayCommand {
+    Contrast(u8),
+    /// Turn the panel on or off, the buffer is kept while it is off
+    Power(bool),
+    /// Hand the screen to a module session, its frames are clipped to
+    /// `viewport` and frames from any other session are discarded
+    Grant {
+        session: SessionId,
+        viewport: Vec<Rectangle>,
+    },
+    /// No module owns the screen until the next grant
+    Revoke,
+    /// Lit pixels dark and the background lit
+    Invert(bool),
+    /// Turn the picture upside down
+    Flip(bool),
+    /// Show a clock moving around a blank screen instead of the picture,
+    /// which is still kept up to date underneath
+    Screensaver(bool),
+}
+
+//where the screensaver clock is and where it goes next
+struct Saver {
+    at: Point,
+    step: Point,
+    next: Instant,
 }
 
 /// MessageType Enum
-/// There's two types of display messages
+/// There's two types of module display messages
 /// First being Lines, a vector of DisplayLines that render in
 ///     a single screen refresh. This will overwrite previous
 ///     lines of text if offset isn't adjusted.
-/// Second, Buffer, a vector of DisplayBuffer rectangles that
-///     render in a single screen refresh. This will overwrite.
+/// Second, Draw, a list of DrawOps: packed bitmaps and primitives
+///     that render in a single screen refresh. This will overwrite
+///     unless a bitmap is masked or XORed.
+/// Status is for system services updating the status bar.
+/// Command is for system services changing display settings.
+/// Toast briefly shows a short notice over the status bar title.
 pub enum MessageType {
     Lines(Vec<DisplayLine>),
-    Buffer(Vec<DisplayBuffer>),
+    Draw(Vec<DrawOp>),
+    Status(StatusUpdate),
+    Command(DisplayCommand),
+    Toast(String),
 }
 
 /// DisplayMessage
@@ -68,7 +200,7 @@ pub struct DisplayMessage {
     pub clear_rect: Rectangle,
 }
 
-pub fn display_error(sender: mpsc::Sender<DisplayMessage>, error_msg: String) {
+pub fn display_error(sender: DisplaySender, error_msg: String) {
     let _ = sender.send(DisplayMessage {
         module_name: "display_err".to_string(),
         content: MessageType::Lines(vec![DisplayLine {
@@ -82,9 +214,38 @@ pub fn display_error(sender: mpsc::Sender<DisplayMessage>, error_msg: String) {
     });
 }
 
+/// Blank a region of the display without drawing anything into it
+pub fn display_clear(sender: &DisplaySender, rect: Rectangle) {
+    let _ = sender.send(DisplayMessage {
+        module_name: "display_clear".to_string(),
+        content: MessageType::Lines(vec![]),
+        status_line: false,
+        clear_rect: rect,
+    });
+}
+
 pub struct Display<'a> {
     text_normal: MonoTextStyle<'a, BinaryColor>,
     text_small: MonoTextStyle<'a, BinaryColor>,
+    text_inverted: MonoTextStyle<'a, BinaryColor>,
+    title: Option<(String, bool)>,
+    //module session allowed to draw, and where
+    grant: Option<(SessionId, Vec<Rectangle>)>,
+    battery: Option<BatteryGlyph>,
+    wifi_bars: Option<u8>,
+    //title updates wait underneath a toast until this
+    toast_until: Option<Instant>,
+    //counts status frames for blinking and the charging animation
+    status_frame: u32,
+    next_status_frame: Instant,
+    //no point animating anything while the panel is off
+    panel_on: bool,
+    inverted: bool,
+    flipped: bool,
+    //pixel shift against burn in
+    shift: usize,
+    next_shift: Instant,
+    saver: Option<Saver>,
 }
 
 impl<'a> Display<'a> {
@@ -97,10 +258,29 @@ impl<'a> Display<'a> {
             .font(&FONT_5X8)
             .text_color(BinaryColor::On)
             .build();
+        let text_inverted = MonoTextStyleBuilder::new()
+            .font(&FONT_5X8)
+            .text_color(BinaryColor::Off)
+            .background_color(BinaryColor::On)
+            .build();
 
         Self {
             text_normal,
             text_small,
+            text_inverted,
+            title: None,
+            grant: None,
+            battery: None,
+            wifi_bars: None,
+            toast_until: None,
+            status_frame: 0,
+            next_status_frame: Instant::now(),
+            panel_on: true,
+            inverted: false,
+            flipped: false,
+            shift: 0,
+            next_shift: Instant::now() + SHIFT_PERIOD,
+            saver: None,
         }
     }
 
@@ -112,70 +292,560 @@ impl<'a> Display<'a> {
         }
     }
 
+    //lines and draw lists, everything outside `clip` is left alone
+    fn draw_frame(
+        frame: &mut Bitmap,
+        clip: &Rectangle,
+        content: &MessageType,
+        style: MonoTextStyle<'a, BinaryColor>,
+    ) {
+        match content {
+            MessageType::Lines(lines) => {
+                let mut clipped = frame.clipped(clip);
+                for line in lines {
+                    let _ = Text::with_baseline(
+                        line.line.as_str(),
+                        Point::new(line.x_offset, line.y_offset),
+                        style,
+                        Baseline::Top,
+                    )
+                    .draw(&mut clipped);
+                }
+            }
+            MessageType::Draw(ops) => {
+                for op in ops {
+                    Self::draw_op(frame, clip, op);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn draw_op(frame: &mut Bitmap, clip: &Rectangle, op: &DrawOp) {
+        //blits clip themselves, they need to read what's under them for XOR
+        if let DrawOp::Blit { bitmap, at, mode } = op {
+            frame.blit(bitmap, *at, *mode, clip);
+            return;
+        }
+        let mut clipped = frame.clipped(clip);
+        let _ = match op {
+            DrawOp::Blit { .. } => Ok(()),
+            DrawOp::Fill { rect, color } => clipped.fill_solid(rect, *color),
+            DrawOp::Rect { rect, color } => rect
+                .into_styled(PrimitiveStyle::with_stroke(*color, 1))
+                .draw(&mut clipped),
+            DrawOp::Line { from, to, color } => Line::new(*from, *to)
+                .into_styled(PrimitiveStyle::with_stroke(*color, 1))
+                .draw(&mut clipped),
+            DrawOp::Circle {
+                center,
+                diameter,
+                filled,
+                color,
+            } => {
+                let style = if *filled {
+                    PrimitiveStyle::with_fill(*color)
+                } else {
+                    PrimitiveStyle::with_stroke(*color, 1)
+                };
+                Circle::with_center(*center, *diameter)
+                    .into_styled(style)
+                    .draw(&mut clipped)
+            }
+        };
+    }
+
+    //a module's frame, only while its session holds the screen and only
+    //inside the viewport it was granted, clearing included
+    fn draw_module(&mut self, frame: &mut Bitmap, session: SessionId, msg: DisplayMessage) {
+        let style = self.lazier_font_selector(msg.status_line);
+        let viewport = match &self.grant {
+            Some((granted, viewport)) if *granted == session => viewport,
+            _ => {
+                metrics::count(&metrics::DISPLAY_STALE);
+                return;
+            }
+        };
+        if !matches!(msg.content, MessageType::Lines(_) | MessageType::Draw(_)) {
+            log::warn!("{:} sent a service message, ignored", msg.module_name);
+            return;
+        }
+        for area in viewport {
+            let _ = frame
+                .clipped(area)
+                .fill_solid(&msg.clear_rect, BinaryColor::Off);
+            Self::draw_frame(frame, area, &msg.content, style);
+        }
+    }
+
+    fn draw_status(&mut self, frame: &mut Bitmap, update: StatusUpdate) {
+        match update {
+            StatusUpdate::Title { name, focused } => {
+                self.title = Some((name, focused));
+                if self.toast_until.is_none() {
+                    self.draw_title(frame);
+                }
+            }
+            StatusUpdate::Battery { soc, charging, low } => {
+                self.battery = Some(BatteryGlyph {
+                    soc: soc.min(100),
+                    charging,
+                    low,
+                });
+                self.draw_battery(frame);
+            }
+            StatusUpdate::Wifi { bars } => {
+                self.wifi_bars = Some(bars);
+                Self::draw_wifi(frame, bars);
+            }
+        }
+    }
+
+    fn draw_title<D>(&self, display: &mut D)
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let (name, focused) = match &self.title {
+            Some(title) => title,
+            None => return,
+        };
+        let (fill, style) = if *focused {
+            (BinaryColor::On, self.text_inverted)
+        } else {
+            (BinaryColor::Off, self.text_small)
+        };
+        let _ = display.fill_solid(&TITLE_AREA, fill);
+        let title: String = name.chars().take(9).collect();
+        let _ = Text::with_baseline(
+            title.as_str(),
+            TITLE_AREA.top_left + Point::new(1, 1),
+            style,
+            Baseline::Top,
+        )
+        .draw(display);
+    }
+
+    fn draw_toast<D>(&mut self, display: &mut D, text: &str)
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let _ = display.fill_solid(&TOAST_AREA, BinaryColor::On);
+        let fits = (TOAST_AREA.size.width as usize - 2) / 5;
+        let text: String = text.chars().take(fits).collect();
+        let _ = Text::with_baseline(
+            text.as_str(),
+            TOAST_AREA.top_left + Point::new(1, 1),
+            self.text_inverted,
+            Baseline::Top,
+        )
+        .draw(display);
+        self.toast_until = Some(Instant::now() + TOAST_TIME);
+    }
+
+    //put the title back once a toast has been up long enough, true if it did
+    fn toast_tick<D>(&mut self, display: &mut D) -> bool
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        match self.toast_until {
+            Some(until) if Instant::now() >= until => {
+                self.toast_until = None;
+                let _ = display.fill_solid(&TOAST_AREA, BinaryColor::Off);
+                self.draw_title(display);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    //after a restart of the service the panel starts blank, bring back what
+    //the status bar showed
+    fn redraw_status(&mut self, frame: &mut Bitmap) {
+        self.toast_until = None;
+        self.draw_title(frame);
+        self.draw_battery(frame);
+        if let Some(bars) = self.wifi_bars {
+            Self::draw_wifi(frame, bars);
+        }
+    }
+
+    fn draw_battery(&mut self, frame: &mut Bitmap) {
+        let glyph = match self.battery {
+            Some(glyph) => glyph,
+            None => return,
+        };
+        let _ = frame.fill_solid(&BATTERY_AREA, BinaryColor::Off);
+        //low and not charging blinks the whole glyph
+        if glyph.low && !glyph.charging && self.status_frame % 2 == 1 {
+            return;
+        }
+        let outline = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+        let _ = BATTERY_BODY.into_styled(outline).draw(frame);
+        let _ = frame.fill_solid(&BATTERY_NUB, BinaryColor::On);
+
+        let full = BATTERY_FILL.size.width;
+        let mut level = (glyph.soc as u32 * full + 50) / 100;
+        if glyph.charging {
+            //sweep from the current level up to full
+            let steps = full - level + 1;
+            level += self.status_frame % steps;
+        }
+        let fill = Rectangle::new(
+            BATTERY_FILL.top_left,
+            Size::new(level, BATTERY_FILL.size.height),
+        );
+        let _ = frame.fill_solid(&fill, BinaryColor::On);
+
+        if glyph.charging {
+            //inverted against the fill, so it shows wherever the level is
+            let bolt = Bitmap::from_bytes(Size::new(6, 6), BOLT.to_vec()).unwrap();
+            frame.blit(&bolt, BOLT_ORIGIN, BlitMode::Xor, &BATTERY_AREA);
+        }
+    }
+
+    fn draw_wifi<D>(display: &mut D, bars: u8)
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let _ = display.fill_solid(&WIFI_AREA, BinaryColor::Off);
+        let bottom = WIFI_AREA.top_left.y + WIFI_AREA.size.height as i32 - 1;
+        for bar in 0..4u32 {
+            let height = 3 + bar * 2;
+            let rect = Rectangle::new(
+                Point::new(
+                    WIFI_AREA.top_left.x + bar as i32 * 3,
+                    bottom - height as i32 + 1,
+                ),
+                Size::new(2, height),
+            );
+            //bars above the current strength only show their base
+            let rect = if bar < bars as u32 {
+                rect
+            } else {
+                Rectangle::new(Point::new(rect.top_left.x, bottom), Size::new(2, 1))
+            };
+            let _ = display.fill_solid(&rect, BinaryColor::On);
+        }
+    }
+
+    //advance blinking and charging animation, true if the battery needs a redraw
+    fn status_tick(&mut self) -> bool {
+        let animated = self
+            .battery
+            .is_some_and(|glyph| glyph.charging || glyph.low);
+        if !animated || !self.panel_on || Instant::now() < self.next_status_frame {
+            return false;
+        }
+        self.next_status_frame = Instant::now() + STATUS_FRAME;
+        self.status_frame = self.status_frame.wrapping_add(1);
+        true
+    }
+
+    //move the picture on to the next offset, true if it's time to
+    fn shift_tick(&mut self) -> bool {
+        if !self.panel_on || Instant::now() < self.next_shift {
+            return false;
+        }
+        self.next_shift = Instant::now() + SHIFT_PERIOD;
+        self.shift = (self.shift + 1) % SHIFTS.len();
+        true
+    }
+
+    //bounce the screensaver clock off the edges, true if it moved
+    fn saver_tick(&mut self) -> bool {
+        if !self.panel_on {
+            return false;
+        }
+        let saver = match self.saver.as_mut() {
+            Some(saver) if Instant::now() >= saver.next => saver,
+            _ => return false,
+        };
+        saver.next = Instant::now() + SAVER_STEP;
+        let room = Point::new(
+            (SCREEN.width - SAVER_TEXT.width) as i32,
+            (SCREEN.height - SAVER_TEXT.height) as i32,
+        );
+        let mut at = saver.at + saver.step;
+        if !(0..=room.x).contains(&at.x) {
+            saver.step.x = -saver.step.x;
+            at.x = saver.at.x + saver.step.x;
+        }
+        if !(0..=room.y).contains(&at.y) {
+            saver.step.y = -saver.step.y;
+            at.y = saver.at.y + saver.step.y;
+        }
+        saver.at = at;
+        true
+    }
+
+    fn saver_frame(&self, saver: &Saver) -> Bitmap {
+        let mut frame = Bitmap::new(SCREEN);
+        let clock = match energy::local_secs() {
+            Some(secs) => {
+                let time = secs.rem_euclid(86_400);
+                format!("{:02}:{:02}", time / 3600, time / 60 % 60)
+            }
+            None => "--:--".to_string(),
+        };
+        let _ =
+            Text::with_baseline(&clock, saver.at, self.text_normal, Baseline::Top).draw(&mut frame);
+        frame
+    }
+
+    //copy the frame, or the screensaver, into the driver's buffer flipped,
+    //inverted and shifted as set, then send it to the panel
+    fn present<I2C>(
+        &self,
+        display: &mut GraphicsMode<I2cInterface<I2C>>,
+        frame: &Bitmap,
+    ) -> Result<(), ServiceError>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        let saver_frame;
+        let shown = match &self.saver {
+            Some(saver) => {
+                saver_frame = self.saver_frame(saver);
+                &saver_frame
+            }
+            None => frame,
+        };
+        //the picture exactly as the panel gets it, screensaver, flip, pixel
+        //shift and inversion included, which is also what screenshots keep
+        let corner = Point::new(SCREEN.width as i32 - 1, SCREEN.height as i32 - 1);
+        let shift = SHIFTS[self.shift];
+        let mut panel = Bitmap::new(SCREEN);
+        for p in panel.bounding_box().points() {
+            let src = (if self.flipped { corner - p } else { p }) - shift;
+            panel.set(p, shown.get(src) != self.inverted);
+        }
+        let _ = display.fill_contiguous(&panel.bounding_box(), panel.pixels());
+        display
+            .flush()
+            .map_err(|e| ServiceError::bus("display", e))?;
+        metrics::count(&metrics::DISPLAY_FRAMES);
+        screenshot::shown(&panel);
+        Ok(())
+    }
+
+    /// Draw messages until the panel stops answering or every sender is gone.
+    /// Can be called again with a fresh bus device after a failure, the status
+    /// bar is redrawn from what it last showed.
     pub fn display_service<I2C>(
         &mut self,
         i2c: I2C,
-        recv: mpsc::Receiver<DisplayMessage>,
-    ) -> Result<()>
+        recv: &DisplayReceiver,
+    ) -> Result<(), ServiceError>
     where
         I2C: embedded_hal::i2c::I2c,
     {
         println!("display_service hit");
+        let bus_error = |e| ServiceError::bus("display", e);
         //this Builder is the specific SH1106 builder
         let mut display: GraphicsMode<I2cInterface<_>> = Builder::new()
             .with_rotation(DisplayRotation::Rotate180)
             .connect_i2c(i2c)
             .into();
 
-        display.init().unwrap();
-        display.flush().unwrap();
+        display.init().map_err(bus_error)?;
+        display.flush().map_err(bus_error)?;
 
-        Text::with_baseline(
+        let _ = Text::with_baseline(
             "Hello world!",
             Point::zero(),
             self.text_normal,
             Baseline::Top,
         )
-        .draw(&mut display)
-        .unwrap();
+        .draw(&mut display);
 
-        display.flush().unwrap();
+        display.flush().map_err(bus_error)?;
         display.clear();
+        //everything is drawn here first, the panel only gets whole frames
+        let mut frame = Bitmap::new(SCREEN);
+        self.redraw_status(&mut frame);
+        self.present(&mut display, &frame)?;
         loop {
-            if let Ok(msg) = recv.try_recv() {
+            service_manager::heartbeat();
+            let mut next = match recv.recv_timeout(IDLE_WAIT) {
+                Ok(msg) => Some(msg),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(ServiceError::Disconnected("display"))
+                }
+            };
+            //draw whatever else is waiting before paying for a flush
+            let mut drawn = 0;
+            while let Some((session, msg)) = next.take() {
+                drawn += 1;
+                if drawn < MAX_BATCH {
+                    next = recv.try_recv().ok();
+                }
+                if let Some(session) = session {
+                    self.draw_module(&mut frame, session, msg);
+                    continue;
+                }
                 //println!("{}", msg.module_name);
                 //clear part of display writer is tells us its using
-                let _ = display.fill_solid(&msg.clear_rect, BinaryColor::Off);
-                //render what was received
+                let _ = frame.fill_solid(&msg.clear_rect, BinaryColor::Off);
+                //render what was received, services can draw anywhere
+                let style = self.lazier_font_selector(msg.status_line);
                 match msg.content {
-                    MessageType::Lines(lines) => {
-                        for line in lines {
-                            Text::with_baseline(
-                                line.line.as_str(),
-                                Point::new(line.x_offset, line.y_offset),
-                                self.lazier_font_selector(msg.status_line),
-                                Baseline::Top,
-                            )
-                            .draw(&mut display)
-                            .unwrap();
-                        }
+                    MessageType::Lines(_) | MessageType::Draw(_) => {
+                        let screen = frame.bounding_box();
+                        Self::draw_frame(&mut frame, &screen, &msg.content, style)
                     }
-                    MessageType::Buffer(bufs) => {
-                        for buf in bufs {
-                            display
-                                .fill_contiguous(&Rectangle::new(buf.offset, buf.size), buf.buf)
-                                .unwrap();
+                    MessageType::Toast(text) => self.draw_toast(&mut frame, &text),
+                    MessageType::Status(update) => self.draw_status(&mut frame, update),
+                    MessageType::Command(cmd) => {
+                        let res = match cmd {
+                            DisplayCommand::Contrast(level) => display.set_contrast(level),
+                            DisplayCommand::Power(on) => {
+                                self.panel_on = on;
+                                display.display_on(on)
+                            }
+                            DisplayCommand::Grant { session, viewport } => {
+                                self.grant = Some((session, viewport));
+                                Ok(())
+                            }
+                            DisplayCommand::Revoke => {
+                                self.grant = None;
+                                Ok(())
+                            }
+                            DisplayCommand::Invert(on) => {
+                                self.inverted = on;
+                                Ok(())
+                            }
+                            DisplayCommand::Flip(on) => {
+                                self.flipped = on;
+                                Ok(())
+                            }
+                            DisplayCommand::Screensaver(on) => {
+                                self.saver = on.then(|| Saver {
+                                    at: Point::zero(),
+                                    step: Point::new(7, 5),
+                                    next: Instant::now() + SAVER_STEP,
+                                });
+                                Ok(())
+                            }
+                        };
+                        if res.is_err() {
+                            log::warn!("display command failed");
                         }
                     }
                 };
-
-                display.flush().unwrap();
             }
-
-            //time for ~24fps
-            //removed this sleep because the channel wasn't clearing fast enough
-            //probably should simply speed it up.
-            //std::thread::sleep(std::time::Duration::from_millis((1000 / 12) as u64));
+            let toast_done = self.toast_tick(&mut frame);
+            let status_due = self.status_tick();
+            if status_due {
+                self.draw_battery(&mut frame);
+            }
+            let shift_due = self.shift_tick();
+            let saver_due = self.saver_tick();
+            if drawn > 0 || toast_done || status_due || shift_due || saver_due {
+                self.present(&mut display, &frame)?;
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(frame: &Bitmap) -> Vec<Point> {
+        frame
+            .bounding_box()
+            .points()
+            .filter(|p| frame.get(*p))
+            .collect()
+    }
+
+    fn draw(clip: Rectangle, op: DrawOp) -> Bitmap {
+        let mut frame = Bitmap::new(Size::new(16, 8));
+        Display::draw_op(&mut frame, &clip, &op);
+        frame
+    }
+
+    fn whole() -> Rectangle {
+        Rectangle::new(Point::zero(), Size::new(16, 8))
+    }
+
+    #[test]
+    fn fills_are_clipped() {
+        let frame = draw(
+            Rectangle::new(Point::new(2, 2), Size::new(2, 2)),
+            DrawOp::Fill {
+                rect: Rectangle::new(Point::zero(), Size::new(16, 8)),
+                color: BinaryColor::On,
+            },
+        );
+        assert_eq!(
+            lit(&frame),
+            [
+                Point::new(2, 2),
+                Point::new(3, 2),
+                Point::new(2, 3),
+                Point::new(3, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn rects_are_outlines() {
+        let frame = draw(
+            whole(),
+            DrawOp::Rect {
+                rect: Rectangle::new(Point::new(1, 1), Size::new(3, 3)),
+                color: BinaryColor::On,
+            },
+        );
+        assert_eq!(lit(&frame).len(), 8);
+        assert!(!frame.get(Point::new(2, 2)));
+    }
+
+    #[test]
+    fn lines_include_both_ends() {
+        let frame = draw(
+            whole(),
+            DrawOp::Line {
+                from: Point::new(2, 5),
+                to: Point::new(12, 5),
+                color: BinaryColor::On,
+            },
+        );
+        let points = lit(&frame);
+        assert_eq!(points.len(), 11);
+        assert!(points.iter().all(|p| p.y == 5));
+    }
+
+    #[test]
+    fn circles_fill_or_outline() {
+        let circle = |filled| DrawOp::Circle {
+            center: Point::new(8, 4),
+            diameter: 7,
+            filled,
+            color: BinaryColor::On,
+        };
+        let filled = draw(whole(), circle(true));
+        let outline = draw(whole(), circle(false));
+        assert!(filled.get(Point::new(8, 4)));
+        assert!(!outline.get(Point::new(8, 4)));
+        assert!(outline.get(Point::new(8, 1)));
+        assert!(lit(&filled).len() > lit(&outline).len());
+    }
+
+    #[test]
+    fn blits_in_a_list_are_clipped() {
+        let sprite = Bitmap::from_bytes(Size::new(8, 1), vec![0xFF]).unwrap();
+        let frame = draw(
+            Rectangle::new(Point::new(4, 0), Size::new(8, 8)),
+            DrawOp::Blit {
+                bitmap: sprite,
+                at: Point::new(0, 3),
+                mode: BlitMode::Copy,
+            },
+        );
+        let xs: Vec<i32> = lit(&frame).iter().map(|p| p.x).collect();
+        assert_eq!(xs, [4, 5, 6, 7]);
+    }
+}
@@ -0,0 +1,394 @@
+//Bounded queue between everything that draws and the display service.
+//Alerts and display commands go out first, then the status bar, then module
+//frames. A frame for a region that still has one queued replaces it. Sending
+//never waits: once the queue is full the oldest, least important message is
+//dropped, so the runner thread can't be held up by a slow panel. Senders
+//handed to modules carry the module's session, so the display can tell whose
+//frame it is looking at.
+
+use embedded_graphics::primitives::Rectangle;
+use std::collections::VecDeque;
+use std::mem::discriminant;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, SendError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::display::{DisplayCommand, DisplayMessage, MessageType, SessionId};
+use crate::metrics;
+
+/// Messages queued at most, the display drains a handful per flush
+pub const CAPACITY: usize = 32;
+
+/// Which messages go first, and which are dropped first when it's full
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    /// Module drawing
+    Frame,
+    /// Status bar items and status line text
+    Status,
+    /// Toasts and display commands
+    Alert,
+}
+
+impl Priority {
+    pub fn of(msg: &DisplayMessage) -> Self {
+        match &msg.content {
+            MessageType::Toast(_) | MessageType::Command(_) => Priority::Alert,
+            MessageType::Status(_) => Priority::Status,
+            _ if msg.status_line => Priority::Status,
+            _ => Priority::Frame,
+        }
+    }
+}
+
+//`new` makes `old` pointless: the same status item or panel setting, or a
+//frame from the same module clearing the same region before it draws
+fn supersedes(new: &DisplayMessage, old: &DisplayMessage) -> bool {
+    match (&new.content, &old.content) {
+        (MessageType::Status(new), MessageType::Status(old)) => {
+            discriminant(new) == discriminant(old)
+        }
+        (MessageType::Command(new), MessageType::Command(old)) => {
+            //only the latest value of a panel setting matters
+            let setting = matches!(
+                new,
+                DisplayCommand::Contrast(_)
+                    | DisplayCommand::Invert(_)
+                    | DisplayCommand::Flip(_)
+                    | DisplayCommand::Screensaver(_)
+            );
+            setting && discriminant(new) == discriminant(old)
+        }
+        (
+            MessageType::Lines(_) | MessageType::Draw(_),
+            MessageType::Lines(_) | MessageType::Draw(_),
+        ) => {
+            new.module_name == old.module_name
+                && new.status_line == old.status_line
+                && new.clear_rect == old.clear_rect
+                && new.clear_rect != Rectangle::zero()
+        }
+        _ => false,
+    }
+}
+
+struct Queued {
+    priority: Priority,
+    session: Option<SessionId>,
+    msg: DisplayMessage,
+}
+
+struct Queue {
+    items: VecDeque<Queued>,
+    receiver_alive: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+    senders: AtomicUsize,
+}
+
+/// Bounded, priority aware replacement for `mpsc::channel::<DisplayMessage>()`
+pub fn display_queue() -> (DisplaySender, DisplayReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            items: VecDeque::with_capacity(CAPACITY),
+            receiver_alive: true,
+        }),
+        changed: Condvar::new(),
+        senders: AtomicUsize::new(1),
+    });
+    (
+        DisplaySender {
+            shared: shared.clone(),
+            session: None,
+        },
+        DisplayReceiver { shared },
+    )
+}
+
+pub struct DisplaySender {
+    shared: Arc<Shared>,
+    session: Option<SessionId>,
+}
+
+impl Clone for DisplaySender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+            session: self.session,
+        }
+    }
+}
+
+impl Drop for DisplaySender {
+    fn drop(&mut self) {
+        self.shared.senders.fetch_sub(1, Ordering::Relaxed);
+        self.shared.changed.notify_all();
+    }
+}
+
+impl DisplaySender {
+    /// A sender whose messages are tagged with a module session, the display
+    /// only draws them while that session holds the screen
+    pub fn for_session(&self, session: SessionId) -> Self {
+        let mut sender = self.clone();
+        sender.session = Some(session);
+        sender
+    }
+
+    /// Queue a message. Only fails once the display service is gone, a
+    /// message that loses out to more important ones is dropped quietly.
+    pub fn send(&self, msg: DisplayMessage) -> Result<(), SendError<DisplayMessage>> {
+        let priority = Priority::of(&msg);
+        let mut queue = self.shared.queue.lock().unwrap();
+        if !queue.receiver_alive {
+            return Err(SendError(msg));
+        }
+        if let Some(idx) = queue
+            .items
+            .iter()
+            .position(|old| old.session == self.session && supersedes(&msg, &old.msg))
+        {
+            //taken out rather than replaced in place, whatever was queued
+            //after it still has to be drawn first
+            queue.items.remove(idx);
+            metrics::count(&metrics::DISPLAY_SUPERSEDED);
+        }
+        if queue.items.len() >= CAPACITY {
+            //make room by dropping the oldest of the least important
+            let lowest = queue.items.iter().map(|q| q.priority).min();
+            let idx = lowest
+                .filter(|lowest| *lowest <= priority)
+                .and_then(|lowest| queue.items.iter().position(|q| q.priority == lowest));
+            metrics::count(&metrics::DISPLAY_DROPPED);
+            match idx {
+                Some(idx) => {
+                    queue.items.remove(idx);
+                }
+                //everything queued matters more than this
+                None => return Ok(()),
+            }
+        }
+        queue.items.push_back(Queued {
+            priority,
+            session: self.session,
+            msg,
+        });
+        drop(queue);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+pub struct DisplayReceiver {
+    shared: Arc<Shared>,
+}
+
+impl Drop for DisplayReceiver {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().receiver_alive = false;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl DisplayReceiver {
+    //most important first, oldest first within that
+    fn pop(&self, queue: &mut Queue) -> Option<(Option<SessionId>, DisplayMessage)> {
+        let top = queue.items.iter().map(|q| q.priority).max()?;
+        let idx = queue.items.iter().position(|q| q.priority == top)?;
+        let msg = queue.items.remove(idx).map(|q| (q.session, q.msg));
+        self.shared.changed.notify_all();
+        msg
+    }
+
+    fn disconnected(&self) -> bool {
+        self.shared.senders.load(Ordering::Relaxed) == 0
+    }
+
+    /// Next message and the session it was sent from, `None` for services
+    pub fn try_recv(&self) -> Result<(Option<SessionId>, DisplayMessage), TryRecvError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        match self.pop(&mut queue) {
+            Some(msg) => Ok(msg),
+            None if self.disconnected() => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<(Option<SessionId>, DisplayMessage), RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.queue.lock().unwrap();
+        loop {
+            if let Some(msg) = self.pop(&mut queue) {
+                return Ok(msg);
+            }
+            if self.disconnected() {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            queue = self.shared.changed.wait_timeout(queue, left).unwrap().0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peripheral_util::display::StatusUpdate;
+    use embedded_graphics::geometry::{Point, Size};
+
+    fn frame(module: &str, y: i32) -> DisplayMessage {
+        DisplayMessage {
+            module_name: module.to_string(),
+            content: MessageType::Lines(vec![]),
+            status_line: false,
+            clear_rect: Rectangle::new(Point::new(0, y), Size::new(128, 10)),
+        }
+    }
+
+    fn message(content: MessageType) -> DisplayMessage {
+        DisplayMessage {
+            module_name: "test".to_string(),
+            content,
+            status_line: false,
+            clear_rect: Rectangle::zero(),
+        }
+    }
+
+    fn wifi(bars: u8) -> DisplayMessage {
+        message(MessageType::Status(StatusUpdate::Wifi { bars }))
+    }
+
+    //module name and clear_rect y of everything queued, in the order drawn
+    fn drain(rx: &DisplayReceiver) -> Vec<(String, i32)> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(_, msg)| (msg.module_name, msg.clear_rect.top_left.y))
+            .collect()
+    }
+
+    #[test]
+    fn alerts_go_before_status_before_frames() {
+        let (tx, rx) = display_queue();
+        tx.send(frame("frame", 0)).unwrap();
+        tx.send(wifi(2)).unwrap();
+        tx.send(message(MessageType::Toast("hi".to_string())))
+            .unwrap();
+        tx.send(frame("frame", 10)).unwrap();
+        let order: Vec<Priority> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(_, msg)| Priority::of(&msg))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                Priority::Alert,
+                Priority::Status,
+                Priority::Frame,
+                Priority::Frame
+            ]
+        );
+    }
+
+    #[test]
+    fn frames_for_the_same_region_supersede() {
+        let (tx, rx) = display_queue();
+        tx.send(frame("a", 0)).unwrap();
+        tx.send(frame("a", 10)).unwrap();
+        tx.send(frame("b", 0)).unwrap();
+        tx.send(frame("a", 0)).unwrap();
+        //the replacement goes to the back, behind what was queued after
+        assert_eq!(
+            drain(&rx),
+            [
+                ("a".to_string(), 10),
+                ("b".to_string(), 0),
+                ("a".to_string(), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn sessions_do_not_supersede_each_other() {
+        let (tx, rx) = display_queue();
+        tx.for_session(1).send(frame("a", 0)).unwrap();
+        tx.for_session(2).send(frame("a", 0)).unwrap();
+        let sessions: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(session, _)| session)
+            .collect();
+        assert_eq!(sessions, [Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn status_items_supersede_by_kind() {
+        let (tx, rx) = display_queue();
+        tx.send(wifi(1)).unwrap();
+        tx.send(wifi(3)).unwrap();
+        let bars: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|(_, msg)| match msg.content {
+                MessageType::Status(StatusUpdate::Wifi { bars }) => bars,
+                _ => panic!("not a wifi update"),
+            })
+            .collect();
+        assert_eq!(bars, [3]);
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest_frame_without_waiting() {
+        let (tx, rx) = display_queue();
+        for y in 0..CAPACITY as i32 {
+            tx.send(frame("a", y)).unwrap();
+        }
+        let dropped = metrics::DISPLAY_DROPPED.load(Ordering::Relaxed);
+        let started = Instant::now();
+        tx.send(frame("a", 100)).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert!(metrics::DISPLAY_DROPPED.load(Ordering::Relaxed) > dropped);
+        let drawn = drain(&rx);
+        assert_eq!(drawn.len(), CAPACITY);
+        assert_eq!(drawn[0], ("a".to_string(), 1));
+        assert_eq!(drawn[CAPACITY - 1], ("a".to_string(), 100));
+    }
+
+    #[test]
+    fn full_queue_drops_frames_before_alerts() {
+        let (tx, rx) = display_queue();
+        for y in 0..CAPACITY as i32 {
+            tx.send(frame("a", y)).unwrap();
+        }
+        tx.send(message(MessageType::Toast("hi".to_string())))
+            .unwrap();
+        let first = rx.try_recv().unwrap().1;
+        assert_eq!(Priority::of(&first), Priority::Alert);
+        assert_eq!(drain(&rx).len(), CAPACITY - 1);
+    }
+
+    #[test]
+    fn frames_are_dropped_when_everything_queued_matters_more() {
+        let (tx, rx) = display_queue();
+        for i in 0..CAPACITY {
+            tx.send(message(MessageType::Toast(format!("{:}", i))))
+                .unwrap();
+        }
+        tx.send(frame("a", 0)).unwrap();
+        let frames = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|(_, msg)| Priority::of(msg) == Priority::Frame)
+            .count();
+        assert_eq!(frames, 0);
+    }
+
+    #[test]
+    fn sending_fails_once_the_display_is_gone() {
+        let (tx, rx) = display_queue();
+        drop(rx);
+        assert!(tx.send(frame("a", 0)).is_err());
+    }
+}
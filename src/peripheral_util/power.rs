@@ -0,0 +1,361 @@
+#[cfg(target_os = "espidf")]
+mod esp_sleep;
+
+#[cfg(target_os = "espidf")]
+use esp_sleep::wifi_power_save;
+#[cfg(target_os = "espidf")]
+pub use esp_sleep::{deep_sleep, restart, shutdown_sleep, woke_from_charger, woke_from_sleep};
+
+use std::time::{Duration, Instant};
+
+use crate::peripheral_util::display::{DisplayCommand, DisplayMessage, MessageType};
+use crate::peripheral_util::display_queue::DisplaySender;
+use embedded_graphics::primitives::Rectangle;
+
+/// GPIOs of the keys that can wake the remote from deep sleep.
+/// Only RTC capable pins can be used for ext1, that leaves out
+/// keys 1, 8 and 9 (GPIO 46, 47, 48).
+pub const WAKE_PINS: [i32; 6] = [9, 11, 12, 13, 14, 21];
+
+/// GPIO the fuel gauge's open drain ALRT output is wired to. In
+/// `hardware/kasa-remote.kicad_sch` that is the BFG_ALRT net from U2
+/// (MAX17048) to IO10 of U1 (ESP32-S3-WROOM-1). The board has no pull-up on
+/// it, `shutdown_sleep` enables the RTC one.
+pub const GAUGE_ALERT_PIN: i32 = 10;
+
+/// Contrast while active until it is changed in settings
+pub const CONTRAST_DEFAULT: u8 = 0x80;
+const CONTRAST_DIM: u8 = 0x08;
+
+/// Source of time for the idle timer, swapped for a fake one when testing
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// How deeply the remote is idling, each one implies the ones before it
+#[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+pub enum PowerState {
+    Active,
+    /// Display contrast turned down
+    Dim,
+    /// Screensaver instead of the usual screen
+    Saver,
+    /// Display off and Wi-Fi in modem sleep
+    Blank,
+    /// Deep sleep, only a key press brings the remote back
+    Sleep,
+}
+
+/// Inactivity needed before entering each state, measured from the last key press
+#[derive(Copy, Clone, Debug)]
+pub struct IdleTimeouts {
+    pub dim: Duration,
+    pub saver: Duration,
+    pub blank: Duration,
+    pub sleep: Duration,
+}
+
+impl IdleTimeouts {
+    pub fn from_secs(dim: u32, saver: u32, blank: u32, sleep: u32) -> Self {
+        Self {
+            dim: Duration::from_secs(dim as u64),
+            saver: Duration::from_secs(saver as u64),
+            blank: Duration::from_secs(blank as u64),
+            sleep: Duration::from_secs(sleep as u64),
+        }
+    }
+
+    fn state_after(&self, idle: Duration) -> PowerState {
+        if idle >= self.sleep {
+            PowerState::Sleep
+        } else if idle >= self.blank {
+            PowerState::Blank
+        } else if idle >= self.saver {
+            PowerState::Saver
+        } else if idle >= self.dim {
+            PowerState::Dim
+        } else {
+            PowerState::Active
+        }
+    }
+}
+
+/// Tracks inactivity and reports when the power state should change.
+/// Doesn't touch any hardware so it can be driven by a fake `Clock`.
+pub struct IdleTimer<C: Clock> {
+    clock: C,
+    timeouts: IdleTimeouts,
+    last_activity: Instant,
+    state: PowerState,
+}
+
+impl<C: Clock> IdleTimer<C> {
+    pub fn new(clock: C, timeouts: IdleTimeouts) -> Self {
+        let last_activity = clock.now();
+        Self {
+            clock,
+            timeouts,
+            last_activity,
+            state: PowerState::Active,
+        }
+    }
+
+    pub fn state(&self) -> PowerState {
+        self.state
+    }
+
+    /// A key was pressed, returns the state left behind if this woke us up
+    pub fn activity(&mut self) -> Option<PowerState> {
+        self.last_activity = self.clock.now();
+        if self.state == PowerState::Active {
+            return None;
+        }
+        Some(std::mem::replace(&mut self.state, PowerState::Active))
+    }
+
+    /// Check the timeouts, returns the new state if it changed
+    pub fn poll(&mut self) -> Option<PowerState> {
+        let idle = self
+            .clock
+            .now()
+            .saturating_duration_since(self.last_activity);
+        let state = self.timeouts.state_after(idle);
+        //only ever get deeper here, waking up is activity's job
+        if state > self.state {
+            self.state = state;
+            return Some(state);
+        }
+        None
+    }
+}
+
+/// Applies the idle timer's decisions to the display, Wi-Fi and chip
+pub struct PowerManager<C: Clock> {
+    timer: IdleTimer<C>,
+    disp_tx: DisplaySender,
+    //contrast while active, from settings
+    contrast: u8,
+}
+
+impl<C: Clock> PowerManager<C> {
+    pub fn new(timer: IdleTimer<C>, disp_tx: DisplaySender) -> Self {
+        Self {
+            timer,
+            disp_tx,
+            contrast: CONTRAST_DEFAULT,
+        }
+    }
+
+    /// Change the contrast used while active, dimming never goes above it
+    pub fn set_contrast(&mut self, contrast: u8) {
+        self.contrast = contrast;
+        if self.timer.state() == PowerState::Active {
+            self.display_command(DisplayCommand::Contrast(contrast));
+        }
+    }
+
+    /// Call on every key press. Returns true if the press woke the display
+    /// from the screensaver or blank, in which case it shouldn't do anything
+    /// else.
+    pub fn activity(&mut self) -> bool {
+        match self.timer.activity() {
+            Some(from) => {
+                log::info!("waking from {:?}", from);
+                if from >= PowerState::Blank {
+                    self.display_command(DisplayCommand::Power(true));
+                    wifi_power_save(false);
+                }
+                if from >= PowerState::Saver {
+                    self.display_command(DisplayCommand::Screensaver(false));
+                }
+                self.display_command(DisplayCommand::Contrast(self.contrast));
+                from >= PowerState::Saver
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `PowerState::Sleep` once the caller should save its state and
+    /// call `deep_sleep`
+    pub fn poll(&mut self) -> Option<PowerState> {
+        let state = self.timer.poll()?;
+        log::info!("idle, entering {:?}", state);
+        match state {
+            PowerState::Dim => {
+                let dim = CONTRAST_DIM.min(self.contrast);
+                self.display_command(DisplayCommand::Contrast(dim))
+            }
+            PowerState::Saver => self.display_command(DisplayCommand::Screensaver(true)),
+            PowerState::Blank => {
+                self.display_command(DisplayCommand::Power(false));
+                wifi_power_save(true);
+            }
+            PowerState::Sleep | PowerState::Active => (),
+        }
+        Some(state)
+    }
+
+    /// Turn the panel off regardless of idle state, for shutting down
+    pub fn display_off(&self) {
+        self.display_command(DisplayCommand::Power(false));
+    }
+
+    fn display_command(&self, cmd: DisplayCommand) {
+        let _ = self.disp_tx.send(DisplayMessage {
+            module_name: "power".to_string(),
+            content: MessageType::Command(cmd),
+            status_line: false,
+            clear_rect: Rectangle::zero(),
+        });
+    }
+}
+
+//a PC has no radio to put to sleep and never wakes from deep sleep
+#[cfg(not(target_os = "espidf"))]
+fn wifi_power_save(_on: bool) {}
+
+#[cfg(not(target_os = "espidf"))]
+pub fn woke_from_sleep() -> bool {
+    false
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub fn woke_from_charger() -> bool {
+    false
+}
+
+/// There is nothing to power down on a PC, the process just ends
+#[cfg(not(target_os = "espidf"))]
+pub fn deep_sleep() -> ! {
+    log::info!("deep sleep requested, exiting");
+    std::process::exit(0)
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub fn shutdown_sleep() -> ! {
+    log::info!("shutdown requested, exiting");
+    std::process::exit(0)
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub fn restart() -> ! {
+    log::info!("restart requested, exiting");
+    std::process::exit(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    //time only moves when the test says so
+    #[derive(Clone)]
+    struct FakeClock {
+        start: Instant,
+        elapsed: Rc<Cell<Duration>>,
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self {
+                start: Instant::now(),
+                elapsed: Rc::new(Cell::new(Duration::ZERO)),
+            }
+        }
+
+        fn set(&self, secs: u64) {
+            self.elapsed.set(Duration::from_secs(secs));
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed.get()
+        }
+    }
+
+    fn idle_timer(
+        dim: u32,
+        saver: u32,
+        blank: u32,
+        sleep: u32,
+    ) -> (IdleTimer<FakeClock>, FakeClock) {
+        let clock = FakeClock::new();
+        let timeouts = IdleTimeouts::from_secs(dim, saver, blank, sleep);
+        (IdleTimer::new(clock.clone(), timeouts), clock)
+    }
+
+    #[test]
+    fn goes_through_every_state() {
+        let (mut timer, clock) = idle_timer(10, 20, 30, 40);
+        assert_eq!(timer.poll(), None);
+        for (secs, state) in [
+            (10, PowerState::Dim),
+            (20, PowerState::Saver),
+            (30, PowerState::Blank),
+            (40, PowerState::Sleep),
+        ] {
+            clock.set(secs - 1);
+            assert_eq!(timer.poll(), None);
+            clock.set(secs);
+            assert_eq!(timer.poll(), Some(state));
+            assert_eq!(timer.poll(), None);
+            assert_eq!(timer.state(), state);
+        }
+    }
+
+    #[test]
+    fn activity_reports_the_state_it_woke_from() {
+        let (mut timer, clock) = idle_timer(10, 20, 30, 40);
+        assert_eq!(timer.activity(), None);
+        clock.set(25);
+        assert_eq!(timer.poll(), Some(PowerState::Saver));
+        assert_eq!(timer.activity(), Some(PowerState::Saver));
+        assert_eq!(timer.state(), PowerState::Active);
+        assert_eq!(timer.activity(), None);
+        //idle time counts from the key press
+        clock.set(34);
+        assert_eq!(timer.poll(), None);
+        clock.set(35);
+        assert_eq!(timer.poll(), Some(PowerState::Dim));
+    }
+
+    #[test]
+    fn poll_never_gets_lighter() {
+        let (mut timer, clock) = idle_timer(10, 20, 30, 40);
+        clock.set(30);
+        assert_eq!(timer.poll(), Some(PowerState::Blank));
+        //a clock that went backwards only looks like less idle time
+        clock.set(0);
+        assert_eq!(timer.poll(), None);
+        assert_eq!(timer.state(), PowerState::Blank);
+        clock.set(15);
+        assert_eq!(timer.poll(), None);
+        assert_eq!(timer.state(), PowerState::Blank);
+    }
+
+    #[test]
+    fn zero_and_equal_timeouts_skip_ahead() {
+        //everything at zero goes straight to sleep on the first poll
+        let (mut timer, _clock) = idle_timer(0, 0, 0, 0);
+        assert_eq!(timer.poll(), Some(PowerState::Sleep));
+        assert_eq!(timer.poll(), None);
+
+        //states sharing a timeout are skipped for the deepest of them
+        let (mut timer, clock) = idle_timer(10, 10, 10, 20);
+        clock.set(10);
+        assert_eq!(timer.poll(), Some(PowerState::Blank));
+        assert_eq!(timer.activity(), Some(PowerState::Blank));
+        clock.set(30);
+        assert_eq!(timer.poll(), Some(PowerState::Sleep));
+    }
+}
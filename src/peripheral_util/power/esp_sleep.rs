@@ -0,0 +1,75 @@
+use esp_idf_svc::sys;
+
+use super::{GAUGE_ALERT_PIN, WAKE_PINS};
+
+/// Modem sleep keeps the association alive but only wakes the radio for
+/// beacons, minimum is the driver's default
+pub fn wifi_power_save(on: bool) {
+    let ps = if on {
+        sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM
+    } else {
+        sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM
+    };
+    if let Err(e) = sys::esp!(unsafe { sys::esp_wifi_set_ps(ps) }) {
+        log::warn!("could not change wifi power save: {:?}", e);
+    }
+}
+
+/// True if the chip just came out of deep sleep from a key press or the
+/// charger being plugged in, as opposed to a power on or reset
+pub fn woke_from_sleep() -> bool {
+    let cause = unsafe { sys::esp_sleep_get_wakeup_cause() };
+    cause == sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1
+        || cause == sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0
+}
+
+/// True if the gauge's ALRT woke the chip from `shutdown_sleep`
+pub fn woke_from_charger() -> bool {
+    unsafe { sys::esp_sleep_get_wakeup_cause() == sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 }
+}
+
+/// Power down until any of the `WAKE_PINS` keys is pressed. The chip resets
+/// on wake, so this never returns.
+pub fn deep_sleep() -> ! {
+    log::info!("entering deep sleep");
+    let mask = WAKE_PINS.iter().fold(0u64, |mask, pin| mask | (1 << pin));
+    unsafe {
+        //the keys pull to ground, keep the internal pullups alive while asleep
+        sys::esp_sleep_pd_config(
+            sys::esp_sleep_pd_domain_t_ESP_PD_DOMAIN_RTC_PERIPH,
+            sys::esp_sleep_pd_option_t_ESP_PD_OPTION_ON,
+        );
+        for pin in WAKE_PINS {
+            sys::rtc_gpio_pullup_en(pin);
+            sys::rtc_gpio_pulldown_dis(pin);
+        }
+        sys::esp_sleep_enable_ext1_wakeup(
+            mask,
+            sys::esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ANY_LOW,
+        );
+        sys::esp_deep_sleep_start();
+    }
+}
+
+/// Deep sleep for a flat battery, the keys are left out so only the gauge
+/// pulling ALRT low wakes the chip. The battery service arms that alert for
+/// a voltage only a charger gets the cell to, main checks it really is
+/// charging before coming up.
+pub fn shutdown_sleep() -> ! {
+    log::info!("battery empty, sleeping until charged");
+    unsafe {
+        sys::esp_sleep_pd_config(
+            sys::esp_sleep_pd_domain_t_ESP_PD_DOMAIN_RTC_PERIPH,
+            sys::esp_sleep_pd_option_t_ESP_PD_OPTION_ON,
+        );
+        sys::rtc_gpio_pullup_en(GAUGE_ALERT_PIN);
+        sys::rtc_gpio_pulldown_dis(GAUGE_ALERT_PIN);
+        sys::esp_sleep_enable_ext0_wakeup(GAUGE_ALERT_PIN, 0);
+        sys::esp_deep_sleep_start();
+    }
+}
+
+/// Software reset, the chip comes back up as if the reset button was pressed
+pub fn restart() -> ! {
+    unsafe { sys::esp_restart() }
+}
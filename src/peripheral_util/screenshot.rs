@@ -0,0 +1,177 @@
+//Screenshots of the OLED. The display service hands over every frame it sends
+//to the panel, a capture keeps a copy of the latest one until the next capture
+//and the HTTP API serves it as PBM or PNG. Lit pixels come out white.
+
+use embedded_graphics::prelude::*;
+use std::sync::Mutex;
+
+use super::bitmap::Bitmap;
+
+//what the panel shows right now, and the last capture
+static SHOWN: Mutex<Option<Bitmap>> = Mutex::new(None);
+static CAPTURED: Mutex<Option<Bitmap>> = Mutex::new(None);
+
+/// Called by the display service with every frame that went out to the panel
+pub fn shown(frame: &Bitmap) {
+    *SHOWN.lock().unwrap() = Some(frame.clone());
+}
+
+/// Keep what's on screen for downloading, false if nothing was drawn yet
+pub fn capture() -> bool {
+    let shown = SHOWN.lock().unwrap().clone();
+    let captured = shown.is_some();
+    if captured {
+        *CAPTURED.lock().unwrap() = shown;
+    }
+    captured
+}
+
+/// The last capture
+pub fn captured() -> Option<Bitmap> {
+    CAPTURED.lock().unwrap().clone()
+}
+
+/// Binary PBM (P4). PBM counts set bits as black, so lit pixels are inverted.
+pub fn pbm(bitmap: &Bitmap) -> Vec<u8> {
+    let size = bitmap.size();
+    let mut out = format!("P4\n{:} {:}\n", size.width, size.height).into_bytes();
+    for row in bitmap.rows() {
+        out.extend(row.iter().map(|byte| !byte));
+    }
+    out
+}
+
+/// 1 bit grayscale PNG. The bitmap rows are already in the layout PNG wants,
+/// they go in uncompressed since a screen is only about a kilobyte.
+pub fn png(bitmap: &Bitmap) -> Vec<u8> {
+    let size = bitmap.size();
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+
+    let mut header = vec![];
+    header.extend(size.width.to_be_bytes());
+    header.extend(size.height.to_be_bytes());
+    //bit depth 1, grayscale, deflate, no filtering, no interlace
+    header.extend([1, 0, 0, 0, 0]);
+    png_chunk(&mut out, b"IHDR", &header);
+
+    //every scanline starts with its filter type, 0 is none
+    let mut raw = vec![];
+    for row in bitmap.rows() {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    png_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend((data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend(crc.to_be_bytes());
+}
+
+//zlib stream made of deflate blocks that aren't compressed at all
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    //deflate, 32K window, no preset dictionary, header checksum
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    if blocks.peek().is_none() {
+        out.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(last as u8);
+        let len = block.len() as u16;
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corners() -> Bitmap {
+        let mut bitmap = Bitmap::new(Size::new(10, 2));
+        bitmap.set(Point::new(0, 0), true);
+        bitmap.set(Point::new(9, 1), true);
+        bitmap
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn pbm_inverts_lit_pixels() {
+        let mut expected = b"P4\n10 2\n".to_vec();
+        expected.extend([0x7f, 0xff, 0xff, 0xbf]);
+        assert_eq!(pbm(&corners()), expected);
+    }
+
+    #[test]
+    fn png_layout() {
+        let png = png(&corners());
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        //IHDR: length, type, 10x2, 1 bit grayscale
+        assert_eq!(&png[8..16], b"\0\0\0\x0dIHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 10, 0, 0, 0, 2, 1, 0, 0, 0, 0]);
+        //IEND with its well known checksum
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+        //IDAT right after the IHDR checksum
+        let idat = &png[33..];
+        let len = u32::from_be_bytes(idat[..4].try_into().unwrap()) as usize;
+        assert_eq!(&idat[4..8], b"IDAT");
+        let zlib = &idat[8..8 + len];
+        let raw = [0, 0x80, 0x00, 0, 0x00, 0x40];
+        assert_eq!(&zlib[..2], &[0x78, 0x01]);
+        assert_eq!(&zlib[2..7], &[1, 6, 0, 0xf9, 0xff]);
+        assert_eq!(&zlib[7..13], &raw);
+        assert_eq!(&zlib[13..], &adler32(&raw).to_be_bytes());
+        let crc = crc32(&idat[4..8 + len]);
+        assert_eq!(&idat[8 + len..12 + len], &crc.to_be_bytes());
+    }
+
+    #[test]
+    fn capture_needs_a_frame() {
+        assert!(captured().is_none());
+        assert!(!capture());
+        shown(&corners());
+        assert!(capture());
+        assert_eq!(captured(), Some(corners()));
+    }
+}
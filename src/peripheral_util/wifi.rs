@@ -1,11 +1,23 @@
 //Modified from wifi lib in https://github.com/esp-rs/std-training
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use embedded_graphics::primitives::Rectangle;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::peripheral,
+    sys,
     wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
 };
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::display::{DisplayMessage, MessageType, StatusUpdate};
+use super::display_queue::DisplaySender;
+use crate::health::{Reporter, Service, ServiceError};
+use crate::{event_log, metrics, service_manager};
+
+static CONNECTED: AtomicBool = AtomicBool::new(false);
 
 pub fn wifi(
     ssid: &str,
@@ -59,8 +71,10 @@ pub fn wifi(
     };
 
     wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid.try_into().unwrap(),
-        password: pass.try_into().unwrap(),
+        ssid: ssid.try_into().map_err(|_| anyhow!("WiFi name too long"))?,
+        password: pass
+            .try_into()
+            .map_err(|_| anyhow!("WiFi password too long"))?,
         channel,
         auth_method,
         ..Default::default()
@@ -80,3 +94,71 @@ pub fn wifi(
 
     Ok(Box::new(esp_wifi))
 }
+
+/// Signal of the current access point in dBm, None when not associated
+pub fn rssi() -> Option<i8> {
+    let mut info = sys::wifi_ap_record_t::default();
+    sys::esp!(unsafe { sys::esp_wifi_sta_get_ap_info(&mut info) }).ok()?;
+    Some(info.rssi)
+}
+
+/// Signal strength as 0-4 bars, 0 when not associated
+pub fn signal_bars(rssi: Option<i8>) -> u8 {
+    let rssi = match rssi {
+        Some(rssi) => rssi,
+        None => return 0,
+    };
+    match rssi {
+        rssi if rssi >= -55 => 4,
+        rssi if rssi >= -65 => 3,
+        rssi if rssi >= -75 => 2,
+        _ => 1,
+    }
+}
+
+/// Whether the station was associated when the Wi-Fi service last looked
+pub fn connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+/// Keep the station connected and the status bar's signal bars current
+pub fn wifi_service(mut wifi: Box<EspWifi<'static>>, disp_tx: DisplaySender, reporter: Reporter) {
+    let mut last_bars = None;
+    let mut was_up = true;
+    loop {
+        service_manager::heartbeat();
+        thread::sleep(Duration::from_millis(1000));
+        let up = wifi.is_connected().unwrap_or(false);
+        CONNECTED.store(up, Ordering::Relaxed);
+        if up != was_up {
+            event_log::record(if up {
+                "wifi: reconnected"
+            } else {
+                "wifi: dropped"
+            });
+            was_up = up;
+        }
+
+        let rssi = rssi();
+        metrics::set_rssi(rssi);
+        let bars = signal_bars(rssi);
+        if last_bars != Some(bars) {
+            let _ = disp_tx.send(DisplayMessage {
+                module_name: "wifi".to_string(),
+                content: MessageType::Status(StatusUpdate::Wifi { bars }),
+                status_line: true,
+                clear_rect: Rectangle::zero(),
+            });
+            last_bars = Some(bars);
+        }
+
+        if !up {
+            log::info!("wifi disconnected");
+            thread::sleep(Duration::from_secs(1)); //sleep a bit
+            if let Err(e) = wifi.connect() {
+                reporter.report(Service::Wifi, ServiceError::Network(format!("{:}", e)));
+                thread::sleep(Duration::from_secs(2)); //sleep a bit
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//The long running threads of the remote, declared once with their name, stack
+//and priority and what they need running first. Services call `heartbeat`
+//from their loops, the manager marks the ones that go quiet as stalled.
+
+#[cfg(target_os = "espidf")]
+mod esp_spawn;
+#[cfg(not(target_os = "espidf"))]
+mod host_spawn;
+
+#[cfg(target_os = "espidf")]
+use esp_spawn::spawn;
+#[cfg(not(target_os = "espidf"))]
+use host_spawn::spawn;
+
+use anyhow::{bail, Result};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::event_log;
+
+#[derive(Copy, Clone, Debug)]
+pub struct ServiceSpec {
+    /// Also the thread name, at most 15 characters for FreeRTOS
+    pub name: &'static str,
+    pub stack_size: usize,
+    /// FreeRTOS priority, ignored on a PC
+    pub priority: u8,
+    /// Services that have to be started before this one
+    pub depends_on: &'static [&'static str],
+    /// Longest the service may go between heartbeats, None if it isn't watched
+    pub liveness: Option<Duration>,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ServiceState {
+    Running,
+    /// No heartbeat within its liveness window
+    Stalled,
+    /// The thread returned
+    Exited,
+    /// The thread couldn't be spawned
+    Failed,
+}
+
+impl ServiceState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServiceState::Running => "running",
+            ServiceState::Stalled => "stalled",
+            ServiceState::Exited => "exited",
+            ServiceState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ServiceStatus {
+    pub spec: ServiceSpec,
+    pub state: ServiceState,
+    /// Time since the last heartbeat, None for services that don't send them
+    pub since_heartbeat: Option<Duration>,
+}
+
+struct Shared {
+    spec: ServiceSpec,
+    state: Mutex<ServiceState>,
+    //milliseconds since START
+    last_beat: AtomicU64,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Shared {
+    fn set_state(&self, state: ServiceState) {
+        let mut current = self.state.lock().unwrap();
+        if *current != state {
+            log::info!("service {:} {:}", self.spec.name, state.name());
+            if state != ServiceState::Running {
+                event_log::record(&format!("service {:} {:}", self.spec.name, state.name()));
+            }
+            *current = state;
+        }
+    }
+}
+
+static START: OnceLock<Instant> = OnceLock::new();
+static SERVICES: Mutex<Vec<Arc<Shared>>> = Mutex::new(vec![]);
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Shared>>> = const { RefCell::new(None) };
+}
+
+fn now_ms() -> u64 {
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Tell the manager the calling service is still making progress, does
+/// nothing on threads it didn't start
+pub fn heartbeat() {
+    CURRENT.with(|current| {
+        if let Some(shared) = current.borrow().as_ref() {
+            shared.last_beat.store(now_ms(), Ordering::Relaxed);
+        }
+    });
+}
+
+type ServiceFn = Box<dyn FnOnce() + Send>;
+
+/// Services waiting to be started
+#[derive(Default)]
+pub struct ServiceManager {
+    pending: Vec<(ServiceSpec, ServiceFn)>,
+}
+
+impl ServiceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, spec: ServiceSpec, run: impl FnOnce() + Send + 'static) {
+        self.pending.push((spec, Box::new(run)));
+    }
+
+    /// Start everything, each service only after the ones it depends on. A
+    /// service that can't be spawned is marked failed and the rest still go.
+    pub fn start(mut self) -> Result<()> {
+        let mut started: Vec<&'static str> = SERVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| s.spec.name)
+            .collect();
+        while !self.pending.is_empty() {
+            let ready = self
+                .pending
+                .iter()
+                .position(|(spec, _)| spec.depends_on.iter().all(|dep| started.contains(dep)));
+            let (spec, run) = match ready {
+                Some(idx) => self.pending.remove(idx),
+                None => {
+                    let names: Vec<_> = self.pending.iter().map(|(s, _)| s.name).collect();
+                    bail!(
+                        "unknown or circular service dependencies: {:}",
+                        names.join(", ")
+                    );
+                }
+            };
+            start_service(spec, run);
+            started.push(spec.name);
+        }
+        Ok(())
+    }
+}
+
+fn start_service(spec: ServiceSpec, run: ServiceFn) {
+    let shared = Arc::new(Shared {
+        spec,
+        state: Mutex::new(ServiceState::Running),
+        last_beat: AtomicU64::new(now_ms()),
+        handle: Mutex::new(None),
+    });
+    let inner = shared.clone();
+    let spawned = spawn(
+        &spec,
+        Box::new(move || {
+            CURRENT.with(|current| *current.borrow_mut() = Some(inner.clone()));
+            run();
+            inner.set_state(ServiceState::Exited);
+        }),
+    );
+    match spawned {
+        Ok(handle) => *shared.handle.lock().unwrap() = Some(handle),
+        Err(e) => {
+            log::error!("could not start {:}: {:?}", spec.name, e);
+            shared.set_state(ServiceState::Failed);
+        }
+    }
+    SERVICES.lock().unwrap().push(shared);
+}
+
+/// Update every service's state, call regularly from the main loop
+pub fn check() {
+    let now = now_ms();
+    for shared in SERVICES.lock().unwrap().iter() {
+        let state = *shared.state.lock().unwrap();
+        let finished = shared
+            .handle
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|h| h.is_finished());
+        let quiet =
+            Duration::from_millis(now.saturating_sub(shared.last_beat.load(Ordering::Relaxed)));
+        let stalled = shared.spec.liveness.is_some_and(|limit| quiet > limit);
+        match state {
+            ServiceState::Exited | ServiceState::Failed => (),
+            _ if finished => shared.set_state(ServiceState::Exited),
+            ServiceState::Running if stalled => shared.set_state(ServiceState::Stalled),
+            ServiceState::Stalled if !stalled => shared.set_state(ServiceState::Running),
+            _ => (),
+        }
+    }
+}
+
+/// Every started service, in the order they were started
+pub fn statuses() -> Vec<ServiceStatus> {
+    let now = now_ms();
+    SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|shared| ServiceStatus {
+            spec: shared.spec,
+            state: *shared.state.lock().unwrap(),
+            since_heartbeat: shared.spec.liveness.map(|_| {
+                Duration::from_millis(now.saturating_sub(shared.last_beat.load(Ordering::Relaxed)))
+            }),
+        })
+        .collect()
+}
+
+/// Nothing stalled or failed to start
+pub fn healthy() -> bool {
+    statuses()
+        .iter()
+        .all(|s| !matches!(s.state, ServiceState::Stalled | ServiceState::Failed))
+}
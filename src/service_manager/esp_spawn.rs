@@ -0,0 +1,26 @@
+use anyhow::Result;
+use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
+use std::thread::{self, JoinHandle};
+
+use super::ServiceSpec;
+
+/// FreeRTOS task with the spec's name, stack and priority
+pub fn spawn(spec: &ServiceSpec, run: Box<dyn FnOnce() + Send>) -> Result<JoinHandle<()>> {
+    //the configuration wants a nul terminated name that outlives the task
+    let name: &'static str = Box::leak(format!("{:}\0", spec.name).into_boxed_str());
+    //this applies to the next thread spawned from this one
+    //https://github.com/esp-rs/esp-idf-hal/issues/228#issuecomment-1676035648
+    ThreadSpawnConfiguration {
+        name: Some(name.as_bytes()),
+        stack_size: spec.stack_size,
+        priority: spec.priority,
+        ..Default::default()
+    }
+    .set()?;
+    let spawned = thread::Builder::new()
+        .stack_size(spec.stack_size)
+        .spawn(run);
+    //threads spawned later without a spec get the defaults again
+    ThreadSpawnConfiguration::default().set()?;
+    Ok(spawned?)
+}
@@ -0,0 +1,12 @@
+use anyhow::Result;
+use std::thread::{self, JoinHandle};
+
+use super::ServiceSpec;
+
+/// Plain std thread, a PC has no use for the priority
+pub fn spawn(spec: &ServiceSpec, run: Box<dyn FnOnce() + Send>) -> Result<JoinHandle<()>> {
+    Ok(thread::Builder::new()
+        .name(spec.name.to_string())
+        .stack_size(spec.stack_size)
+        .spawn(run)?)
+}
@@ -0,0 +1,121 @@
+#[cfg(target_os = "espidf")]
+mod nvs_store;
+#[cfg(target_os = "espidf")]
+pub use nvs_store::SettingsStore;
+
+use anyhow::Result;
+use std::sync::{Arc, Mutex};
+
+use crate::kasa::transport::{self, Credentials};
+use crate::peripheral_util::power::CONTRAST_DEFAULT;
+use crate::CONFIG;
+
+/// User adjustable settings, persisted in NVS.
+/// Anything never saved falls back to the values in `cfg.toml`.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Names of modules hidden from the launcher and module switching
+    pub disabled_modules: Vec<String>,
+    /// Module that was open when the remote went to deep sleep
+    pub last_module: Option<String>,
+    /// Kasa/Tapo cloud account for devices on the KLAP protocol
+    pub kasa_username: String,
+    pub kasa_password: String,
+    /// Display contrast while active, dimming turns it down from there
+    pub contrast: u8,
+    /// Lit pixels dark and the background lit
+    pub invert_display: bool,
+    /// Picture turned upside down, for holding the remote the other way round
+    pub flip_display: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            disabled_modules: split_list(CONFIG.disabled_modules),
+            last_module: None,
+            kasa_username: CONFIG.kasa_username.to_string(),
+            kasa_password: CONFIG.kasa_password.to_string(),
+            contrast: CONTRAST_DEFAULT,
+            invert_display: false,
+            flip_display: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn module_enabled(&self, name: &str) -> bool {
+        !self.disabled_modules.iter().any(|m| m == name)
+    }
+
+    pub fn set_module_enabled(&mut self, name: &str, enabled: bool) {
+        self.disabled_modules.retain(|m| m != name);
+        if !enabled {
+            self.disabled_modules.push(name.to_string());
+        }
+    }
+
+    pub fn kasa_credentials(&self) -> Credentials {
+        Credentials {
+            username: self.kasa_username.clone(),
+            password: self.kasa_password.clone(),
+        }
+    }
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Keeps settings for as long as the process runs, a PC has no NVS
+#[cfg(not(target_os = "espidf"))]
+#[derive(Default)]
+pub struct SettingsStore {
+    saved: Option<Settings>,
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl SettingsStore {
+    pub fn load(&self) -> Settings {
+        self.saved.clone().unwrap_or_default()
+    }
+
+    pub fn save(&mut self, settings: &Settings) -> Result<()> {
+        self.saved = Some(settings.clone());
+        Ok(())
+    }
+}
+
+/// Settings shared between services, every update is written through to NVS
+#[derive(Clone)]
+pub struct SharedSettings {
+    inner: Arc<Mutex<(Settings, SettingsStore)>>,
+}
+
+impl SharedSettings {
+    pub fn new(store: SettingsStore) -> Self {
+        let settings = store.load();
+        Self {
+            inner: Arc::new(Mutex::new((settings, store))),
+        }
+    }
+
+    /// Copy of the current settings
+    pub fn get(&self) -> Settings {
+        self.inner.lock().unwrap().0.clone()
+    }
+
+    /// Change the settings and persist them. New Kasa credentials take
+    /// effect right away, dropping the KLAP sessions made with the old ones.
+    pub fn update(&self, f: impl FnOnce(&mut Settings)) -> Result<()> {
+        let mut guard = self.inner.lock().unwrap();
+        let (settings, store) = &mut *guard;
+        f(settings);
+        transport::set_credentials(settings.kasa_credentials());
+        store.save(settings)
+    }
+}
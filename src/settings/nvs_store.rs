@@ -0,0 +1,70 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+use super::{split_list, Settings};
+
+const NAMESPACE: &str = "settings";
+const KEY_DISABLED: &str = "disabled_mods";
+const KEY_LAST_MODULE: &str = "last_module";
+const KEY_KASA_USER: &str = "kasa_user";
+const KEY_KASA_PASSWORD: &str = "kasa_pass";
+const KEY_CONTRAST: &str = "contrast";
+const KEY_INVERT: &str = "invert";
+const KEY_FLIP: &str = "flip";
+
+pub struct SettingsStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl SettingsStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    pub fn load(&self) -> Settings {
+        let mut settings = Settings::default();
+        let mut buf = [0u8; 128];
+        if let Ok(Some(disabled)) = self.nvs.get_str(KEY_DISABLED, &mut buf) {
+            settings.disabled_modules = split_list(disabled);
+        }
+        if let Ok(Some(last)) = self.nvs.get_str(KEY_LAST_MODULE, &mut buf) {
+            settings.last_module = Some(last.to_string());
+        }
+        if let Ok(Some(user)) = self.nvs.get_str(KEY_KASA_USER, &mut buf) {
+            settings.kasa_username = user.to_string();
+        }
+        if let Ok(Some(password)) = self.nvs.get_str(KEY_KASA_PASSWORD, &mut buf) {
+            settings.kasa_password = password.to_string();
+        }
+        if let Ok(Some(contrast)) = self.nvs.get_u8(KEY_CONTRAST) {
+            settings.contrast = contrast;
+        }
+        if let Ok(Some(invert)) = self.nvs.get_u8(KEY_INVERT) {
+            settings.invert_display = invert != 0;
+        }
+        if let Ok(Some(flip)) = self.nvs.get_u8(KEY_FLIP) {
+            settings.flip_display = flip != 0;
+        }
+        settings
+    }
+
+    pub fn save(&mut self, settings: &Settings) -> Result<()> {
+        self.nvs
+            .set_str(KEY_DISABLED, &settings.disabled_modules.join(","))?;
+        match &settings.last_module {
+            Some(last) => self.nvs.set_str(KEY_LAST_MODULE, last)?,
+            None => {
+                self.nvs.remove(KEY_LAST_MODULE)?;
+            }
+        }
+        self.nvs.set_str(KEY_KASA_USER, &settings.kasa_username)?;
+        self.nvs
+            .set_str(KEY_KASA_PASSWORD, &settings.kasa_password)?;
+        self.nvs.set_u8(KEY_CONTRAST, settings.contrast)?;
+        self.nvs.set_u8(KEY_INVERT, settings.invert_display as u8)?;
+        self.nvs.set_u8(KEY_FLIP, settings.flip_display as u8)?;
+        Ok(())
+    }
+}